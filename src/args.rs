@@ -1,3 +1,8 @@
+use crate::codec;
+use crate::db::{Backend, Options};
+use crate::error;
+use crate::export;
+use crate::output::Mode;
 use crate::value::Value;
 use anyhow::{bail, Context, Error, Result};
 use itertools::Itertools;
@@ -7,8 +12,38 @@ use std::env;
 #[derive(Debug)]
 pub enum Command {
     Info,
-    Tables,
+    /// Tables and views, those whose name is `LIKE` the pattern if given.
+    Tables(Option<String>),
+    Stats,
+    Freelist,
+    Analyze,
+    Check,
+    /// Statements creating the objects of the tables `LIKE` the pattern, or
+    /// of every one.
+    Schema(Option<String>),
+    /// Indexes of the tables `LIKE` the pattern, or of all of them.
+    Indexes(Option<String>),
+    Headers(bool),
+    Mode(Mode),
+    /// Column separator, and row separator when given.
+    Separator(String, Option<String>),
+    NullValue(String),
+    Export(Export),
+    /// SQL text rebuilding the tables `LIKE` the pattern, or every one.
+    Dump(Option<String>),
+    Import(Import),
     Select(Select),
+    /// EXPLAIN QUERY PLAN of a query.
+    Explain(Select),
+    Insert(Insert),
+    Update(Update),
+    Delete(Delete),
+    Begin,
+    Commit,
+    Rollback,
+    Pragma(Pragma),
+    /// ANALYZE of the table or index named, or of every table.
+    Statistics(Option<String>),
 }
 
 #[derive(Debug, Clone)]
@@ -20,21 +55,30 @@ impl ColumnNames {
     }
 }
 
+impl From<Vec<String>> for ColumnNames {
+    fn from(value: Vec<String>) -> Self {
+        Self(value)
+    }
+}
+
 impl From<&str> for ColumnNames {
     fn from(value: &str) -> Self {
-        Self(
-            value
-                .to_string()
-                .split(',')
-                .map(|s| s.trim().to_string())
-                .collect_vec(),
-        )
+        Self(value.split(',').map(|s| s.trim().to_string()).collect_vec())
     }
 }
 
+/// Comparison of a `WHERE` clause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Equal,
+    /// SQL `LIKE`, against the text of the value.
+    Like,
+}
+
 #[derive(Debug)]
 pub struct Condition {
     pub name: String,
+    pub op: Operator,
     pub value: Value,
 }
 
@@ -42,11 +86,30 @@ impl TryFrom<&str> for Condition {
     type Error = Error;
 
     fn try_from(value: &str) -> Result<Self> {
-        let mut iter = value.split('=');
-        let name = iter.next().context("column")?.trim().to_string();
-        let rhs = iter.next().context("value")?.trim();
-        let value = rhs.parse::<Value>()?;
-        Ok(Self { name, value })
+        let Ok(("", (name, op, literal))) = codec::sql::comparison(value) else {
+            bail!(error::Error::Parse(format!("not supported: {value}")));
+        };
+        let op = match op {
+            "=" => Operator::Equal,
+            _ => Operator::Like,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            op,
+            value: literal.parse()?,
+        })
+    }
+}
+
+impl TryFrom<(&str, &str)> for Condition {
+    type Error = Error;
+
+    fn try_from((name, value): (&str, &str)) -> Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            op: Operator::Equal,
+            value: value.parse()?,
+        })
     }
 }
 
@@ -54,6 +117,7 @@ impl TryFrom<&str> for Condition {
 pub enum Select {
     Count {
         table: String,
+        cond: Option<Condition>,
     },
     Column {
         table: String,
@@ -66,33 +130,217 @@ impl TryFrom<String> for Select {
     type Error = Error;
 
     fn try_from(value: String) -> Result<Self> {
-        let rg_count = Regex::new(r"select count\(\*\) from (?P<table>[A-Za-z]+)")?;
+        // Statements typed over several shell lines come joined with newlines.
+        let rg_count = Regex::new(
+            r"(?is)^\s*select\s+count\(\*\)\s+from\s+(?P<table>[A-Z]+)(\s+where\s+(?P<cond>.+))?\s*;?\s*$",
+        )?;
         let rg_col = Regex::new(
-            r"(?i)select (?P<columns>[A-Z,\s]+) from (?P<table>[A-Z]+)(\s+where\s+(?P<cond>[A-Z='\s_]+))?",
+            r"(?is)^\s*select\s+(?P<columns>[A-Z,\s]+)\s+from\s+(?P<table>[A-Z]+)(\s+where\s+(?P<cond>.+))?\s*;?\s*$",
         )?;
         match value.as_str() {
             s if rg_count.is_match(s) => {
                 let caps = rg_count.captures(s).context("select count regex")?;
-                let table = (&caps["table"]).to_string();
-                Ok(Select::Count { table })
+                let table = caps["table"].to_string();
+                let cond = caps
+                    .name("cond")
+                    .map(|m| m.as_str())
+                    .map(Condition::try_from)
+                    .transpose()?;
+                Ok(Select::Count { table, cond })
             }
             s if rg_col.is_match(s) => {
                 let caps = rg_col.captures(s).context("select col regex")?;
-                let table = (&caps["table"]).to_string();
-                let columns = (&caps["columns"]).into();
+                let table = caps["table"].to_string();
+                let columns = caps["columns"].into();
                 let cond = caps
                     .name("cond")
                     .map(|m| m.as_str())
                     .map(Condition::try_from)
-                    .and_then(|r| r.ok());
+                    .transpose()?;
                 Ok(Select::Column {
                     table,
                     columns,
                     cond,
                 })
             }
-            e => bail!("Not supported: {e}"),
+            e => bail!(error::Error::Parse(format!("not supported: {e}"))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Insert {
+    pub table: String,
+    pub columns: Option<Vec<String>>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl TryFrom<String> for Insert {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let Ok(("", insert)) = codec::sql::insert(&value) else {
+            bail!(error::Error::Parse(format!("not supported: {value}")));
+        };
+        let rows = insert
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|v| v.parse()).collect())
+            .collect::<Result<_, error::Error>>()?;
+        Ok(Self {
+            table: insert.table.to_string(),
+            columns: insert
+                .columns
+                .map(|cols| cols.into_iter().map(String::from).collect()),
+            rows,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Update {
+    pub table: String,
+    pub set: Vec<(String, Value)>,
+    pub cond: Option<Condition>,
+}
+
+impl TryFrom<String> for Update {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let Ok(("", update)) = codec::sql::update(&value) else {
+            bail!(error::Error::Parse(format!("not supported: {value}")));
+        };
+        let set = update
+            .set
+            .iter()
+            .map(|(col, v)| Ok((col.to_string(), v.parse()?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            table: update.table.to_string(),
+            set,
+            cond: update.cond.map(Condition::try_from).transpose()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Delete {
+    pub table: String,
+    pub cond: Option<Condition>,
+}
+
+impl TryFrom<String> for Delete {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let Ok(("", delete)) = codec::sql::delete(&value) else {
+            bail!(error::Error::Parse(format!("not supported: {value}")));
+        };
+        Ok(Self {
+            table: delete.table.to_string(),
+            cond: delete.cond.map(Condition::try_from).transpose()?,
+        })
+    }
+}
+
+/// `.export TABLE ?FILE? ?--format csv|jsonl? ?--blobs hex|base64?`
+#[derive(Debug)]
+pub struct Export {
+    pub table: String,
+    /// File written to, standard output when missing.
+    pub path: Option<String>,
+    pub format: export::Format,
+    pub blobs: export::Blobs,
+}
+
+impl TryFrom<&[&str]> for Export {
+    type Error = Error;
+
+    fn try_from(args: &[&str]) -> Result<Self> {
+        let (table, rest) = args.split_first().context("Missing table")?;
+        let mut export = Self {
+            table: unescape(table),
+            path: None,
+            format: Default::default(),
+            blobs: Default::default(),
+        };
+        let mut rest = rest.iter();
+        while let Some(arg) = rest.next() {
+            match *arg {
+                "--format" => export.format = rest.next().context("Missing format")?.parse()?,
+                "--blobs" => {
+                    export.blobs = rest.next().context("Missing blob encoding")?.parse()?
+                }
+                path if export.path.is_none() => export.path = Some(unescape(path)),
+                e => bail!(error::Error::Parse(format!("unexpected argument: {e}"))),
+            }
+        }
+        Ok(export)
+    }
+}
+
+/// `.import ?--csv? ?--skip N? ?--separator SEP? FILE TABLE`
+#[derive(Debug)]
+pub struct Import {
+    pub path: String,
+    pub table: String,
+    /// Field separator, the one of the output mode when missing.
+    pub separator: Option<String>,
+    /// Lines skipped at the start of the file.
+    pub skip: usize,
+}
+
+impl TryFrom<&[&str]> for Import {
+    type Error = Error;
+
+    fn try_from(args: &[&str]) -> Result<Self> {
+        let mut names = vec![];
+        let (mut separator, mut skip) = (None, 0);
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                // Fields are always read as CSV.
+                "--csv" => {}
+                "--skip" => skip = args.next().context("Missing line count")?.parse()?,
+                "--separator" => {
+                    separator = Some(unescape(args.next().context("Missing separator")?))
+                }
+                e if e.starts_with("--") => {
+                    bail!(error::Error::Parse(format!("unknown option: {e}")))
+                }
+                name => names.push(unescape(name)),
+            }
         }
+        let Ok([path, table]) = <[String; 2]>::try_from(names) else {
+            bail!(error::Error::Parse("usage: .import FILE TABLE".to_string()));
+        };
+        Ok(Self {
+            path,
+            table,
+            separator,
+            skip,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Pragma {
+    pub name: String,
+    pub arg: Option<String>,
+}
+
+impl TryFrom<String> for Pragma {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let Ok(("", pragma)) = codec::sql::pragma(&value) else {
+            bail!(error::Error::Parse(format!("not supported: {value}")));
+        };
+        Ok(Self {
+            name: pragma.name.to_lowercase(),
+            arg: pragma.arg.map(String::from),
+        })
     }
 }
 
@@ -101,27 +349,233 @@ impl TryFrom<String> for Command {
 
     fn try_from(value: String) -> Result<Self> {
         match value.as_str() {
-            ".dbinfo" => Ok(Command::Info),
-            ".tables" => Ok(Command::Tables),
+            s if s.starts_with('.') => dot(s),
             s if s.to_lowercase().starts_with("select") => {
                 let sel = Select::try_from(value)?;
                 Ok(Command::Select(sel))
             }
-            e => bail!("Not a command: {e}"),
+            s if s.to_lowercase().starts_with("explain") => {
+                let rg_explain = Regex::new(r"(?is)^explain\s+query\s+plan\s+(?P<select>.+)$")?;
+                let Some(caps) = rg_explain.captures(s) else {
+                    bail!(error::Error::Parse(format!("not supported: {s}")));
+                };
+                let sel = Select::try_from(caps["select"].to_string())?;
+                Ok(Command::Explain(sel))
+            }
+            s if s.to_lowercase().starts_with("insert") => {
+                let insert = Insert::try_from(value)?;
+                Ok(Command::Insert(insert))
+            }
+            s if s.to_lowercase().starts_with("update") => {
+                let update = Update::try_from(value)?;
+                Ok(Command::Update(update))
+            }
+            s if s.to_lowercase().starts_with("delete") => {
+                let delete = Delete::try_from(value)?;
+                Ok(Command::Delete(delete))
+            }
+            s if s.to_lowercase().starts_with("analyze") => {
+                let Ok(("", name)) = codec::sql::analyze(s) else {
+                    bail!(error::Error::Parse(format!("not supported: {s}")));
+                };
+                Ok(Command::Statistics(name.map(String::from)))
+            }
+            s if s.to_lowercase().starts_with("pragma") => {
+                let pragma = Pragma::try_from(value)?;
+                Ok(Command::Pragma(pragma))
+            }
+            s => match codec::sql::control(s) {
+                Ok(("", codec::sql::Control::Begin)) => Ok(Command::Begin),
+                Ok(("", codec::sql::Control::Commit)) => Ok(Command::Commit),
+                Ok(("", codec::sql::Control::Rollback)) => Ok(Command::Rollback),
+                _ => bail!(error::Error::Parse(format!("not a command: {s}"))),
+            },
         }
     }
 }
 
+/// Parses a dot-command, its name followed by whitespace separated
+/// arguments.
+fn dot(line: &str) -> Result<Command> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let args = words.collect_vec();
+    let cmd = match (name, args.as_slice()) {
+        (".dbinfo", []) => Command::Info,
+        (".tables", []) => Command::Tables(None),
+        (".tables", [pattern]) => Command::Tables(Some(unescape(pattern))),
+        (".stats", []) => Command::Stats,
+        (".freelist", []) => Command::Freelist,
+        (".analyze", []) => Command::Analyze,
+        (".check", []) => Command::Check,
+        (".schema", []) => Command::Schema(None),
+        (".schema", [pattern]) => Command::Schema(Some(unescape(pattern))),
+        (".indexes" | ".indices", []) => Command::Indexes(None),
+        (".indexes" | ".indices", [pattern]) => Command::Indexes(Some(unescape(pattern))),
+        (".headers", [switch]) => Command::Headers(parse_switch(switch)?),
+        (".mode", [mode]) => Command::Mode(mode.parse()?),
+        (".separator", [col]) => Command::Separator(unescape(col), None),
+        (".separator", [col, row]) => Command::Separator(unescape(col), Some(unescape(row))),
+        (".nullvalue", [null]) => Command::NullValue(unescape(null)),
+        (".dump", []) => Command::Dump(None),
+        (".dump", [pattern]) => Command::Dump(Some(unescape(pattern))),
+        (".import", args) => Command::Import(Import::try_from(args)?),
+        (".export", args) => Command::Export(Export::try_from(args)?),
+        _ => bail!(error::Error::Parse(format!("not a command: {line}"))),
+    };
+    Ok(cmd)
+}
+
+/// On/off setting, spelled as sqlite3 accepts it.
+fn parse_switch(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "on" | "yes" | "true" | "1" => Ok(true),
+        "off" | "no" | "false" | "0" => Ok(false),
+        e => bail!(error::Error::Parse(format!("expected on or off, not {e}"))),
+    }
+}
+
+/// Dot-command argument with its quotes dropped and the C escapes sqlite3
+/// knows for separators, like `\t`, resolved.
+fn unescape(arg: &str) -> String {
+    let arg = match arg.as_bytes() {
+        [q @ (b'"' | b'\''), .., l] if q == l && arg.len() > 1 => &arg[1..arg.len() - 1],
+        _ => arg,
+    };
+    let mut out = String::with_capacity(arg.len());
+    let mut chars = arg.chars().peekable();
+    while let Some(c) = chars.next() {
+        let escaped = match c {
+            '\\' => chars.next_if(|e| "tnr\\\"'".contains(*e)),
+            _ => None,
+        };
+        out.push(match escaped {
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some(e) => e,
+            None => c,
+        });
+    }
+    out
+}
+
 #[derive(Debug)]
 pub struct Args {
     pub filename: String,
+    pub opts: Options,
+    /// Workers used to scan tables, one means a sequential scan.
+    pub threads: usize,
+    /// Output mode to start in, from `--mode`.
+    pub mode: Option<Mode>,
+    /// Commands to run, none to start an interactive shell.
     pub cmds: Vec<Command>,
 }
 
+fn parse_size(value: &str) -> Result<usize> {
+    let value = value.trim();
+    let (num, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+    let scale = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        e => bail!("Unknown size unit: {e}"),
+    };
+    Ok(num.parse::<usize>()? * scale)
+}
+
 pub fn build() -> Result<Args> {
-    let mut args = env::args().skip(1);
+    let mut args = env::args().skip(1).peekable();
+    let mut opts = Options::default();
+    let mut threads = 1;
+    let mut mode = None;
+    while let Some(flag) = args.next_if(|a| a.starts_with("--")) {
+        match flag.as_str() {
+            "--cache-size" => {
+                let size = args.next().context("Missing cache size")?;
+                opts.cache_size = parse_size(&size)?;
+            }
+            "--mmap" => opts.backend = Backend::Mmap,
+            "--readonly" => opts.read_only = true,
+            "--threads" => {
+                let count = args.next().context("Missing thread count")?;
+                threads = count.parse::<usize>()?.max(1);
+            }
+            "--mode" => mode = Some(args.next().context("Missing mode")?.parse()?),
+            e => bail!("Unknown option: {e}"),
+        }
+    }
     let filename = args.next().context("Missing filename")?;
     let cmds = args.map(Command::try_from).collect::<Result<_>>();
     let cmds = cmds?;
-    Ok(Args { filename, cmds })
+    Ok(Args {
+        filename,
+        opts,
+        threads,
+        mode,
+        cmds,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_where_takes_any_literal() {
+        let select = |s: &str| Select::try_from(s.to_string()).unwrap();
+        let Select::Column { table, cond, .. } =
+            select("SELECT name, color FROM apples WHERE name = 'Äpfel-Grün (№2)'")
+        else {
+            panic!("not a column select");
+        };
+        let cond = cond.unwrap();
+        assert_eq!(table, "apples");
+        assert_eq!((cond.name.as_str(), cond.op), ("name", Operator::Equal));
+        assert_eq!(cond.value, Value::Text("Äpfel-Grün (№2)".into()));
+
+        let Select::Count { cond, .. } = select("select count(*) from apples where id = 42") else {
+            panic!("not a count");
+        };
+        assert_eq!(cond.unwrap().value, Value::Int(42));
+        let Select::Count { cond, .. } = select("select count(*) from apples where color = 'x=y'")
+        else {
+            panic!("not a count");
+        };
+        assert_eq!(cond.unwrap().value, Value::Text("x=y".into()));
+        let count = "select count(*) from apples where color >= 'x'";
+        assert!(Select::try_from(count.to_string()).is_err());
+        let Select::Column { cond, .. } = select("select name from apples where name like 'a%_'")
+        else {
+            panic!("not a column select");
+        };
+        let cond = cond.unwrap();
+        assert_eq!(
+            (cond.op, cond.value),
+            (Operator::Like, Value::Text("a%_".into()))
+        );
+
+        // A quoted `=` is part of the literal, a condition that does not
+        // parse fails the query instead of dropping the filter.
+        let Select::Column { cond, .. } = select("SELECT name FROM apples WHERE name = 'a=b'")
+        else {
+            panic!("not a column select");
+        };
+        assert_eq!(cond.unwrap().value, Value::Text("a=b".into()));
+        for bad in [
+            "SELECT name FROM apples WHERE color > 5",
+            "SELECT name FROM apples WHERE name = 'a' AND id = 1",
+            "SELECT name FROM apples LIMIT 1",
+        ] {
+            let err = Select::try_from(bad.to_string()).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(error::Error::Parse(_))),
+                "{bad}"
+            );
+        }
+    }
 }
@@ -0,0 +1,41 @@
+use std::fmt::{Debug, Formatter};
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+type Source = dyn AsRef<[u8]> + Send + Sync;
+
+/// Cheaply clonable view over a shared byte buffer, either owned on the heap
+/// or borrowed from a memory mapping. Safe to hand across threads.
+#[derive(Clone)]
+pub struct Chunk {
+    src: Arc<Source>,
+    range: Range<usize>,
+}
+
+impl Chunk {
+    pub fn new(src: Arc<Source>, range: Range<usize>) -> Self {
+        assert!(range.end <= (*src).as_ref().len());
+        Self { src, range }
+    }
+}
+
+impl From<Vec<u8>> for Chunk {
+    fn from(value: Vec<u8>) -> Self {
+        let range = 0..value.len();
+        Self::new(Arc::new(value), range)
+    }
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.src).as_ref()[self.range.clone()]
+    }
+}
+
+impl Debug for Chunk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Chunk").field("range", &self.range).finish()
+    }
+}
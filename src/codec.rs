@@ -1,7 +1,6 @@
-use crate::Result;
-use nom::bytes::complete::{tag, take_until, take_while};
+use crate::error::{Error, Result};
+use nom::bytes::complete::tag;
 use nom::character::complete::{char, multispace0};
-use nom::multi::separated_list0;
 use nom::sequence::preceded;
 use nom::{IResult, Parser};
 
@@ -9,6 +8,10 @@ pub mod varint {
     use super::*;
 
     pub fn take(io: &[u8]) -> IResult<&[u8], i64> {
+        if io.is_empty() {
+            let kind = nom::error::ErrorKind::Eof;
+            return Err(nom::Err::Error(nom::error::Error::new(io, kind)));
+        }
         let mut varint: i64 = 0;
         let mut bytes_read: usize = 0;
         for (i, byte) in io.iter().enumerate().take(9) {
@@ -26,25 +29,64 @@ pub mod varint {
 
         Ok((&io[bytes_read..], varint))
     }
+
+    pub fn encode(value: i64) -> Vec<u8> {
+        let mut v = value as u64;
+        if v >> 56 != 0 {
+            let mut buf = [0u8; 9];
+            buf[8] = v as u8;
+            v >>= 8;
+            for b in buf[..8].iter_mut().rev() {
+                *b = (v as u8 & 0b0111_1111) | 0b1000_0000;
+                v >>= 7;
+            }
+            return buf.to_vec();
+        }
+        let mut buf = Vec::with_capacity(9);
+        loop {
+            buf.push((v as u8 & 0b0111_1111) | 0b1000_0000);
+            v >>= 7;
+            if v == 0 {
+                break;
+            }
+        }
+        buf[0] &= 0b0111_1111;
+        buf.reverse();
+        buf
+    }
 }
 
 pub mod two_complements {
     use super::*;
 
+    /// Big-endian, sign extended from however many bytes the serial type uses.
     pub fn decode(src: &[u8]) -> Result<i64> {
-        anyhow::ensure!(src.len() <= 8);
-        let val = match src.len() {
-            1 => u8::from_be_bytes([src[0]]) as i64,
-            len => {
-                let mut buf = [0u8; 8];
-                let start = buf.len() - len;
-                for (i, b) in src.iter().enumerate() {
-                    buf[start + i] = !b
-                }
-                i64::from_be_bytes(buf) + 0x1
-            }
+        if src.is_empty() || src.len() > 8 {
+            return Err(Error::corrupt(format!("{} byte integer", src.len())));
+        }
+        let fill = match src[0] & 0x80 {
+            0 => 0x00,
+            _ => 0xFF,
         };
-        Ok(val)
+        let mut buf = [fill; 8];
+        buf[8 - src.len()..].copy_from_slice(src);
+        Ok(i64::from_be_bytes(buf))
+    }
+
+    /// Bytes needed to store `value`, as one of the serial type widths.
+    pub fn width(value: i64) -> usize {
+        match value {
+            -0x80..=0x7F => 1,
+            -0x8000..=0x7FFF => 2,
+            -0x80_0000..=0x7F_FFFF => 3,
+            -0x8000_0000..=0x7FFF_FFFF => 4,
+            -0x8000_0000_0000..=0x7FFF_FFFF_FFFF => 6,
+            _ => 8,
+        }
+    }
+
+    pub fn encode(value: i64, width: usize) -> Vec<u8> {
+        value.to_be_bytes()[8 - width..].to_vec()
     }
 }
 
@@ -52,36 +94,724 @@ pub mod float {
     use super::*;
 
     pub fn decode(src: &[u8]) -> Result<f64> {
-        anyhow::ensure!(src.len() == 8);
+        if src.len() != 8 {
+            return Err(Error::corrupt(format!("{} byte float", src.len())));
+        }
         let mut buf = [0u8; 8];
         buf.copy_from_slice(src);
         Ok(f64::from_be_bytes(buf))
     }
+
+    pub fn encode(value: f64) -> Vec<u8> {
+        value.to_be_bytes().to_vec()
+    }
+}
+
+pub mod text {
+    use super::*;
+
+    /// Text encoding declared at offset 56 of the database header.
+    #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+    pub enum Encoding {
+        #[default]
+        Utf8,
+        Utf16le,
+        Utf16be,
+    }
+
+    impl TryFrom<u32> for Encoding {
+        type Error = Error;
+
+        fn try_from(value: u32) -> Result<Self> {
+            match value {
+                // A freshly created, still empty, database leaves the field unset.
+                0 | 1 => Ok(Self::Utf8),
+                2 => Ok(Self::Utf16le),
+                3 => Ok(Self::Utf16be),
+                e => Err(Error::Unsupported(format!("text encoding {e}"))),
+            }
+        }
+    }
+
+    pub fn decode(src: &[u8], enc: Encoding) -> Result<String> {
+        let units = |f: fn([u8; 2]) -> u16| {
+            src.chunks_exact(2)
+                .map(|c| f([c[0], c[1]]))
+                .collect::<Vec<_>>()
+        };
+        let text = match enc {
+            Encoding::Utf8 => std::str::from_utf8(src).map(str::to_string).ok(),
+            Encoding::Utf16le => String::from_utf16(&units(u16::from_le_bytes)).ok(),
+            Encoding::Utf16be => String::from_utf16(&units(u16::from_be_bytes)).ok(),
+        };
+        text.ok_or_else(|| Error::corrupt(format!("invalid {enc:?} text")))
+    }
+
+    pub fn encode(src: &str, enc: Encoding) -> Vec<u8> {
+        match enc {
+            Encoding::Utf8 => src.as_bytes().to_vec(),
+            Encoding::Utf16le => src.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            Encoding::Utf16be => src.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+        }
+    }
 }
 
 pub mod sql {
     use super::*;
+    use nom::branch::alt;
+    use nom::bytes::complete::{is_not, tag_no_case, take_till1, take_while1};
+    use nom::character::complete::{digit0, digit1, hex_digit0, one_of};
+    use nom::combinator::{opt, recognize};
+    use nom::multi::{many0, separated_list1};
+    use nom::sequence::{delimited, pair, terminated, tuple};
+    use std::borrow::Cow;
 
-    fn extract_name(io: &str) -> IResult<&str, &str> {
-        take_while(|c| c != ',')
-            .and_then(preceded(
-                multispace0,
-                take_while(|c: char| c.is_ascii_graphic()),
-            ))
-            .parse(io)
+    /// Keywords that end the declared type of a column definition.
+    const CONSTRAINTS: [&str; 11] = [
+        "CONSTRAINT",
+        "PRIMARY",
+        "NOT",
+        "NULL",
+        "UNIQUE",
+        "CHECK",
+        "DEFAULT",
+        "COLLATE",
+        "REFERENCES",
+        "GENERATED",
+        "AS",
+    ];
+
+    /// Keywords opening a table constraint instead of a column definition.
+    const TABLE_CONSTRAINTS: [&str; 5] = ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"];
+
+    /// Words sqlite3 reserves, which need quotes to be used as names.
+    const KEYWORDS: [&str; 147] = [
+        "ABORT",
+        "ACTION",
+        "ADD",
+        "AFTER",
+        "ALL",
+        "ALTER",
+        "ALWAYS",
+        "ANALYZE",
+        "AND",
+        "AS",
+        "ASC",
+        "ATTACH",
+        "AUTOINCREMENT",
+        "BEFORE",
+        "BEGIN",
+        "BETWEEN",
+        "BY",
+        "CASCADE",
+        "CASE",
+        "CAST",
+        "CHECK",
+        "COLLATE",
+        "COLUMN",
+        "COMMIT",
+        "CONFLICT",
+        "CONSTRAINT",
+        "CREATE",
+        "CROSS",
+        "CURRENT",
+        "CURRENT_DATE",
+        "CURRENT_TIME",
+        "CURRENT_TIMESTAMP",
+        "DATABASE",
+        "DEFAULT",
+        "DEFERRABLE",
+        "DEFERRED",
+        "DELETE",
+        "DESC",
+        "DETACH",
+        "DISTINCT",
+        "DO",
+        "DROP",
+        "EACH",
+        "ELSE",
+        "END",
+        "ESCAPE",
+        "EXCEPT",
+        "EXCLUDE",
+        "EXCLUSIVE",
+        "EXISTS",
+        "EXPLAIN",
+        "FAIL",
+        "FILTER",
+        "FIRST",
+        "FOLLOWING",
+        "FOR",
+        "FOREIGN",
+        "FROM",
+        "FULL",
+        "GENERATED",
+        "GLOB",
+        "GROUP",
+        "GROUPS",
+        "HAVING",
+        "IF",
+        "IGNORE",
+        "IMMEDIATE",
+        "IN",
+        "INDEX",
+        "INDEXED",
+        "INITIALLY",
+        "INNER",
+        "INSERT",
+        "INSTEAD",
+        "INTERSECT",
+        "INTO",
+        "IS",
+        "ISNULL",
+        "JOIN",
+        "KEY",
+        "LAST",
+        "LEFT",
+        "LIKE",
+        "LIMIT",
+        "MATCH",
+        "MATERIALIZED",
+        "NATURAL",
+        "NO",
+        "NOT",
+        "NOTHING",
+        "NOTNULL",
+        "NULL",
+        "NULLS",
+        "OF",
+        "OFFSET",
+        "ON",
+        "OR",
+        "ORDER",
+        "OTHERS",
+        "OUTER",
+        "OVER",
+        "PARTITION",
+        "PLAN",
+        "PRAGMA",
+        "PRECEDING",
+        "PRIMARY",
+        "QUERY",
+        "RAISE",
+        "RANGE",
+        "RECURSIVE",
+        "REFERENCES",
+        "REGEXP",
+        "REINDEX",
+        "RELEASE",
+        "RENAME",
+        "REPLACE",
+        "RESTRICT",
+        "RETURNING",
+        "RIGHT",
+        "ROLLBACK",
+        "ROW",
+        "ROWS",
+        "SAVEPOINT",
+        "SELECT",
+        "SET",
+        "TABLE",
+        "TEMP",
+        "TEMPORARY",
+        "THEN",
+        "TIES",
+        "TO",
+        "TRANSACTION",
+        "TRIGGER",
+        "UNBOUNDED",
+        "UNION",
+        "UNIQUE",
+        "UPDATE",
+        "USING",
+        "VACUUM",
+        "VALUES",
+        "VIEW",
+        "VIRTUAL",
+        "WHEN",
+        "WHERE",
+        "WINDOW",
+        "WITH",
+        "WITHOUT",
+    ];
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ColumnDef<'a> {
+        pub name: &'a str,
+        /// Declared type, as written, used to derive the column affinity.
+        pub kind: &'a str,
+        pub primary_key: bool,
+        /// Whether the PRIMARY KEY is declared AUTOINCREMENT.
+        pub autoincrement: bool,
+        pub not_null: bool,
+        /// Literal DEFAULT value, as written.
+        pub default: Option<&'a str>,
+        /// Collating sequence, BINARY when missing.
+        pub collate: Option<&'a str>,
+    }
+
+    /// Top level, comma separated, items between the outermost parentheses.
+    fn split_body(io: &str) -> Vec<&str> {
+        let Some(open) = io.find('(') else {
+            return vec![];
+        };
+        let body = &io[open + 1..];
+        let mut items = Vec::new();
+        let (mut depth, mut quote, mut start) = (0, None, 0);
+        for (i, c) in body.char_indices() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '\'' | '"' | '`') => quote = Some(c),
+                (None, '[') => quote = Some(']'),
+                (None, '(') => depth += 1,
+                (None, ')') if depth == 0 => {
+                    items.push(body[start..i].trim());
+                    return items;
+                }
+                (None, ')') => depth -= 1,
+                (None, ',') if depth == 0 => {
+                    items.push(body[start..i].trim());
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        items
     }
 
-    fn parse_between(io: &str) -> IResult<&str, Vec<&str>> {
-        let mut drop_statement = take_until("(");
-        let (io, _) = drop_statement.parse(io)?;
+    /// Upper case `io` with the content of quoted literals and names blanked
+    /// out, so keywords are only found where they are keywords. Offsets
+    /// stay those of `io`.
+    fn keywords_only(io: &str) -> String {
+        let mut quote = None;
+        io.chars()
+            .map(|c| match (quote, c) {
+                (Some(q), c) if c == q => {
+                    quote = None;
+                    c.to_string()
+                }
+                (Some(_), c) => " ".repeat(c.len_utf8()),
+                (None, '\'' | '"' | '`') => {
+                    quote = Some(c);
+                    c.to_string()
+                }
+                (None, '[') => {
+                    quote = Some(']');
+                    c.to_string()
+                }
+                (None, c) => c.to_ascii_uppercase().to_string(),
+            })
+            .collect()
+    }
 
-        preceded(tag("("), take_until(")"))
-            .and_then(separated_list0(char(','), extract_name))
-            .parse(io)
+    fn starts_with_keyword(io: &str, keywords: &[&str]) -> bool {
+        let word = io
+            .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or_default();
+        keywords.iter().any(|k| k.eq_ignore_ascii_case(word))
+    }
+
+    /// Splits an identifier, quoted or not, from the rest of the input.
+    pub fn identifier(io: &str) -> (&str, &str) {
+        let io = io.trim_start();
+        let close = match io.chars().next() {
+            Some('"') => '"',
+            Some('`') => '`',
+            Some('[') => ']',
+            Some('\'') => '\'',
+            _ => {
+                let end = io
+                    .find(|c: char| c.is_whitespace() || c == '(' || c == ',')
+                    .unwrap_or(io.len());
+                return (&io[..end], &io[end..]);
+            }
+        };
+        match io[1..].find(close) {
+            Some(end) => (&io[1..end + 1], &io[end + 2..]),
+            None => (&io[1..], ""),
+        }
+    }
+
+    /// `name` as it has to be written in a statement, in double quotes
+    /// unless it is a plain word that is not a keyword.
+    pub fn quote_identifier(name: &str) -> Cow<'_, str> {
+        let plain = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !KEYWORDS.iter().any(|k| k.eq_ignore_ascii_case(name));
+        match plain {
+            true => Cow::Borrowed(name),
+            false => Cow::Owned(format!("\"{}\"", name.replace('"', "\"\""))),
+        }
+    }
+
+    fn column_def(io: &str) -> ColumnDef<'_> {
+        let (name, rest) = identifier(io);
+        let rest = rest.trim_start();
+        let mut end = rest.len();
+        let mut depth = 0;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ if depth == 0
+                    && (i == 0 || rest[..i].ends_with(char::is_whitespace))
+                    && starts_with_keyword(&rest[i..], &CONSTRAINTS) =>
+                {
+                    end = i;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let kind = rest[..end].trim();
+        let upper = keywords_only(&rest[end..]);
+        let words = upper
+            .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .collect::<Vec<_>>();
+        let primary_key = upper.contains("PRIMARY KEY");
+        let autoincrement = primary_key && words.contains(&"AUTOINCREMENT");
+        let not_null = upper.contains("NOT NULL");
+        let default = upper
+            .find("DEFAULT")
+            .and_then(|at| literal(&rest[end + at + "DEFAULT".len()..]).ok())
+            .map(|(_, value)| value);
+        let collate = upper
+            .find("COLLATE")
+            .map(|at| identifier(&rest[end + at + "COLLATE".len()..]).0);
+        ColumnDef {
+            name,
+            kind,
+            primary_key,
+            autoincrement,
+            not_null,
+            default,
+            collate,
+        }
+    }
+
+    /// Column definitions of a CREATE TABLE statement. A table level
+    /// PRIMARY KEY over a single column flags that column.
+    pub fn columns(io: &str) -> Vec<ColumnDef<'_>> {
+        let items = split_body(io);
+        let (constraints, defs): (Vec<&str>, Vec<&str>) = items
+            .into_iter()
+            .partition(|i| starts_with_keyword(i, &TABLE_CONSTRAINTS));
+        let mut cols = defs.into_iter().map(column_def).collect::<Vec<_>>();
+        for c in constraints {
+            let upper = c.to_uppercase();
+            let Some(at) = upper.find("PRIMARY KEY") else {
+                continue;
+            };
+            let keys = split_body(&c[at..]);
+            if let [key] = keys.as_slice() {
+                let (key, order) = identifier(key);
+                let autoincrement = keywords_only(order)
+                    .split_whitespace()
+                    .any(|w| w == "AUTOINCREMENT");
+                cols.iter_mut()
+                    .filter(|col| col.name.eq_ignore_ascii_case(key))
+                    .for_each(|col| {
+                        col.primary_key = true;
+                        col.autoincrement = autoincrement;
+                    });
+            }
+        }
+        cols
+    }
+
+    /// Position of the INTEGER PRIMARY KEY column, which aliases the rowid.
+    pub fn rowid_alias(cols: &[ColumnDef<'_>]) -> Option<usize> {
+        cols.iter()
+            .position(|c| c.primary_key && c.kind.eq_ignore_ascii_case("INTEGER"))
     }
 
     pub fn column_names(io: &str) -> Vec<&str> {
-        parse_between(io).map(|r| r.1).unwrap_or_default()
+        columns(io).into_iter().map(|c| c.name).collect()
+    }
+
+    pub fn without_rowid(io: &str) -> bool {
+        let tail = io.rsplit(')').next().unwrap_or_default();
+        let words = tail.split_whitespace().collect::<Vec<_>>();
+        words
+            .windows(2)
+            .any(|w| w[0].eq_ignore_ascii_case("WITHOUT") && w[1].eq_ignore_ascii_case("ROWID"))
+    }
+
+    fn string(io: &str) -> IResult<&str, &str> {
+        recognize(delimited(
+            char('\''),
+            many0(alt((is_not("'"), tag("''")))),
+            char('\''),
+        ))
+        .parse(io)
+    }
+
+    fn blob(io: &str) -> IResult<&str, &str> {
+        recognize(tuple((one_of("xX"), char('\''), hex_digit0, char('\'')))).parse(io)
+    }
+
+    fn number(io: &str) -> IResult<&str, &str> {
+        recognize(tuple((
+            opt(one_of("+-")),
+            alt((
+                recognize(pair(digit1, opt(pair(char('.'), digit0)))),
+                recognize(pair(char('.'), digit1)),
+            )),
+            opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+        )))
+        .parse(io)
+    }
+
+    fn keyword(io: &str) -> IResult<&str, &str> {
+        alt((
+            tag_no_case("NULL"),
+            tag_no_case("TRUE"),
+            tag_no_case("FALSE"),
+        ))
+        .parse(io)
+    }
+
+    /// A literal value, returned as written.
+    pub fn literal(io: &str) -> IResult<&str, &str> {
+        preceded(multispace0, alt((string, blob, number, keyword))).parse(io)
+    }
+
+    fn symbol<'a>(c: char) -> impl FnMut(&'a str) -> IResult<&'a str, char> {
+        delimited(multispace0, char(c), multispace0)
+    }
+
+    fn name(io: &str) -> IResult<&str, &str> {
+        let (io, _) = multispace0(io)?;
+        let quoted =
+            |open, close| delimited(char(open), take_till1(move |c| c == close), char(close));
+        let quoted = alt((quoted('"', '"'), quoted('`', '`'), quoted('[', ']')));
+        alt((
+            quoted,
+            take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+        ))
+        .parse(io)
+    }
+
+    fn list<'a, O>(
+        item: impl FnMut(&'a str) -> IResult<&'a str, O>,
+    ) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<O>> {
+        delimited(symbol('('), separated_list1(symbol(','), item), symbol(')'))
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Insert<'a> {
+        pub table: &'a str,
+        pub columns: Option<Vec<&'a str>>,
+        pub rows: Vec<Vec<&'a str>>,
+    }
+
+    fn word<'a>(k: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+        delimited(multispace0, tag_no_case(k), multispace0)
+    }
+
+    /// `col = literal`, the only condition statements support.
+    fn condition(io: &str) -> IResult<&str, (&str, &str)> {
+        let (io, _) = word("WHERE")(io)?;
+        let (io, (col, _, value)) = tuple((name, symbol('='), literal)).parse(io)?;
+        Ok((io, (col, value)))
+    }
+
+    fn end(io: &str) -> IResult<&str, ()> {
+        terminated(multispace0, opt(char(';')))
+            .map(|_| ())
+            .parse(io)
+    }
+
+    /// `col = literal` or `col LIKE literal`, the WHERE clause of a query,
+    /// with the operator as written.
+    pub fn comparison(io: &str) -> IResult<&str, (&str, &str, &str)> {
+        let op = alt((symbol('=').map(|_| "="), word("LIKE")));
+        let (io, cond) = tuple((name, op, literal)).parse(io)?;
+        let (io, _) = end(io)?;
+        Ok((io, cond))
+    }
+
+    /// `INSERT INTO table [(col, ...)] VALUES (lit, ...)[, (lit, ...)]`
+    pub fn insert(io: &str) -> IResult<&str, Insert<'_>> {
+        let (io, _) = tuple((word("INSERT"), word("INTO"))).parse(io)?;
+        let (io, table) = name(io)?;
+        let (io, columns) = opt(list(name)).parse(io)?;
+        let (io, _) = word("VALUES")(io)?;
+        let (io, rows) = separated_list1(symbol(','), list(literal)).parse(io)?;
+        let (io, _) = end(io)?;
+        let insert = Insert {
+            table,
+            columns,
+            rows,
+        };
+        Ok((io, insert))
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Update<'a> {
+        pub table: &'a str,
+        pub set: Vec<(&'a str, &'a str)>,
+        pub cond: Option<(&'a str, &'a str)>,
+    }
+
+    /// `UPDATE table SET col = lit[, col = lit] [WHERE col = lit]`
+    pub fn update(io: &str) -> IResult<&str, Update<'_>> {
+        let (io, _) = word("UPDATE")(io)?;
+        let (io, table) = name(io)?;
+        let (io, _) = word("SET")(io)?;
+        let assign = tuple((name, symbol('='), literal)).map(|(col, _, value)| (col, value));
+        let (io, set) = separated_list1(symbol(','), assign).parse(io)?;
+        let (io, cond) = opt(condition).parse(io)?;
+        let (io, _) = end(io)?;
+        Ok((io, Update { table, set, cond }))
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Delete<'a> {
+        pub table: &'a str,
+        pub cond: Option<(&'a str, &'a str)>,
+    }
+
+    /// `DELETE FROM table [WHERE col = lit]`
+    pub fn delete(io: &str) -> IResult<&str, Delete<'_>> {
+        let (io, _) = tuple((word("DELETE"), word("FROM"))).parse(io)?;
+        let (io, table) = name(io)?;
+        let (io, cond) = opt(condition).parse(io)?;
+        let (io, _) = end(io)?;
+        Ok((io, Delete { table, cond }))
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Control {
+        Begin,
+        Commit,
+        Rollback,
+    }
+
+    /// `BEGIN [DEFERRED | IMMEDIATE | EXCLUSIVE] [TRANSACTION]`,
+    /// `COMMIT | END [TRANSACTION]` or `ROLLBACK [TRANSACTION]`
+    pub fn control(io: &str) -> IResult<&str, Control> {
+        let begin = tuple((
+            word("BEGIN"),
+            opt(alt((
+                word("DEFERRED"),
+                word("IMMEDIATE"),
+                word("EXCLUSIVE"),
+            ))),
+        ))
+        .map(|_| Control::Begin);
+        let commit = alt((word("COMMIT"), word("END"))).map(|_| Control::Commit);
+        let rollback = word("ROLLBACK").map(|_| Control::Rollback);
+        let (io, control) = alt((begin, commit, rollback)).parse(io)?;
+        let (io, _) = opt(word("TRANSACTION")).parse(io)?;
+        let (io, _) = end(io)?;
+        Ok((io, control))
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Pragma<'a> {
+        pub name: &'a str,
+        pub arg: Option<&'a str>,
+    }
+
+    /// `PRAGMA name [= value | (value)]`, the value a name or a literal.
+    pub fn pragma(io: &str) -> IResult<&str, Pragma<'_>> {
+        let (io, _) = word("PRAGMA")(io)?;
+        let (io, key) = name(io)?;
+        let value = || alt((literal, name));
+        let (io, arg) = opt(alt((
+            preceded(symbol('='), value()),
+            delimited(symbol('('), value(), symbol(')')),
+        )))
+        .parse(io)?;
+        let (io, _) = end(io)?;
+        Ok((io, Pragma { name: key, arg }))
+    }
+
+    /// `ANALYZE [name]`, the name of a table or an index.
+    pub fn analyze(io: &str) -> IResult<&str, Option<&str>> {
+        let (io, _) = word("ANALYZE")(io)?;
+        let (io, name) = opt(name).parse(io)?;
+        let (io, _) = end(io)?;
+        Ok((io, name))
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct IndexDef<'a> {
+        pub unique: bool,
+        /// Indexed columns, with their ASC/DESC/COLLATE suffix if any.
+        pub columns: Vec<(&'a str, &'a str)>,
+        /// Whether the index only covers rows matching a WHERE clause.
+        pub partial: bool,
+    }
+
+    /// Parts of a CREATE INDEX statement.
+    pub fn index(io: &str) -> IndexDef<'_> {
+        let head = io
+            .split('(')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        let tail = io
+            .rsplit(')')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        let columns = split_body(io)
+            .into_iter()
+            .map(|c| {
+                let (col, rest) = identifier(c);
+                (col, rest.trim())
+            })
+            .collect();
+        IndexDef {
+            unique: head.split_whitespace().any(|w| w == "UNIQUE"),
+            columns,
+            partial: tail.split_whitespace().any(|w| w == "WHERE"),
+        }
+    }
+
+    /// Column sets of the UNIQUE and PRIMARY KEY constraints of a table in
+    /// the order sqlite3 numbers their `sqlite_autoindex_*` indexes. A lone
+    /// INTEGER PRIMARY KEY is the rowid and gets no index.
+    pub fn unique_keys(io: &str) -> Vec<Vec<&str>> {
+        let items = split_body(io);
+        let (constraints, defs): (Vec<&str>, Vec<&str>) = items
+            .into_iter()
+            .partition(|i| starts_with_keyword(i, &TABLE_CONSTRAINTS));
+        let mut keys = Vec::new();
+        for def in defs {
+            let col = column_def(def);
+            let upper = def.to_ascii_uppercase();
+            let words = upper.split_whitespace().collect::<Vec<_>>();
+            let rowid = col.kind.eq_ignore_ascii_case("INTEGER");
+            if col.primary_key && !rowid || words.contains(&"UNIQUE") {
+                keys.push(vec![col.name]);
+            }
+        }
+        let cols = columns(io);
+        for c in constraints {
+            let upper = c.to_ascii_uppercase();
+            if !upper.contains("UNIQUE") && !upper.contains("PRIMARY KEY") {
+                continue;
+            }
+            let key = split_body(c)
+                .into_iter()
+                .map(|k| identifier(k).0)
+                .collect::<Vec<_>>();
+            let rowid = match key.as_slice() {
+                [k] => cols.iter().any(|c| {
+                    c.name.eq_ignore_ascii_case(k) && c.kind.eq_ignore_ascii_case("INTEGER")
+                }),
+                _ => false,
+            };
+            if !(upper.contains("PRIMARY KEY") && rowid) {
+                keys.push(key);
+            }
+        }
+        keys
     }
 }
 
@@ -90,6 +820,141 @@ mod tests {
     use super::*;
     const SQL_CREATE: &str = "CREATE TABLE butterscotch (id integer primary key, grape text,eye_color text,coconut text,coffee text,butterscotch text)";
 
+    #[test]
+    fn test_varint_roundtrip() {
+        let cases = [
+            0,
+            1,
+            127,
+            128,
+            240,
+            2287,
+            16383,
+            16384,
+            1 << 56,
+            -1,
+            i64::MIN,
+        ];
+        for v in cases {
+            let buf = varint::encode(v);
+            assert!(buf.len() <= 9);
+            assert_eq!(varint::take(&buf).unwrap(), (&[] as &[u8], v));
+        }
+        assert_eq!(varint::encode(127), [0x7F]);
+        assert_eq!(varint::encode(128), [0x81, 0x00]);
+        assert_eq!(varint::encode(-1).len(), 9);
+    }
+
+    #[test]
+    fn test_two_complements_decode() {
+        assert_eq!(two_complements::decode(&[0x05]).unwrap(), 5);
+        assert_eq!(two_complements::decode(&[0xFF]).unwrap(), -1);
+        assert_eq!(two_complements::decode(&[0x01, 0x2C]).unwrap(), 300);
+        assert_eq!(two_complements::decode(&[0xFE, 0xD4]).unwrap(), -300);
+        assert_eq!(two_complements::decode(&[0x01, 0x11, 0x70]).unwrap(), 70000);
+    }
+
+    #[test]
+    fn test_text_decode_utf16() {
+        let le = [0x68, 0x00, 0xED, 0x00, 0x3D, 0xD8, 0x00, 0xDE];
+        let be = [0x00, 0x68, 0x00, 0xED, 0xD8, 0x3D, 0xDE, 0x00];
+        let expected = "h\u{ed}\u{1f600}";
+        assert_eq!(
+            text::decode(&le, text::Encoding::Utf16le).unwrap(),
+            expected
+        );
+        assert_eq!(
+            text::decode(&be, text::Encoding::Utf16be).unwrap(),
+            expected
+        );
+        assert!(text::decode(&[0x3D, 0xD8], text::Encoding::Utf16le).is_err());
+    }
+
+    #[test]
+    fn test_sql_columns() {
+        let sql = "CREATE TABLE t (\"my id\" INTEGER, price DECIMAL(10, 2) NOT NULL, c, PRIMARY KEY (\"my id\"))";
+        let cols = sql::columns(sql);
+        assert_eq!(
+            cols.iter()
+                .map(|c| (c.name, c.kind, c.primary_key))
+                .collect::<Vec<_>>(),
+            [
+                ("my id", "INTEGER", true),
+                ("price", "DECIMAL(10, 2)", false),
+                ("c", "", false)
+            ]
+        );
+        assert_eq!(sql::rowid_alias(&cols), Some(0));
+        let cols = sql::columns("CREATE TABLE t (a TEXT COLLATE nocase UNIQUE, b)");
+        assert_eq!((cols[0].collate, cols[1].collate), (Some("nocase"), None));
+        // Keywords inside literals are not constraints.
+        let autoincrement = |sql| {
+            let cols = sql::columns(sql);
+            cols.iter()
+                .map(|c| (c.primary_key, c.autoincrement))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            autoincrement("CREATE TABLE t(id INTEGER PRIMARY KEY, n DEFAULT 'no AUTOINCREMENT')"),
+            [(true, false), (false, false)]
+        );
+        assert_eq!(
+            autoincrement("CREATE TABLE t(id INTEGER PRIMARY KEY AUTOINCREMENT, n NOT NULL)"),
+            [(true, true), (false, false)]
+        );
+        assert_eq!(
+            autoincrement("CREATE TABLE t(id INTEGER, n DEFAULT 'PRIMARY KEY', PRIMARY KEY(id AUTOINCREMENT))"),
+            [(true, true), (false, false)]
+        );
+        assert!(!sql::without_rowid(sql));
+        assert!(sql::without_rowid(
+            "CREATE TABLE t (a PRIMARY KEY) WITHOUT ROWID"
+        ));
+    }
+
+    #[test]
+    fn test_sql_insert() {
+        let sql = "insert into fruits (name, qty) values ('it''s', -1.5e3), (X'00ff', NULL);";
+        let (rest, insert) = sql::insert(sql).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(insert.table, "fruits");
+        assert_eq!(insert.columns, Some(vec!["name", "qty"]));
+        assert_eq!(insert.rows, [["'it''s'", "-1.5e3"], ["X'00ff'", "NULL"]]);
+    }
+
+    #[test]
+    fn test_sql_update_delete() {
+        let (_, update) = sql::update("UPDATE t SET a = 1, \"b c\" = 'x' WHERE id = 3").unwrap();
+        assert_eq!(update.table, "t");
+        assert_eq!(update.set, [("a", "1"), ("b c", "'x'")]);
+        assert_eq!(update.cond, Some(("id", "3")));
+        assert_eq!(
+            sql::comparison("\"b c\" like 'a=b%';"),
+            Ok(("", ("b c", "like", "'a=b%'")))
+        );
+        assert!(sql::comparison("color > 5").is_err());
+        let (rest, delete) = sql::delete("delete from t;").unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(delete.cond, None);
+        assert_eq!(
+            sql::control("begin immediate transaction;"),
+            Ok(("", sql::Control::Begin))
+        );
+        assert_eq!(sql::control("END"), Ok(("", sql::Control::Commit)));
+        let (_, pragma) = sql::pragma("PRAGMA wal_checkpoint(TRUNCATE);").unwrap();
+        assert_eq!(pragma.name, "wal_checkpoint");
+        assert_eq!(pragma.arg, Some("TRUNCATE"));
+    }
+
+    #[test]
+    fn test_sql_unique_keys() {
+        let sql = "CREATE TABLE t (id INTEGER PRIMARY KEY, a TEXT UNIQUE, b, c, UNIQUE (b, c))";
+        assert_eq!(sql::unique_keys(sql), [vec!["a"], vec!["b", "c"]]);
+        let index = sql::index("CREATE UNIQUE INDEX i ON t (a DESC, b)");
+        assert!(index.unique && !index.partial);
+        assert_eq!(index.columns, [("a", "DESC"), ("b", "")]);
+    }
+
     #[test]
     fn test_sql_column_names() {
         let names = sql::column_names(SQL_CREATE);
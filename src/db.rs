@@ -1,63 +1,774 @@
+pub mod analyze;
+pub mod btree;
+mod cache;
+mod check;
+mod freelist;
 mod header;
-mod table;
+mod io;
+mod journal;
+pub mod plan;
+pub mod stat;
+pub mod table;
+mod transaction;
+mod wal;
 
-use crate::offset::Offset;
-use crate::page::Page;
+use crate::chunk::Chunk;
+use crate::error::Error;
+use crate::page::{self, Format, Page};
 use crate::schema::Schema;
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Result};
+use cache::SharedCache;
+pub use cache::{Stats, DEFAULT_CACHE_SIZE};
+pub use freelist::Freelist;
 use header::Header;
-use std::cell::RefCell;
-use std::fs::File;
-use std::io::{BufReader, Read, Seek};
+pub use io::Backend;
+use io::{FileLock, Level, Storage};
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 use table::Table;
+pub use transaction::Transaction;
+pub use wal::Checkpoint;
+use wal::Wal;
 
-pub trait SQL {
-    fn schema(&self) -> Result<Schema>;
-    fn page_at(&self, idx: i64) -> Result<Page>;
-    fn table(&self, name: &str) -> Result<Table>;
+/// Read access to the pages of a database, either as committed on disk or as
+/// seen from inside a write transaction.
+#[allow(clippy::upper_case_acronyms)]
+pub trait SQL: Sync {
+    fn format(&self) -> Format;
+
+    /// Raw bytes of page `idx`, counting from one.
+    fn raw_page(&self, idx: i64) -> Result<Chunk>;
+
+    fn schema(&self) -> Result<Arc<Schema>>;
+
+    fn page_at(&self, idx: i64) -> Result<Page> {
+        // The first page starts right after the database header.
+        let pad = match idx {
+            1 => Header::size(),
+            _ => 0,
+        };
+        page::decode::take_page(self.raw_page(idx)?, pad, self.format())
+            .map_err(|e| e.on_page(idx).into())
+    }
+
+    fn table(&self, name: &str) -> Result<Table<'_>>
+    where
+        Self: Sized,
+    {
+        let schema = self.schema()?;
+        let desc = schema.table_named(name)?.clone();
+        Ok(Table::new(self, desc))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Options {
+    /// Byte budget of the page cache, zero disables it.
+    pub cache_size: usize,
+    pub backend: Backend,
+    /// Never open the file for writing, even when permissions allow it.
+    pub read_only: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            cache_size: DEFAULT_CACHE_SIZE,
+            backend: Backend::default(),
+            read_only: false,
+        }
+    }
+}
+
+/// sqlite3 lock a handle holds on its database file, and how many of its
+/// [`FileGuard`]s share it.
+struct FileLocks {
+    file: FileLock,
+    guards: usize,
+    /// Right to append to the log, held by the write transaction in WAL
+    /// mode.
+    wal: Option<wal::WriteLock>,
+}
+
+/// Hold on the locks of a database file: SHARED while any guard of the
+/// handle is alive, so that no other process commits meanwhile, and
+/// RESERVED, or the write lock of the log in WAL mode, as well while that
+/// of a write transaction is.
+pub struct FileGuard<'a> {
+    db: &'a SQLiteFile,
+    reserved: bool,
+}
+
+impl FileGuard<'_> {
+    /// Adds RESERVED, or the write lock of the log, failing when another
+    /// process is already writing.
+    fn reserve(&mut self) -> Result<()> {
+        let db = self.db;
+        let mut locks = db.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        if db.is_wal() {
+            let shm = wal::WriteLock::acquire(&db.path)?;
+            // Frames committed meanwhile are read before any are added.
+            db.catch_up(&mut db.wal.write().unwrap_or_else(PoisonError::into_inner))?;
+            locks.wal = Some(shm);
+        } else if !locks.file.lock(Level::Reserved)? {
+            bail!("database is locked");
+        }
+        self.reserved = true;
+        Ok(())
+    }
 }
 
+impl Drop for FileGuard<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.db.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        locks.guards -= 1;
+        let level = match locks.guards {
+            0 => Level::None,
+            _ => Level::Shared,
+        };
+        if self.reserved {
+            locks.wal = None;
+        }
+        if self.reserved || level == Level::None {
+            // Closing the file would release them as well.
+            let _ = locks.file.unlock(level);
+        }
+    }
+}
+
+/// Database handle. It is `Send + Sync`, so one open file can be shared, e.g.
+/// behind an `Arc`, by any number of concurrent readers. Writes go through a
+/// [`Transaction`], one at a time.
 pub struct SQLiteFile {
-    pub head: Header,
-    io: RefCell<BufReader<File>>,
+    path: PathBuf,
+    backend: Backend,
+    head: RwLock<Header>,
+    fmt: Format,
+    read_only: bool,
+    io: Box<dyn Storage>,
+    /// Pages committed to the write-ahead log, read in place of the file's.
+    wal: RwLock<Option<Wal>>,
+    cache: SharedCache,
+    schema: RwLock<Option<Arc<Schema>>>,
+    writer: Mutex<()>,
+    locks: Mutex<FileLocks>,
 }
 
 impl SQLiteFile {
-    pub fn open_at(name: &str) -> Result<SQLiteFile> {
-        let file = File::open(name)?;
-        let mut io = RefCell::new(BufReader::new(file));
-        let head = Header::read(io.get_mut())?;
-        Ok(Self { head, io })
+    pub fn open_with(name: &str, opts: Options) -> Result<SQLiteFile> {
+        let path = PathBuf::from(name);
+        let open = |writable: bool| match OpenOptions::new().read(true).write(writable).open(name) {
+            Ok(file) => Ok((file, writable)),
+            Err(e) if writable && e.kind() == std::io::ErrorKind::PermissionDenied => {
+                Ok((File::open(name)?, false))
+            }
+            Err(e) => Err(e),
+        };
+        let (file, writable) = open(!opts.read_only)?;
+        let mut lock = FileLock::new(file, writable);
+        if !lock.lock(Level::Shared)? {
+            bail!("database is locked");
+        }
+        // A commit was interrupted, put the database back as it was.
+        journal::recover(&path, &mut lock)?;
+        let (file, writable) = open(!opts.read_only && opts.backend != Backend::Mmap)?;
+        let read_only = !writable;
+        let io = opts.backend.open(file)?;
+        let wal = Wal::open(&wal::path(&path), opts.backend, !read_only)?;
+        let first = match wal.as_ref().map(|w| w.page(1)).transpose()?.flatten() {
+            Some(page) => page,
+            None => io.read_at(0, Header::size())?,
+        };
+        let head = Header::read(&mut &first[..Header::size()])?;
+        let page_size = head.page_size() as usize;
+        if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+            bail!(Error::corrupt(format!("invalid page size {page_size}")));
+        }
+        if let Some(wal) = &wal {
+            ensure!(
+                wal.page_size() == page_size,
+                "WAL page size {} does not match the database",
+                wal.page_size()
+            );
+        }
+        let fmt = Format {
+            page_size,
+            usable: page_size - head.reserved_page_size() as usize,
+            enc: head.text_encoding()?,
+        };
+        if fmt.usable < 480 {
+            bail!(Error::corrupt(format!("usable page size {}", fmt.usable)));
+        }
+        let cache = SharedCache::new(opts.cache_size);
+        lock.unlock(Level::None)?;
+        Ok(Self {
+            path,
+            backend: opts.backend,
+            head: RwLock::new(head),
+            fmt,
+            read_only,
+            io,
+            wal: RwLock::new(wal),
+            cache,
+            schema: RwLock::default(),
+            writer: Mutex::default(),
+            locks: Mutex::new(FileLocks {
+                file: lock,
+                guards: 0,
+                wal: None,
+            }),
+        })
+    }
+
+    /// Holds SHARED on the file until the guard is dropped, as a sqlite3
+    /// read transaction does. Taking it first plays back a hot journal, and
+    /// forgets pages and schema read before another process committed.
+    pub fn lock_shared(&self) -> Result<FileGuard<'_>> {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        if locks.guards == 0 {
+            if !locks.file.lock(Level::Shared)? {
+                bail!("database is locked");
+            }
+            if let Err(e) = self.refresh(&mut locks.file) {
+                let _ = locks.file.unlock(Level::None);
+                return Err(e);
+            }
+        }
+        locks.guards += 1;
+        Ok(FileGuard {
+            db: self,
+            reserved: false,
+        })
+    }
+
+    /// Catches up with commits made since SHARED was last held, which the
+    /// change counter of the header tells about in rollback mode.
+    fn refresh(&self, lock: &mut FileLock) -> Result<()> {
+        let recovered = journal::recover(&self.path, lock)?;
+        if self.is_wal() {
+            return self.catch_up(&mut self.wal.write().unwrap_or_else(PoisonError::into_inner));
+        }
+        let head = Header::read(&mut &self.io.read_at(0, Header::size())?[..])?;
+        if recovered || head.change_counter() != self.header().change_counter() {
+            self.cache.clear();
+            *self.head.write().unwrap_or_else(PoisonError::into_inner) = head;
+            *self.schema.write().unwrap_or_else(PoisonError::into_inner) = None;
+        }
+        Ok(())
+    }
+
+    /// Reads the commits other connections added to the log, or the log
+    /// they started over, forgetting what was read before them.
+    fn catch_up(&self, wal: &mut Option<Wal>) -> Result<()> {
+        let path = wal::path(&self.path);
+        if !wal::refresh(wal, &path, self.backend, !self.read_only)? {
+            return Ok(());
+        }
+        if let Some(log) = wal.as_ref() {
+            ensure!(
+                log.page_size() == self.fmt.page_size,
+                "WAL page size {} does not match the database",
+                log.page_size()
+            );
+        }
+        let first = match wal.as_ref().map(|w| w.page(1)).transpose()?.flatten() {
+            Some(page) => page,
+            None => self.io.read_at(0, Header::size())?,
+        };
+        let head = Header::read(&mut &first[..Header::size()])?;
+        self.cache.clear();
+        *self.head.write().unwrap_or_else(PoisonError::into_inner) = head;
+        *self.schema.write().unwrap_or_else(PoisonError::into_inner) = None;
+        Ok(())
+    }
+
+    pub fn header(&self) -> Header {
+        *self.head.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Pages in the database, as of the last commit in the write-ahead log
+    /// if any, from the header when it can be trusted otherwise.
+    pub fn page_count(&self) -> Result<u32> {
+        let wal = self.wal.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(pages) = wal.as_ref().and_then(Wal::page_count) {
+            return Ok(pages);
+        }
+        let len = self.io.len()? / self.fmt.page_size as u64;
+        Ok(self.header().page_count().unwrap_or(len as u32))
+    }
+
+    /// Free pages, as counted by the header.
+    pub fn freelist_count(&self) -> u32 {
+        self.header().freelist_count()
+    }
+
+    /// Pages on the freelist, decoded from the trunk chain.
+    pub fn freelist(&self) -> Result<Freelist> {
+        Freelist::read(self, self.header().freelist_trunk(), self.page_count()?)
+    }
+
+    /// Problems found by a full structural check of the file, at most
+    /// `limit` of them.
+    pub fn integrity_check(&self, limit: usize) -> Result<Vec<String>> {
+        check::integrity_check(self, self.page_count()?, limit)
+    }
+
+    pub fn cache_stats(&self) -> Stats {
+        self.cache.stats()
+    }
+
+    /// Whether writes are refused, as asked for or as the file allows.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Starts the only write transaction allowed at a time, waiting for the
+    /// current one to end.
+    pub fn begin(&self) -> Result<Transaction<'_>> {
+        if self.read_only {
+            bail!("attempt to write a readonly database");
+        }
+        let lock = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut file = self.lock_shared()?;
+        file.reserve()?;
+        Transaction::new(self, file, lock)
+    }
+
+    /// Whether commits go to the write-ahead log.
+    fn is_wal(&self) -> bool {
+        let wal = self.wal.read().unwrap_or_else(PoisonError::into_inner);
+        wal.is_some() || self.header().is_wal()
+    }
+
+    /// Copies the pages committed to the write-ahead log back into the
+    /// database file, as far as sqlite3 readers of older snapshots allow.
+    /// Returns whether readers kept a FULL, RESTART or TRUNCATE checkpoint
+    /// from finishing, how many frames the log holds and how many were
+    /// copied, both -1 outside WAL mode.
+    pub fn checkpoint(&self, mode: Checkpoint) -> Result<(bool, i64, i64)> {
+        if self.read_only {
+            bail!("attempt to write a readonly database");
+        }
+        let _lock = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let shm = wal::WriteLock::acquire(&self.path)?;
+        shm.lock_checkpoint()?;
+        let mut wal = self.wal.write().unwrap_or_else(PoisonError::into_inner);
+        self.catch_up(&mut wal)?;
+        let Some(log) = wal.as_mut() else {
+            return Ok(match self.header().is_wal() {
+                true => (false, 0, 0),
+                false => (false, -1, -1),
+            });
+        };
+        let frames = log.frame_count();
+        let safe = shm.safe_frames(frames)?;
+        let size = self.fmt.page_size as u64;
+        for (idx, data) in log.pages(safe)? {
+            self.io.write_at((idx - 1) as u64 * size, &data)?;
+        }
+        if let Some(pages) = log.page_count().filter(|_| safe == frames) {
+            if self.io.len()? > pages as u64 * size {
+                self.io.truncate(pages as u64 * size)?;
+            }
+        }
+        self.io.sync()?;
+        if safe == frames {
+            log.backfilled();
+        }
+        // The log only starts over, or empties, with no reader left in it.
+        let busy = match mode {
+            Checkpoint::Passive => false,
+            Checkpoint::Full => safe < frames,
+            Checkpoint::Restart | Checkpoint::Truncate => {
+                safe < frames || !shm.exclude_readers()?
+            }
+        };
+        if mode == Checkpoint::Truncate && !busy {
+            log.truncate()?;
+            shm.invalidate_index()?;
+            return Ok((false, 0, 0));
+        }
+        Ok((busy, frames as i64, safe as i64))
+    }
+
+    /// Writes the pages of a committed transaction and the updated header,
+    /// atomically: either as frames of the write-ahead log, or in place with
+    /// the original pages saved to the rollback journal first.
+    fn apply(&self, head: Header, pages: &[(i64, Chunk)]) -> Result<()> {
+        if self.is_wal() {
+            let locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+            let shm = locks
+                .wal
+                .as_ref()
+                .context("the log is not locked for writing")?;
+            let mut wal = self.wal.write().unwrap_or_else(PoisonError::into_inner);
+            let log = match wal.as_mut() {
+                Some(log) => log,
+                None => wal.insert(Wal::create(&wal::path(&self.path), self.fmt.page_size)?),
+            };
+            let count = head.page_count().context("missing database size")?;
+            // A backfilled log starts over once no sqlite3 reader is in it.
+            let restart = log.is_backfilled() && shm.exclude_readers()?;
+            let done = log
+                .commit(pages, count, restart)
+                .and_then(|()| shm.invalidate_index());
+            if restart {
+                shm.admit_readers()?;
+            }
+            done?;
+            drop(wal);
+            drop(locks);
+            self.applied(head, pages);
+            return Ok(());
+        }
+
+        // Readers of other processes are gone once EXCLUSIVE is held.
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        if !locks.file.lock(Level::Exclusive)? {
+            bail!("database is locked");
+        }
+        let done = self.write_journaled(pages);
+        locks.file.unlock(Level::Reserved)?;
+        done?;
+        self.applied(head, pages);
+        Ok(())
+    }
+
+    /// Writes `pages` in place, their original content saved to the
+    /// rollback journal first.
+    fn write_journaled(&self, pages: &[(i64, Chunk)]) -> Result<()> {
+        let size = self.fmt.page_size;
+        let count = self.page_count()?;
+        let path = journal::path(&self.path);
+        let journal = (|| -> Result<journal::Journal> {
+            let mut journal = journal::Journal::create(path.clone(), count, size)?;
+            for (idx, _) in pages.iter().filter(|(idx, _)| *idx <= count as i64) {
+                journal.append(*idx, &self.raw_page(*idx)?)?;
+            }
+            journal.seal()?;
+            Ok(journal)
+        })();
+        let journal = match journal {
+            Ok(journal) => journal,
+            Err(e) => {
+                // The database is untouched, an unsealed journal is harmless.
+                let _ = io::remove(&path);
+                return Err(e);
+            }
+        };
+
+        let written = pages
+            .iter()
+            .try_for_each(|(idx, data)| self.io.write_at((*idx - 1) as u64 * size as u64, data))
+            .and_then(|()| self.io.sync())
+            .and_then(|()| journal.remove());
+        if let Err(e) = written {
+            for (idx, _) in pages {
+                self.cache.remove(*idx);
+            }
+            // Best effort, a journal still there is played back by the next
+            // reader.
+            let _ = journal::rollback(&path, &*self.io);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Makes a commit visible to readers of this handle.
+    fn applied(&self, head: Header, pages: &[(i64, Chunk)]) {
+        for (idx, _) in pages {
+            self.cache.remove(*idx);
+        }
+        *self.head.write().unwrap_or_else(PoisonError::into_inner) = head;
+        *self.schema.write().unwrap_or_else(PoisonError::into_inner) = None;
     }
 }
 
 impl SQL for SQLiteFile {
-    fn schema(&self) -> Result<Schema> {
-        decode::read_page(self, 0i64.into(), Header::size()).and_then(Schema::try_from)
+    fn format(&self) -> Format {
+        self.fmt
     }
 
-    fn page_at(&self, idx: i64) -> Result<Page> {
-        let idx = idx - 1;
-        let offset = idx * self.head.page_size() as i64;
-        decode::read_page(self, offset.into(), 0)
+    fn raw_page(&self, idx: i64) -> Result<Chunk> {
+        ensure!(idx > 0, "invalid page number {idx}");
+        if let Some(page) = self.cache.get(idx) {
+            return Ok(page);
+        }
+        let wal = self.wal.read().unwrap_or_else(PoisonError::into_inner);
+        let page = match wal.as_ref().map(|w| w.page(idx)).transpose()?.flatten() {
+            Some(page) => page,
+            None => {
+                let size = self.fmt.page_size;
+                self.io.read_at((idx - 1) as u64 * size as u64, size)?
+            }
+        };
+        self.cache.insert(idx, page.clone());
+        Ok(page)
     }
 
-    fn table(&self, name: &str) -> Result<Table> {
-        let schema = self.schema()?;
-        let desc = schema.table_named(name)?.clone();
-        let root = self.page_at(desc.root)?;
-        Ok(Table::new(self, desc, root))
+    fn schema(&self) -> Result<Arc<Schema>> {
+        let cached = self.schema.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(schema) = cached.as_ref() {
+            return Ok(schema.clone());
+        }
+        drop(cached);
+        let schema = Schema::read(self).map(Arc::new)?;
+        let mut cached = self.schema.write().unwrap_or_else(PoisonError::into_inner);
+        Ok(cached.get_or_insert(schema).clone())
     }
 }
-mod decode {
+
+#[cfg(test)]
+mod tests {
     use super::*;
-    use crate::page;
-
-    pub fn read_page(db: &SQLiteFile, adr: Offset, pad: usize) -> Result<Page> {
-        let file = &mut db.io.borrow_mut();
-        file.seek(adr.into())?;
-        let mut data = vec![0u8; db.head.page_size() as usize];
-        file.read_exact(&mut data)?;
-        page::decode::take_page(&data, pad)
+    use crate::args::{ColumnNames, Condition, Operator};
+    use crate::db::transaction::tests::create_index;
+    use crate::fixture::{TempDb, SAMPLE_DB};
+    use crate::value::{Collation, Value};
+
+    #[test]
+    fn test_concurrent_readers_share_handle() {
+        fn assert_shareable<T: Send + Sync>(_: &T) {}
+
+        for backend in [Backend::File, Backend::Mmap] {
+            let opts = Options {
+                backend,
+                ..Options::default()
+            };
+            let db = Arc::new(SQLiteFile::open_with(SAMPLE_DB, opts).unwrap());
+            assert_shareable(&db);
+
+            let workers = (0..8)
+                .map(|_| {
+                    let db = db.clone();
+                    std::thread::spawn(move || {
+                        let cols = ColumnNames::from("name");
+                        let table = db.table("apples").unwrap();
+                        table.select(&cols, None).unwrap().count()
+                    })
+                })
+                .collect::<Vec<_>>();
+            for w in workers {
+                assert_eq!(w.join().unwrap(), 4);
+            }
+            assert!(db.cache_stats().hits > 0);
+        }
+    }
+
+    #[test]
+    fn test_insert_splits_pages() {
+        let file = TempDb::sample("insert");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+
+        let rows = (0..500)
+            .map(|i| vec![Value::Text(format!("apple {i}").repeat(20)), Value::Null])
+            .collect::<Vec<_>>();
+        let cols = ["name".to_string(), "color".to_string()];
+        let mut tx = db.begin().unwrap();
+        assert_eq!(tx.insert("apples", Some(&cols), rows).unwrap(), 500);
+        tx.commit().unwrap();
+
+        let table = db.table("apples").unwrap();
+        assert_eq!(table.count(None).unwrap(), 504);
+        let ids = table
+            .select(&ColumnNames::from("id"), None)
+            .unwrap()
+            .flat_map(|row| row.unwrap().into_iter())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (1..=504).map(Value::Int).collect::<Vec<_>>());
+
+        // A table merely mentioning AUTOINCREMENT keeps no sequence.
+        let mut tx = db.begin().unwrap();
+        let sql =
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, note TEXT DEFAULT 'no AUTOINCREMENT here')";
+        tx.create_table("t", sql).unwrap();
+        let note = ["note".to_string()];
+        let row = vec![vec![Value::Text("y".into())]];
+        assert_eq!(tx.insert("t", Some(&note), row).unwrap(), 1);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_writes_keep_commits_of_other_connections() {
+        let file = TempDb::sample("other-writer");
+        let ours = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let theirs = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let insert = |db: &SQLiteFile, name: &str| {
+            let mut tx = db.begin().unwrap();
+            let row = vec![Value::Null, Value::Text(name.into()), Value::Null];
+            tx.insert("apples", None, vec![row]).unwrap();
+            tx.commit().unwrap();
+        };
+        let names = |db: &SQLiteFile| {
+            let _file = db.lock_shared().unwrap();
+            db.table("apples")
+                .unwrap()
+                .select(&ColumnNames::from("name"), None)
+                .unwrap()
+                .map(|r| r.unwrap().into_iter().next().unwrap())
+                .skip(4)
+                .collect::<Vec<_>>()
+        };
+
+        insert(&ours, "ours 1");
+        assert_eq!(names(&ours).len(), 1);
+        insert(&theirs, "theirs");
+        // The change counter moved, what was read before is dropped.
+        assert_eq!(names(&ours).len(), 2);
+        insert(&ours, "ours 2");
+        let expected = ["ours 1", "theirs", "ours 2"].map(|n| Value::Text(n.into()));
+        assert_eq!(names(&theirs), expected);
+        assert!(ours.integrity_check(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_update_and_delete_rows() {
+        let file = TempDb::sample("update");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+
+        let cond = |name: &str, value| Condition {
+            name: name.to_string(),
+            op: Operator::Equal,
+            value,
+        };
+        let set = [("color".to_string(), Value::Text("Green".repeat(300)))];
+        let mut tx = db.begin().unwrap();
+        assert_eq!(
+            tx.update("apples", &set, Some(cond("id", Value::Int(1))))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            tx.delete("apples", Some(cond("id", Value::Int(2))))
+                .unwrap(),
+            1
+        );
+        assert!(tx
+            .delete("apples", Some(cond("colour", Value::Null)))
+            .is_err());
+        // The second row fails after the first went in, neither is kept.
+        let rows = vec![
+            vec![Value::Null, Value::Text("Pink".into()), Value::Null],
+            vec![Value::Null],
+        ];
+        assert!(tx.insert("apples", None, rows).is_err());
+        tx.commit().unwrap();
+
+        let table = db.table("apples").unwrap();
+        let rows = table
+            .select(&ColumnNames::from("id,color"), None)
+            .unwrap()
+            .map(|row| row.unwrap().into_iter().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], [Value::Int(1), Value::Text("Green".repeat(300))]);
+        assert_eq!(rows[1][0], Value::Int(3));
+    }
+
+    #[test]
+    fn test_indexes_follow_column_collation() {
+        let file = TempDb::sample("collate");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let mut tx = db.begin().unwrap();
+        let sql = "CREATE TABLE people(id INTEGER PRIMARY KEY, name TEXT COLLATE NOCASE)";
+        tx.create_table("people", sql).unwrap();
+        let sql = "CREATE INDEX people_name ON people(name)";
+        create_index(&mut tx, "people_name", "people", sql).unwrap();
+        let rows = (0..300)
+            .map(|i| {
+                let name = format!("{} {i:03}", ["apple", "Apple", "BANANA", "banana"][i % 4]);
+                vec![Value::Null, Value::Text(name.repeat(5))]
+            })
+            .collect();
+        tx.insert("people", None, rows).unwrap();
+        let cond = |id| {
+            Some(Condition {
+                name: "id".to_string(),
+                op: Operator::Equal,
+                value: Value::Int(id),
+            })
+        };
+        let set = [("name".to_string(), Value::Text("aPPLE".to_string()))];
+        tx.update("people", &set, cond(7)).unwrap();
+        tx.delete("people", cond(8)).unwrap();
+        tx.commit().unwrap();
+
+        let root = db
+            .schema()
+            .unwrap()
+            .desc
+            .iter()
+            .find(|d| d.name == "people_name")
+            .unwrap()
+            .root;
+        let entries = btree::IndexEntries::new(&db, root)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 299);
+        let order = |c| {
+            let mut pairs = entries.iter().zip(&entries[1..]);
+            pairs.all(|(a, b)| btree::compare_keys(a, b, &[c]).is_lt())
+        };
+        assert!(order(Collation::NoCase));
+        assert!(!order(Collation::Binary));
+        assert!(db.integrity_check(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_commit_survives_crash_at_every_step() {
+        let file = TempDb::new("crash");
+        let name = file.name();
+        let rows = |name: &str| {
+            let db = SQLiteFile::open_with(name, Options::default()).unwrap();
+            let table = db.table("apples").unwrap();
+            let rows = table
+                .select(&ColumnNames::from("id,name,color"), None)
+                .unwrap()
+                .map(|row| row.unwrap().into_iter().collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            let len = std::fs::metadata(name).unwrap().len();
+            let size = db.format().page_size as u64;
+            assert_eq!(db.header().page_count(), Some((len / size) as u32));
+            rows
+        };
+        let commit = |db: &SQLiteFile| {
+            let mut tx = db.begin()?;
+            let rows = (0..100)
+                .map(|i| vec![Value::Text(format!("apple {i}").repeat(30)), Value::Null])
+                .collect();
+            let cols = ["name".to_string(), "color".to_string()];
+            tx.insert("apples", Some(&cols), rows)?;
+            let set = [("color".to_string(), Value::Text("Red".repeat(2000)))];
+            let id = Condition {
+                name: "id".to_string(),
+                op: Operator::Equal,
+                value: Value::Int(1),
+            };
+            tx.update("apples", &set, Some(id))?;
+            tx.commit()
+        };
+
+        std::fs::copy(SAMPLE_DB, file.path()).unwrap();
+        let before = rows(name);
+        for budget in 0.. {
+            std::fs::copy(SAMPLE_DB, file.path()).unwrap();
+            let db = SQLiteFile::open_with(name, Options::default()).unwrap();
+            io::fault::arm(Some(budget));
+            let res = commit(&db);
+            io::fault::arm(None);
+            drop(db);
+
+            let after = rows(name);
+            assert!(!journal::path(file.path()).exists());
+            if res.is_ok() {
+                assert_eq!(after.len(), 104);
+                assert_eq!(after[0][2], Value::Text("Red".repeat(2000)));
+                // Journal writes and syncs, page writes, sync and delete.
+                assert!(budget > 20);
+                break;
+            }
+            assert_eq!(after, before, "crash after {budget} file changes");
+        }
     }
 }
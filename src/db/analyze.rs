@@ -0,0 +1,221 @@
+use crate::db::btree::MAX_DEPTH;
+use crate::db::SQL;
+use crate::page::{self, Kind};
+use crate::schema::Type;
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+
+const KINDS: [(Kind, &str); 4] = [
+    (Kind::TableInterior, "table interior"),
+    (Kind::TableLeaf, "table leaf"),
+    (Kind::IndexInterior, "index interior"),
+    (Kind::IndexLeaf, "index leaf"),
+];
+
+/// Disk usage of a single B-tree, as reported by `.analyze`.
+#[derive(Debug, Clone)]
+pub struct Usage {
+    pub name: String,
+    pub kind: Type,
+    pub pages: HashMap<Kind, usize>,
+    pub overflow_pages: usize,
+    pub depth: usize,
+    /// Children over all interior pages.
+    pub children: usize,
+    pub max_fanout: usize,
+    /// Cells holding a row or an index entry, on index interior pages too.
+    pub entries: usize,
+    /// Entries spilling into overflow pages.
+    pub overflowing: usize,
+    /// Payload bytes, overflow included.
+    pub payload: u64,
+    pub cells: usize,
+    /// On-page bytes of every cell.
+    pub cell_bytes: u64,
+    pub max_cell: usize,
+    pub unused: u64,
+}
+
+impl Usage {
+    fn new(name: &str, kind: Type) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            pages: HashMap::new(),
+            overflow_pages: 0,
+            depth: 0,
+            children: 0,
+            max_fanout: 0,
+            entries: 0,
+            overflowing: 0,
+            payload: 0,
+            cells: 0,
+            cell_bytes: 0,
+            max_cell: 0,
+            unused: 0,
+        }
+    }
+
+    /// B-tree pages, overflow pages excluded.
+    pub fn tree_pages(&self) -> usize {
+        self.pages.values().sum()
+    }
+
+    pub fn total_pages(&self) -> usize {
+        self.tree_pages() + self.overflow_pages
+    }
+
+    fn interior_pages(&self) -> usize {
+        self.pages[&Kind::TableInterior] + self.pages[&Kind::IndexInterior]
+    }
+
+    /// Walks the B-tree rooted at `root`.
+    fn walk(&mut self, db: &dyn SQL, root: i64) -> Result<()> {
+        let fmt = db.format();
+        for (kind, _) in KINDS {
+            self.pages.insert(kind, 0);
+        }
+        let mut stack = vec![(root, 1)];
+        while let Some((idx, depth)) = stack.pop() {
+            ensure!(depth <= MAX_DEPTH, "B-tree {} is too deep", self.name);
+            let page = db.page_at(idx)?;
+            let kind = page.head.kind;
+            *self.pages.entry(kind).or_default() += 1;
+            self.depth = self.depth.max(depth);
+            self.unused += page.unused()? as u64;
+
+            let cells = page.cell_slices()?;
+            for cell in &cells {
+                self.cells += 1;
+                self.cell_bytes += cell.len() as u64;
+                self.max_cell = self.max_cell.max(cell.len());
+                let payload = match kind {
+                    Kind::TableInterior => Some(None),
+                    Kind::TableLeaf => page::decode::take_leaf_cell(cell, fmt)
+                        .map(|(_, c)| Some(c.payload))
+                        .ok(),
+                    k => page::decode::take_index_cell(cell, k, fmt)
+                        .map(|(_, c)| Some(c.payload))
+                        .ok(),
+                };
+                let Some(payload) = payload else {
+                    bail!("Cell decoding failed on page {idx}");
+                };
+                if let Some(payload) = payload {
+                    let spilled = payload.len - payload.local.len();
+                    self.payload += payload.len as u64;
+                    if spilled > 0 {
+                        self.overflowing += 1;
+                        self.overflow_pages += spilled.div_ceil(fmt.overflow_size());
+                    }
+                }
+            }
+
+            if kind.is_interior() {
+                let right = page.head.right_leave.context("Missing right leave")?;
+                let mut children = cells
+                    .iter()
+                    .map(|c| Ok(u32::from_be_bytes(c[..4].try_into()?) as i64))
+                    .collect::<Result<Vec<_>>>()?;
+                children.push(right as i64);
+                self.children += children.len();
+                self.max_fanout = self.max_fanout.max(children.len());
+                stack.extend(children.into_iter().rev().map(|c| (c, depth + 1)));
+            }
+            if kind != Kind::TableInterior {
+                self.entries += cells.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Type::Table => "table",
+            Type::Index => "index",
+            Type::View => "view",
+            Type::Trigger => "trigger",
+        };
+        let avg = |sum: f64, count: usize| match count {
+            0 => 0.0,
+            n => sum / n as f64,
+        };
+        let by_kind = KINDS
+            .iter()
+            .filter(|(k, _)| self.pages[k] > 0)
+            .map(|(k, name)| format!("{name} {}", self.pages[k]))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(f, "*** {} ({kind}) ***", self.name)?;
+        writeln!(
+            f,
+            "pages: {} ({by_kind}), overflow {}",
+            self.total_pages(),
+            self.overflow_pages
+        )?;
+        writeln!(
+            f,
+            "depth: {}, fanout: avg {:.1}, max {}",
+            self.depth,
+            avg(self.children as f64, self.interior_pages()),
+            self.max_fanout
+        )?;
+        writeln!(
+            f,
+            "entries: {}, {} using overflow",
+            self.entries, self.overflowing
+        )?;
+        writeln!(
+            f,
+            "payload: {} bytes, avg {:.1} per entry",
+            self.payload,
+            avg(self.payload as f64, self.entries)
+        )?;
+        writeln!(
+            f,
+            "cell size: avg {:.1}, max {}",
+            avg(self.cell_bytes as f64, self.cells),
+            self.max_cell
+        )?;
+        write!(f, "unused: {} bytes", self.unused)
+    }
+}
+
+/// Disk usage of every B-tree in the database, the schema table first.
+pub fn analyze(db: &dyn SQL) -> Result<Vec<Usage>> {
+    let mut usage = Usage::new("sqlite_schema", Type::Table);
+    usage.walk(db, 1)?;
+    let mut all = vec![usage];
+    for desc in db.schema()?.desc.iter().filter(|d| d.root > 0) {
+        let mut usage = Usage::new(&desc.name, desc.kind);
+        usage.walk(db, desc.root)?;
+        all.push(usage);
+    }
+    Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Options, SQLiteFile};
+    use crate::fixture::SAMPLE_DB;
+
+    #[test]
+    fn test_analyze_accounts_for_every_page() {
+        let db = SQLiteFile::open_with(SAMPLE_DB, Options::default()).unwrap();
+        let usage = analyze(&db).unwrap();
+        let used: usize = usage.iter().map(Usage::total_pages).sum();
+        let free = db.freelist().unwrap().len();
+        assert_eq!(used + free, db.page_count().unwrap() as usize);
+
+        let apples = usage.iter().find(|u| u.name == "apples").unwrap();
+        assert_eq!((apples.entries, apples.depth), (4, 1));
+        assert_eq!(apples.pages[&Kind::TableLeaf], 1);
+        let size = db.format().usable as u64;
+        let header = 8 + 2 * apples.cells as u64;
+        assert_eq!(apples.unused + apples.cell_bytes + header, size);
+    }
+}
@@ -0,0 +1,840 @@
+use crate::codec::varint;
+use crate::db::{Transaction, SQL};
+use crate::error::Error;
+use crate::page::{self, Kind, Page, Payload, Record, TableLeafPage};
+use crate::value::{Collation, Value};
+use anyhow::{bail, ensure, Context, Result};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::ops::Range;
+
+/// Depth first walk over the leaves of a table B-tree, in rowid order.
+pub struct Leaves<'a> {
+    db: &'a dyn SQL,
+    /// Pages still to visit, with their depth.
+    stack: Vec<(i64, usize)>,
+}
+
+impl<'a> Leaves<'a> {
+    pub fn new(db: &'a dyn SQL, root: i64) -> Self {
+        Self {
+            db,
+            stack: vec![(root, 0)],
+        }
+    }
+
+    fn descend(&mut self, idx: i64, depth: usize) -> Result<Option<TableLeafPage>> {
+        if depth > MAX_DEPTH {
+            // Only a loop in the page graph gets this deep.
+            self.stack.clear();
+            bail!(Error::corrupt("B-tree too deep").on_page(idx));
+        }
+        let page = self.db.page_at(idx)?;
+        match page.head.kind {
+            Kind::TableLeaf => Ok(Some(page.into_leaf()?)),
+            Kind::TableInterior => {
+                let children = page.into_interior()?.children()?;
+                self.stack
+                    .extend(children.into_iter().rev().map(|c| (c, depth + 1)));
+                Ok(None)
+            }
+            k => bail!("Unexpected {k:?} page {idx} in table b-tree"),
+        }
+    }
+}
+
+impl Iterator for Leaves<'_> {
+    type Item = Result<TableLeafPage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((idx, depth)) = self.stack.pop() {
+            match self.descend(idx, depth) {
+                Ok(None) => continue,
+                res => return res.transpose(),
+            }
+        }
+        None
+    }
+}
+
+/// Splits the tree under `root` at interior page boundaries into at least
+/// `count` disjoint subtrees, when deep enough, listed in rowid order.
+pub fn partitions(db: &dyn SQL, root: i64, count: usize) -> Result<Vec<i64>> {
+    let mut parts = vec![root];
+    while parts.len() < count {
+        let mut next = Vec::with_capacity(parts.len());
+        let mut split = false;
+        for idx in parts {
+            let page = db.page_at(idx)?;
+            match page.head.kind {
+                Kind::TableInterior => {
+                    next.extend(page.into_interior()?.children()?);
+                    split = true;
+                }
+                _ => next.push(idx),
+            }
+        }
+        parts = next;
+        if !split {
+            break;
+        }
+    }
+    Ok(parts)
+}
+
+/// Whole payload of a cell, read from its overflow chain when it does not
+/// fit on the page.
+pub fn payload<'a>(db: &dyn SQL, p: &Payload<'a>) -> Result<Cow<'a, [u8]>> {
+    let Some(mut next) = p.overflow else {
+        return Ok(Cow::Borrowed(p.local));
+    };
+    let size = db.format().overflow_size();
+    let mut buf = Vec::with_capacity(p.len);
+    buf.extend_from_slice(p.local);
+    while buf.len() < p.len {
+        ensure!(next != 0, "Overflow chain ends early");
+        let page = db.raw_page(next as i64)?;
+        let take = size.min(p.len - buf.len());
+        buf.extend_from_slice(&page[4..4 + take]);
+        next = u32::from_be_bytes(page[..4].try_into()?);
+    }
+    Ok(Cow::Owned(buf))
+}
+
+/// Largest rowid in the tree, zero when it is empty.
+pub fn max_rowid(db: &dyn SQL, root: i64) -> Result<i64> {
+    let mut idx = root;
+    for _ in 0..=MAX_DEPTH {
+        let page = db.page_at(idx)?;
+        match page.head.kind {
+            Kind::TableInterior => idx = page.into_interior()?.rhs()? as i64,
+            Kind::TableLeaf => {
+                let leaf = page.into_leaf()?;
+                return Ok(leaf.cells()?.last().map_or(0, |c| c.id));
+            }
+            k => bail!("Unexpected {k:?} page {idx} in table b-tree"),
+        }
+    }
+    bail!(Error::corrupt("B-tree too deep"))
+}
+
+/// Rowid of a table cell, the key of both leaf and interior cells.
+fn cell_key(kind: Kind, cell: &[u8]) -> Result<i64> {
+    let key = match kind {
+        Kind::TableLeaf => varint::take(cell).and_then(|(io, _)| varint::take(io)),
+        _ => varint::take(cell.get(4..).unwrap_or_default()),
+    };
+    let Ok((_, key)) = key else {
+        bail!("Cell key decoding failed");
+    };
+    Ok(key)
+}
+
+/// Key of an index cell: the indexed values followed by the rowid.
+fn index_key(db: &dyn SQL, kind: Kind, cell: &[u8]) -> Result<Vec<Value>> {
+    let fmt = db.format();
+    let Ok((_, cell)) = page::decode::take_index_cell(cell, kind, fmt) else {
+        bail!("Index cell decoding failed");
+    };
+    let payload = payload(db, &cell.payload)?;
+    let record = Record::parse(&payload, fmt.enc)?;
+    Ok(record.values()?)
+}
+
+/// Index keys ordering, text in each column following its collation and
+/// BINARY past those given, a key sorting before every longer key it starts.
+pub fn compare_keys(a: &[Value], b: &[Value], collations: &[Collation]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .enumerate()
+        .map(|(i, (a, b))| a.collate(b, collations.get(i).copied().unwrap_or_default()))
+        .find(|o| o.is_ne())
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+fn cells_of(page: &Page) -> Result<Vec<Vec<u8>>> {
+    let cells = page.cell_slices()?;
+    Ok(cells.into_iter().map(<[u8]>::to_vec).collect())
+}
+
+/// First page of the file carries the database header.
+fn pad_of(idx: i64) -> usize {
+    match idx {
+        1 => 100,
+        _ => 0,
+    }
+}
+
+/// Interior pages from the root down, each with the index of the child
+/// followed, leading to a page being modified.
+type Path = Vec<(i64, usize)>;
+
+/// Path from the root down to the leaf that holds, or would hold, `rowid`,
+/// with the child index followed on every interior page.
+fn seek(db: &dyn SQL, root: i64, rowid: i64) -> Result<(Path, i64)> {
+    let mut path = Vec::new();
+    let mut idx = root;
+    loop {
+        let page = db.page_at(idx)?;
+        match page.head.kind {
+            Kind::TableLeaf => return Ok((path, idx)),
+            Kind::TableInterior => {
+                let interior = page.into_interior()?;
+                let cells = interior.cells()?;
+                let pos = cells.partition_point(|c| c.row < rowid);
+                path.push((idx, pos));
+                idx = match cells.get(pos) {
+                    Some(c) => c.lhs as i64,
+                    None => interior.rhs()? as i64,
+                };
+            }
+            k => bail!("Unexpected {k:?} page {idx} in table b-tree"),
+        }
+        if path.len() > MAX_DEPTH {
+            bail!(Error::corrupt("B-tree too deep"));
+        }
+    }
+}
+
+/// Deepest level sqlite3 itself accepts.
+pub(crate) const MAX_DEPTH: usize = 20;
+
+/// Position of an index key: the page holding it, or the leaf it would go
+/// to, and the cell index there.
+struct Slot {
+    path: Path,
+    idx: i64,
+    pos: usize,
+    found: bool,
+}
+
+fn seek_index(
+    tx: &Transaction,
+    root: i64,
+    key: &[Value],
+    collations: &[Collation],
+) -> Result<Slot> {
+    let mut path = Vec::new();
+    let mut idx = root;
+    loop {
+        let page = tx.page_at(idx)?;
+        let kind = page.head.kind;
+        ensure!(
+            !kind.is_table(),
+            "Unexpected {kind:?} page {idx} in index b-tree"
+        );
+        let cells = page.cell_slices()?;
+        let keys = cells
+            .iter()
+            .map(|c| index_key(tx, kind, c))
+            .collect::<Result<Vec<_>>>()?;
+        let order = |k: &Vec<Value>| compare_keys(k, key, collations);
+        let pos = keys.partition_point(|k| order(k).is_lt());
+        let found = keys.get(pos).is_some_and(|k| order(k).is_eq());
+        if found || kind == Kind::IndexLeaf {
+            return Ok(Slot {
+                path,
+                idx,
+                pos,
+                found,
+            });
+        }
+        path.push((idx, pos));
+        idx = child(&page, pos)?;
+        if path.len() > MAX_DEPTH {
+            bail!(Error::corrupt("B-tree too deep"));
+        }
+    }
+}
+
+/// Page number of child `pos` of an interior page, the right-most pointer
+/// following the cells.
+fn child(page: &Page, pos: usize) -> Result<i64> {
+    let cells = page.cell_slices()?;
+    let lhs = match cells.get(pos) {
+        Some(cell) => u32::from_be_bytes(cell[..4].try_into()?),
+        None => page.head.right_leave.context("Missing right leave")?,
+    };
+    Ok(lhs as i64)
+}
+
+/// Starts a new, empty B-tree of `kind` pages, returning its root.
+pub fn create(tx: &mut Transaction, kind: Kind) -> Result<i64> {
+    let root = tx.allocate()?;
+    write(tx, root, kind, &[], None)?;
+    Ok(root)
+}
+
+/// Adds a leaf cell for a new `rowid`, splitting pages up to the root when
+/// they run out of space.
+pub fn insert(tx: &mut Transaction, root: i64, rowid: i64, cell: Vec<u8>) -> Result<()> {
+    store(tx, root, rowid, cell, false)
+}
+
+/// Overwrites the leaf cell of an existing `rowid`.
+pub fn replace(tx: &mut Transaction, root: i64, rowid: i64, cell: Vec<u8>) -> Result<()> {
+    store(tx, root, rowid, cell, true)
+}
+
+fn store(tx: &mut Transaction, root: i64, rowid: i64, cell: Vec<u8>, replace: bool) -> Result<()> {
+    let (mut path, idx) = seek(tx, root, rowid)?;
+    let mut cells = cells_of(&tx.page_at(idx)?)?;
+    let keys = cells
+        .iter()
+        .map(|c| cell_key(Kind::TableLeaf, c))
+        .collect::<Result<Vec<_>>>()?;
+    let pos = keys.partition_point(|k| *k < rowid);
+    let found = keys.get(pos) == Some(&rowid);
+    match (found, replace) {
+        (true, false) => bail!("UNIQUE constraint failed: rowid {rowid}"),
+        (false, true) => bail!("No row with rowid {rowid}"),
+        (true, true) => {
+            let old = std::mem::replace(&mut cells[pos], cell);
+            free_overflow(tx, Kind::TableLeaf, &old)?;
+        }
+        (false, false) => cells.insert(pos, cell),
+    }
+    let appended = !replace && pos + 1 == cells.len();
+    if replace && !path.is_empty() && underfull(&cells, tx.format()) {
+        write(tx, idx, Kind::TableLeaf, &cells, None)?;
+        return rebalance(tx, path, idx);
+    }
+    place(tx, &mut path, idx, Kind::TableLeaf, cells, None, appended)
+}
+
+/// Decoded row stored under `rowid`, if any.
+pub fn row(db: &Transaction, root: i64, rowid: i64) -> Result<Option<Vec<Value>>> {
+    let (_, idx) = seek(db, root, rowid)?;
+    let leaf = db.page_at(idx)?.into_leaf()?;
+    let cells = leaf.cells()?;
+    let Some(cell) = cells.iter().find(|c| c.id == rowid) else {
+        return Ok(None);
+    };
+    let payload = payload(db, &cell.payload)?;
+    let record = Record::parse(&payload, leaf.enc())?;
+    Ok(Some(record.values()?))
+}
+
+/// Leaf page of the table holding `rowid`, or that would hold it.
+pub fn leaf(db: &dyn SQL, root: i64, rowid: i64) -> Result<i64> {
+    Ok(seek(db, root, rowid)?.1)
+}
+
+/// Removes the row stored under `rowid`, merging pages left too empty.
+pub fn delete(tx: &mut Transaction, root: i64, rowid: i64) -> Result<()> {
+    let (path, idx) = seek(tx, root, rowid)?;
+    let mut cells = cells_of(&tx.page_at(idx)?)?;
+    let keys = cells
+        .iter()
+        .map(|c| cell_key(Kind::TableLeaf, c))
+        .collect::<Result<Vec<_>>>()?;
+    let Ok(pos) = keys.binary_search(&rowid) else {
+        bail!("No row with rowid {rowid}");
+    };
+    let old = cells.remove(pos);
+    free_overflow(tx, Kind::TableLeaf, &old)?;
+    write(tx, idx, Kind::TableLeaf, &cells, None)?;
+    match !path.is_empty() && underfull(&cells, tx.format()) {
+        true => rebalance(tx, path, idx),
+        false => Ok(()),
+    }
+}
+
+/// Whether an index already holds an entry starting with `values`.
+pub fn index_contains(
+    tx: &Transaction,
+    root: i64,
+    values: &[Value],
+    collations: &[Collation],
+) -> Result<bool> {
+    let mut idx = root;
+    for _ in 0..=MAX_DEPTH {
+        let page = tx.page_at(idx)?;
+        let kind = page.head.kind;
+        let cells = page.cell_slices()?;
+        let mut pos = cells.len();
+        for (i, cell) in cells.iter().enumerate() {
+            let key = index_key(tx, kind, cell)?;
+            let prefix = &key[..values.len().min(key.len())];
+            match compare_keys(prefix, values, collations) {
+                Ordering::Less => continue,
+                Ordering::Equal => return Ok(true),
+                Ordering::Greater => {
+                    pos = i;
+                    break;
+                }
+            }
+        }
+        if kind == Kind::IndexLeaf {
+            return Ok(false);
+        }
+        idx = child(&page, pos)?;
+    }
+    bail!(Error::corrupt("B-tree too deep"))
+}
+
+/// Rowids of the index entries whose first value is `value`, in key order.
+pub fn index_rowids(db: &dyn SQL, root: i64, value: &Value) -> Result<Vec<i64>> {
+    index_seek(db, root, value)?
+        .iter()
+        .map(|key| match key.last() {
+            Some(Value::Int(rowid)) => Ok(*rowid),
+            _ => bail!(Error::corrupt("Index entry without rowid")),
+        })
+        .collect()
+}
+
+/// Index entries whose first value is `value`, in key order.
+pub fn index_seek(db: &dyn SQL, root: i64, value: &Value) -> Result<Vec<Vec<Value>>> {
+    let mut entries = Vec::new();
+    collect_entries(db, root, value, 0, &mut entries)?;
+    Ok(entries)
+}
+
+fn collect_entries(
+    db: &dyn SQL,
+    idx: i64,
+    value: &Value,
+    depth: usize,
+    entries: &mut Vec<Vec<Value>>,
+) -> Result<()> {
+    if depth > MAX_DEPTH {
+        bail!(Error::corrupt("B-tree too deep").on_page(idx));
+    }
+    let page = db.page_at(idx)?;
+    let kind = page.head.kind;
+    let cells = page.cell_slices()?;
+    for (i, cell) in cells.iter().enumerate() {
+        let key = index_key(db, kind, cell)?;
+        let order = key.first().map_or(Ordering::Less, |k| k.compare(value));
+        // Entries equal to `value` may start in the subtree left of a larger one.
+        if kind == Kind::IndexInterior && order.is_ge() {
+            collect_entries(db, child(&page, i)?, value, depth + 1, entries)?;
+        }
+        match order {
+            Ordering::Less => continue,
+            Ordering::Greater => return Ok(()),
+            // Comparing equal is looser than being equal, as 1 and 1.0 are.
+            Ordering::Equal if key[0] != *value => continue,
+            Ordering::Equal => entries.push(key),
+        }
+    }
+    if kind == Kind::IndexInterior {
+        collect_entries(db, child(&page, cells.len())?, value, depth + 1, entries)?;
+    }
+    Ok(())
+}
+
+/// Entries of the index under `root`, counted without decoding them.
+pub fn index_len(db: &dyn SQL, root: i64) -> Result<usize> {
+    let mut stack = vec![(root, 0)];
+    let mut len = 0;
+    while let Some((idx, depth)) = stack.pop() {
+        if depth > MAX_DEPTH {
+            bail!(Error::corrupt("B-tree too deep").on_page(idx));
+        }
+        let page = db.page_at(idx)?;
+        let cells = page.cell_slices()?;
+        len += cells.len();
+        if page.head.kind == Kind::IndexInterior {
+            for cell in &cells {
+                stack.push((u32::from_be_bytes(cell[..4].try_into()?) as i64, depth + 1));
+            }
+            stack.push((child(&page, cells.len())?, depth + 1));
+        }
+    }
+    Ok(len)
+}
+
+/// In-order walk over the entries of an index B-tree, each being the
+/// indexed values followed by the rowid.
+pub struct IndexEntries<'a> {
+    db: &'a dyn SQL,
+    /// Pages still to visit with their depth, and entries of the pages
+    /// visited still to hand out, the next one last.
+    stack: Vec<Pending>,
+}
+
+enum Pending {
+    Page(i64, usize),
+    Entry(Vec<Value>),
+}
+
+impl<'a> IndexEntries<'a> {
+    pub fn new(db: &'a dyn SQL, root: i64) -> Self {
+        Self {
+            db,
+            stack: vec![Pending::Page(root, 0)],
+        }
+    }
+
+    fn descend(&mut self, idx: i64, depth: usize) -> Result<()> {
+        if depth > MAX_DEPTH {
+            // Only a loop in the page graph gets this deep.
+            self.stack.clear();
+            bail!(Error::corrupt("B-tree too deep").on_page(idx));
+        }
+        let page = self.db.page_at(idx)?;
+        let kind = page.head.kind;
+        ensure!(
+            matches!(kind, Kind::IndexLeaf | Kind::IndexInterior),
+            "Unexpected {kind:?} page {idx} in index b-tree"
+        );
+        let interior = kind == Kind::IndexInterior;
+        let cells = page.cell_slices()?;
+        let mut pending = Vec::with_capacity(2 * cells.len() + 1);
+        for (i, cell) in cells.iter().enumerate() {
+            if interior {
+                pending.push(Pending::Page(child(&page, i)?, depth + 1));
+            }
+            pending.push(Pending::Entry(index_key(self.db, kind, cell)?));
+        }
+        if interior {
+            pending.push(Pending::Page(child(&page, cells.len())?, depth + 1));
+        }
+        self.stack.extend(pending.into_iter().rev());
+        Ok(())
+    }
+}
+
+impl Iterator for IndexEntries<'_> {
+    type Item = Result<Vec<Value>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(pending) = self.stack.pop() {
+            match pending {
+                Pending::Entry(key) => return Some(Ok(key)),
+                Pending::Page(idx, depth) => {
+                    if let Err(e) = self.descend(idx, depth) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Adds the index entry `key`, its last value being the rowid.
+pub fn index_insert(
+    tx: &mut Transaction,
+    root: i64,
+    key: &[Value],
+    collations: &[Collation],
+) -> Result<()> {
+    let Slot {
+        mut path,
+        idx,
+        pos,
+        found,
+    } = seek_index(tx, root, key, collations)?;
+    ensure!(!found, "Duplicate index entry");
+    let payload = page::encode::record(key, tx.format().enc);
+    let cell = tx.index_cell(&payload)?;
+    let mut cells = cells_of(&tx.page_at(idx)?)?;
+    cells.insert(pos, cell);
+    place(tx, &mut path, idx, Kind::IndexLeaf, cells, None, false)
+}
+
+/// Removes the index entry `key`. An entry on an interior page is replaced
+/// by the entry preceding it, taken out of a leaf.
+pub fn index_delete(
+    tx: &mut Transaction,
+    root: i64,
+    key: &[Value],
+    collations: &[Collation],
+) -> Result<()> {
+    let Slot {
+        mut path,
+        idx,
+        pos,
+        found,
+    } = seek_index(tx, root, key, collations)?;
+    ensure!(found, "Missing index entry in page {idx}");
+    let page = tx.page_at(idx)?;
+    let kind = page.head.kind;
+    let mut cells = cells_of(&page)?;
+    if kind == Kind::IndexLeaf {
+        let old = cells.remove(pos);
+        free_overflow(tx, kind, &old)?;
+        write(tx, idx, kind, &cells, None)?;
+        return match !path.is_empty() && underfull(&cells, tx.format()) {
+            true => rebalance(tx, path, idx),
+            false => Ok(()),
+        };
+    }
+
+    let (_, leaf) = rightmost(tx, Vec::new(), child(&page, pos)?)?;
+    let mut leaf_cells = cells_of(&tx.page_at(leaf)?)?;
+    let pred = leaf_cells.pop().context("Empty index leaf")?;
+    write(tx, leaf, Kind::IndexLeaf, &leaf_cells, None)?;
+    let pred_key = index_key(tx, Kind::IndexLeaf, &pred)?;
+
+    let divider = [&cells[pos][..4], &pred].concat();
+    let old = std::mem::replace(&mut cells[pos], divider);
+    free_overflow(tx, kind, &old)?;
+    let rhs = page.head.right_leave;
+    place(tx, &mut path, idx, kind, cells, rhs, false)?;
+
+    // The interior page may have been split, find the leaf again.
+    let slot = seek_index(tx, root, &pred_key, collations)?;
+    let mut path = slot.path;
+    path.push((slot.idx, slot.pos));
+    let from = child(&tx.page_at(slot.idx)?, slot.pos)?;
+    let (path, leaf) = rightmost(tx, path, from)?;
+    let leaf_cells = cells_of(&tx.page_at(leaf)?)?;
+    match underfull(&leaf_cells, tx.format()) {
+        true => rebalance(tx, path, leaf),
+        false => Ok(()),
+    }
+}
+
+/// Walks down the right-most pointers from `idx` to a leaf.
+fn rightmost(tx: &Transaction, mut path: Path, mut idx: i64) -> Result<(Path, i64)> {
+    loop {
+        let page = tx.page_at(idx)?;
+        let Some(rhs) = page.head.right_leave else {
+            return Ok((path, idx));
+        };
+        path.push((idx, page.head.cell_count as usize));
+        idx = rhs as i64;
+        if path.len() > MAX_DEPTH {
+            bail!(Error::corrupt("B-tree too deep"));
+        }
+    }
+}
+
+/// Returns the overflow pages of a cell that is going away.
+fn free_overflow(tx: &mut Transaction, kind: Kind, cell: &[u8]) -> Result<()> {
+    let fmt = tx.format();
+    let payload = match kind {
+        Kind::TableLeaf => page::decode::take_leaf_cell(cell, fmt).map(|r| r.1.payload),
+        Kind::TableInterior => return Ok(()),
+        _ => page::decode::take_index_cell(cell, kind, fmt).map(|r| r.1.payload),
+    };
+    let Ok(payload) = payload else {
+        bail!("Cell decoding failed");
+    };
+    let mut next = payload.overflow;
+    let mut left = payload.len - payload.local.len();
+    while let Some(idx) = next.filter(|n| *n != 0 && left > 0) {
+        let data = tx.raw_page(idx as i64)?;
+        next = Some(u32::from_be_bytes(data[..4].try_into()?));
+        left = left.saturating_sub(fmt.overflow_size());
+        tx.free(idx as i64)?;
+    }
+    Ok(())
+}
+
+/// Pages using less than a third of their space get merged with a sibling.
+fn underfull(cells: &[Vec<u8>], fmt: page::Format) -> bool {
+    let used: usize = cells.iter().map(|c| c.len() + 2).sum();
+    cells.is_empty() || used * 3 < fmt.usable
+}
+
+fn write(
+    tx: &mut Transaction,
+    idx: i64,
+    kind: Kind,
+    cells: &[Vec<u8>],
+    rhs: Option<u32>,
+) -> Result<()> {
+    // New pages may lie past the end of the file, only the first one keeps
+    // bytes of its previous version.
+    let pad = pad_of(idx);
+    let base = match pad {
+        0 => Vec::new(),
+        _ => tx.raw_page(idx)?.to_vec(),
+    };
+    let data = page::encode::page(kind, cells, rhs, pad, &base, tx.format())?;
+    tx.write_page(idx, data)
+}
+
+/// Merges page `idx` with a sibling, or evens out their cells when both do
+/// not fit on one page. A root left with a single child takes its content.
+fn rebalance(tx: &mut Transaction, mut path: Path, idx: i64) -> Result<()> {
+    let fmt = tx.format();
+    let Some(&(parent, pos)) = path.last() else {
+        return collapse(tx, idx);
+    };
+    let page = tx.page_at(parent)?;
+    let mut pcells = cells_of(&page)?;
+    let prhs = page.head.right_leave;
+    if pcells.is_empty() {
+        path.pop();
+        return rebalance(tx, path, parent);
+    }
+    let left = pos.saturating_sub(1);
+    let (lidx, ridx) = (child(&page, left)?, child(&page, left + 1)?);
+    let (lpage, rpage) = (tx.page_at(lidx)?, tx.page_at(ridx)?);
+    let kind = rpage.head.kind;
+    ensure!(
+        lpage.head.kind == kind,
+        "Sibling pages {lidx} and {ridx} differ in kind"
+    );
+
+    // The divider between both pages comes down unless keys live in leaves.
+    let divider = pcells.remove(left);
+    let mut cells = cells_of(&lpage)?;
+    match (kind, lpage.head.right_leave) {
+        (Kind::TableLeaf, _) => {}
+        (Kind::IndexLeaf, _) => cells.push(divider[4..].to_vec()),
+        (_, Some(lrhs)) => cells.push([&lrhs.to_be_bytes(), &divider[4..]].concat()),
+        _ => bail!("Missing right leave on page {lidx}"),
+    }
+    cells.extend(cells_of(&rpage)?);
+    let rhs = rpage.head.right_leave;
+
+    write(tx, parent, kind.interior(), &pcells, prhs)?;
+    tx.free(lidx)?;
+    if let Some(last) = path.last_mut() {
+        last.1 = left;
+    }
+    if !page::encode::fits(kind, &cells, 0, fmt) {
+        return place(tx, &mut path, ridx, kind, cells, rhs, false);
+    }
+    write(tx, ridx, kind, &cells, rhs)?;
+    path.pop();
+    match path.is_empty() || underfull(&pcells, fmt) {
+        true => rebalance(tx, path, parent),
+        false => Ok(()),
+    }
+}
+
+/// Moves the only child of an emptied root into the root, which never moves.
+fn collapse(tx: &mut Transaction, root: i64) -> Result<()> {
+    let page = tx.page_at(root)?;
+    let Some(rhs) = page.head.right_leave.filter(|_| page.head.cell_count == 0) else {
+        return Ok(());
+    };
+    let child = tx.page_at(rhs as i64)?;
+    let (kind, cells) = (child.head.kind, cells_of(&child)?);
+    if !page::encode::fits(kind, &cells, pad_of(root), tx.format()) {
+        return Ok(());
+    }
+    write(tx, root, kind, &cells, child.head.right_leave)?;
+    tx.free(rhs as i64)?;
+    collapse(tx, root)
+}
+
+/// Writes `cells` to page `idx`, or spreads them over new sibling pages when
+/// they do not fit, adding the dividers to the parent from `path`.
+fn place(
+    tx: &mut Transaction,
+    path: &mut Path,
+    mut idx: i64,
+    kind: Kind,
+    cells: Vec<Vec<u8>>,
+    rhs: Option<u32>,
+    appended: bool,
+) -> Result<()> {
+    let fmt = tx.format();
+    if page::encode::fits(kind, &cells, pad_of(idx), fmt) {
+        return write(tx, idx, kind, &cells, rhs);
+    }
+
+    if path.is_empty() {
+        // The root never moves: its content goes down to a new child, which
+        // is then split with the root as its parent.
+        let child = tx.allocate()?;
+        write(tx, idx, kind.interior(), &[], Some(child as u32))?;
+        path.push((idx, 0));
+        idx = child;
+    }
+
+    let groups = split(kind, &cells, appended, fmt)?;
+    let (last, groups) = groups.split_last().context("Nothing to split")?;
+    let mut dividers = Vec::with_capacity(groups.len());
+    for range in groups {
+        let sibling = tx.allocate()?;
+        let promoted = &cells[range.end - 1];
+        let (content, rhs, divider) = match kind {
+            Kind::TableLeaf => {
+                let key = cell_key(kind, promoted)?;
+                let divider = page::encode::interior_cell(sibling as u32, key);
+                (&cells[range.clone()], None, divider)
+            }
+            Kind::TableInterior => {
+                let lhs = u32::from_be_bytes(promoted[..4].try_into()?);
+                let divider =
+                    page::encode::interior_cell(sibling as u32, cell_key(kind, promoted)?);
+                (&cells[range.start..range.end - 1], Some(lhs), divider)
+            }
+            Kind::IndexLeaf => {
+                let divider = [&(sibling as u32).to_be_bytes(), &promoted[..]].concat();
+                (&cells[range.start..range.end - 1], None, divider)
+            }
+            Kind::IndexInterior => {
+                let lhs = u32::from_be_bytes(promoted[..4].try_into()?);
+                let divider = [&(sibling as u32).to_be_bytes(), &promoted[4..]].concat();
+                (&cells[range.start..range.end - 1], Some(lhs), divider)
+            }
+        };
+        write(tx, sibling, kind, content, rhs)?;
+        dividers.push(divider);
+    }
+    write(tx, idx, kind, &cells[last.clone()], rhs)?;
+
+    let (parent, pos) = path.pop().context("Missing parent page")?;
+    let page = tx.page_at(parent)?;
+    let mut pcells = cells_of(&page)?;
+    let prhs = page.head.right_leave;
+    pcells.splice(pos..pos, dividers);
+    place(tx, path, parent, kind.interior(), pcells, prhs, false)
+}
+
+/// Ranges of `cells` going to each page of a split, in key order. Except on
+/// table leaves, the last cell of every range but the last one moves up to
+/// the parent, its child, if any, becoming the right-most pointer of the new
+/// page. Only non-root pages are split, so none of them carries the file
+/// header.
+fn split(
+    kind: Kind,
+    cells: &[Vec<u8>],
+    appended: bool,
+    fmt: page::Format,
+) -> Result<Vec<Range<usize>>> {
+    let n = cells.len();
+    let promotes = kind != Kind::TableLeaf;
+    let fits = |range: Range<usize>, last: bool| {
+        let range = match (promotes, last) {
+            (true, false) => range.start..range.end - 1,
+            _ => range,
+        };
+        page::encode::fits(kind, &cells[range], 0, fmt)
+    };
+
+    // Rows appended in rowid order leave full pages behind them.
+    if !promotes && appended && n > 1 && fits(0..n - 1, false) && fits(n - 1..n, true) {
+        return Ok(vec![0..n - 1, n - 1..n]);
+    }
+
+    let size = |range: Range<usize>| cells[range].iter().map(|c| c.len() + 2).sum::<usize>();
+    let lo = if promotes { 2 } else { 1 };
+    let balanced = (lo..n)
+        .filter(|m| fits(0..*m, false) && fits(*m..n, true))
+        .min_by_key(|m| size(0..*m).abs_diff(size(*m..n)));
+    if let Some(m) = balanced {
+        return Ok(vec![0..m, m..n]);
+    }
+
+    let mut groups = Vec::new();
+    let mut start = 0;
+    while start < n {
+        if fits(start..n, true) {
+            groups.push(start..n);
+            return Ok(groups);
+        }
+        let mut end = start + 1;
+        while end < n && fits(start..end + 1, false) {
+            end += 1;
+        }
+        ensure!(fits(start..end, false), "Cell too large for a page");
+        groups.push(start..end);
+        start = end;
+    }
+    // Every cell moved up, the last page is left with its right pointer.
+    ensure!(kind.is_interior(), "Cannot split {kind:?} page");
+    groups.push(n..n);
+    Ok(groups)
+}
@@ -0,0 +1,199 @@
+use crate::chunk::Chunk;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Default byte budget, in line with sqlite3's own `cache_size` of -2000 (KiB).
+pub const DEFAULT_CACHE_SIZE: usize = 2000 * 1024;
+
+const MAX_SHARDS: usize = 16;
+const MIN_SHARD_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub pages: usize,
+    pub bytes: usize,
+}
+
+struct Entry {
+    page: Chunk,
+    tick: u64,
+}
+
+/// Raw pages keyed by page number, evicted least recently used first once
+/// the byte budget is exceeded.
+pub struct PageCache {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    pages: HashMap<i64, Entry>,
+    lru: BTreeMap<u64, i64>,
+    stats: Stats,
+}
+
+impl PageCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            tick: 0,
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub fn get(&mut self, idx: i64) -> Option<Chunk> {
+        let tick = self.next_tick();
+        let Some(entry) = self.pages.get_mut(&idx) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.lru.remove(&entry.tick);
+        self.lru.insert(tick, idx);
+        entry.tick = tick;
+        self.stats.hits += 1;
+        Some(entry.page.clone())
+    }
+
+    pub fn insert(&mut self, idx: i64, page: Chunk) {
+        let size = page.len();
+        if size > self.budget {
+            return;
+        }
+        self.remove(idx);
+        while self.used + size > self.budget {
+            let Some((_, lru)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.pages.remove(&lru) {
+                self.used -= entry.page.len();
+                self.stats.evictions += 1;
+            }
+        }
+        let tick = self.next_tick();
+        self.lru.insert(tick, idx);
+        self.pages.insert(idx, Entry { page, tick });
+        self.used += size;
+    }
+
+    pub fn remove(&mut self, idx: i64) {
+        if let Some(entry) = self.pages.remove(&idx) {
+            self.lru.remove(&entry.tick);
+            self.used -= entry.page.len();
+        }
+    }
+
+    /// Drops every page, keeping the statistics.
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.lru.clear();
+        self.used = 0;
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            pages: self.pages.len(),
+            bytes: self.used,
+            ..self.stats
+        }
+    }
+}
+
+/// Page cache split into independently locked shards, so concurrent readers
+/// rarely wait on each other. Each shard runs its own LRU over an even share
+/// of the byte budget.
+pub struct SharedCache {
+    shards: Vec<Mutex<PageCache>>,
+}
+
+impl SharedCache {
+    pub fn new(budget: usize) -> Self {
+        let count = (budget / MIN_SHARD_SIZE).clamp(1, MAX_SHARDS);
+        let shards = (0..count)
+            .map(|_| Mutex::new(PageCache::new(budget / count)))
+            .collect();
+        Self { shards }
+    }
+
+    fn shard(&self, idx: i64) -> MutexGuard<'_, PageCache> {
+        let shard = &self.shards[idx.unsigned_abs() as usize % self.shards.len()];
+        shard.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, idx: i64) -> Option<Chunk> {
+        self.shard(idx).get(idx)
+    }
+
+    pub fn insert(&self, idx: i64, page: Chunk) {
+        self.shard(idx).insert(idx, page)
+    }
+
+    pub fn remove(&self, idx: i64) {
+        self.shard(idx).remove(idx)
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap_or_else(PoisonError::into_inner).stats())
+            .fold(Stats::default(), |acc, s| Stats {
+                hits: acc.hits + s.hits,
+                misses: acc.misses + s.misses,
+                evictions: acc.evictions + s.evictions,
+                pages: acc.pages + s.pages,
+                bytes: acc.bytes + s.bytes,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(size: usize) -> Chunk {
+        vec![0u8; size].into()
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = PageCache::new(3 * 512);
+        for idx in 1..=3 {
+            cache.insert(idx, leaf(512));
+        }
+        assert!(cache.get(1).is_some());
+        cache.insert(4, leaf(512));
+
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(3).is_some());
+        assert!(cache.get(4).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 4);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.bytes, 3 * 512);
+    }
+
+    #[test]
+    fn test_zero_budget_disables_cache() {
+        let mut cache = PageCache::new(0);
+        cache.insert(1, leaf(512));
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.stats().pages, 0);
+    }
+}
@@ -0,0 +1,662 @@
+use crate::codec::{self, varint};
+use crate::db::btree::{compare_keys, MAX_DEPTH};
+use crate::db::header::{Header, PENDING_BYTE};
+use crate::db::SQL;
+use crate::page::decode::{take_index_cell, take_interior_cell, take_leaf_cell};
+use crate::page::{self, Format, Kind, Payload, Record};
+use crate::schema::{self, Descriptor};
+use crate::value::{Collation, Type, Value, ValueRef};
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// B-tree to walk, with what to check and keep of its content.
+struct Tree {
+    root: i64,
+    table: bool,
+    /// Collations keys sort by, none for trees whose order we cannot check
+    /// such as those with descending columns.
+    collations: Option<Vec<Collation>>,
+    /// Whether to keep the rows or entries for the index cross-check.
+    collect: bool,
+}
+
+/// Key of a cell and, for leaf table cells, the row it holds.
+struct Entry {
+    key: Vec<Value>,
+    values: Vec<Value>,
+}
+
+struct Checker<'a> {
+    db: &'a dyn SQL,
+    fmt: Format,
+    pages: u32,
+    used: Vec<bool>,
+    problems: Vec<String>,
+    limit: usize,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([data[offset], data[offset + 1]]) as usize
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+/// Checks that a record header is well-formed and describes exactly the
+/// bytes of its body.
+fn check_record(payload: &[u8]) -> std::result::Result<(), String> {
+    let Ok((rest, size)) = varint::take(payload) else {
+        return Err("empty record".to_string());
+    };
+    let start = payload.len() - rest.len();
+    if size < start as i64 || size > payload.len() as i64 {
+        return Err(format!("record header size {size} out of range"));
+    }
+    let mut types = &payload[start..size as usize];
+    let mut body = 0u64;
+    while !types.is_empty() {
+        let Ok((rest, t)) = varint::take(types) else {
+            return Err("truncated record header".to_string());
+        };
+        let Ok(kind) = Type::try_from(t) else {
+            return Err(format!("invalid serial type {t}"));
+        };
+        body += kind.len() as u64;
+        types = rest;
+    }
+    if size as u64 + body != payload.len() as u64 {
+        return Err(format!(
+            "record of {} bytes, its header describes {}",
+            payload.len(),
+            size as u64 + body
+        ));
+    }
+    Ok(())
+}
+
+impl Checker<'_> {
+    fn report(&mut self, problem: String) {
+        if self.problems.len() < self.limit {
+            self.problems.push(problem);
+        }
+    }
+
+    fn full(&self) -> bool {
+        self.problems.len() >= self.limit
+    }
+
+    /// Takes note of page `idx` being used, reporting it when out of range
+    /// or already used.
+    fn claim(&mut self, idx: i64, ctx: &str) -> bool {
+        if idx < 1 || idx > self.pages as i64 {
+            self.report(format!("{ctx}: invalid page number {idx}"));
+            return false;
+        }
+        if std::mem::replace(&mut self.used[idx as usize], true) {
+            self.report(format!("{ctx}: 2nd reference to page {idx}"));
+            return false;
+        }
+        true
+    }
+
+    fn raw_page(&mut self, idx: i64, ctx: &str) -> Option<crate::chunk::Chunk> {
+        match self.db.raw_page(idx) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                self.report(format!("{ctx}: {e}"));
+                None
+            }
+        }
+    }
+
+    fn freelist(&mut self, trunk: u32, count: u32) {
+        let max = self.fmt.usable / 4 - 2;
+        let mut found = 0;
+        let mut next = trunk as i64;
+        while next != 0 && !self.full() {
+            if !self.claim(next, "Freelist") {
+                break;
+            }
+            let Some(data) = self.raw_page(next, "Freelist") else {
+                break;
+            };
+            found += 1;
+            let mut leaves = u32_at(&data, 4) as usize;
+            if leaves > max {
+                self.report(format!("Freelist: trunk page {next} lists {leaves} leaves"));
+                leaves = max;
+            }
+            for at in (0..leaves).map(|i| 8 + 4 * i) {
+                if self.claim(u32_at(&data, at) as i64, "Freelist") {
+                    found += 1;
+                }
+            }
+            next = u32_at(&data, 0) as i64;
+        }
+        if found != count {
+            self.report(format!("Freelist: size is {found} but should be {count}"));
+        }
+    }
+
+    /// Whole payload of a cell, its overflow pages claimed on the way.
+    fn payload(&mut self, p: &Payload, ctx: &str) -> Option<Vec<u8>> {
+        let mut buf = p.local.to_vec();
+        let Some(mut next) = p.overflow else {
+            return Some(buf);
+        };
+        let size = self.fmt.overflow_size();
+        let expected = (p.len - p.local.len()).div_ceil(size);
+        for i in 0..expected {
+            if next == 0 {
+                self.report(format!(
+                    "{ctx}: overflow list ends after {i} of {expected} pages"
+                ));
+                return None;
+            }
+            if !self.claim(next as i64, ctx) {
+                return None;
+            }
+            let data = self.raw_page(next as i64, ctx)?;
+            let take = size.min(p.len - buf.len());
+            buf.extend_from_slice(&data[4..4 + take]);
+            next = u32_at(&data, 0);
+        }
+        if next != 0 {
+            self.report(format!("{ctx}: overflow list longer than {expected} pages"));
+        }
+        Some(buf)
+    }
+
+    /// Values of a record, reporting a malformed one. Text that is not valid
+    /// in the database encoding reads as NULL, as it is no structural fault.
+    fn values(&mut self, payload: &[u8], ctx: &str) -> Option<Vec<Value>> {
+        let record = check_record(payload).and_then(|_| {
+            Record::parse(payload, self.fmt.enc).map_err(|_| "malformed record".to_string())
+        });
+        match record {
+            Ok(r) => Some(
+                (0..r.len())
+                    .map(|i| r.get(i).map_or(Value::Null, ValueRef::into_owned))
+                    .collect(),
+            ),
+            Err(e) => {
+                self.report(format!("{ctx}: {e}"));
+                None
+            }
+        }
+    }
+
+    /// Checks the subtree at `idx`, its keys bounded by `lower` and `upper`,
+    /// appending its entries in key order. Returns the depth of its leaves.
+    fn page(
+        &mut self,
+        tree: &Tree,
+        idx: i64,
+        depth: usize,
+        bounds: (Option<&[Value]>, Option<&[Value]>),
+        out: &mut Vec<Entry>,
+    ) -> Option<usize> {
+        let ctx = format!("Tree {} page {idx}", tree.root);
+        if depth > MAX_DEPTH {
+            self.report(format!("{ctx}: B-tree is too deep"));
+            return None;
+        }
+        if self.full() || !self.claim(idx, &ctx) {
+            return None;
+        }
+        let data = self.raw_page(idx, &ctx)?;
+        let pad = if idx == 1 { Header::size() } else { 0 };
+        let head = match page::decode::take_page(data.clone(), pad, self.fmt) {
+            Ok(page) => page.head,
+            Err(e) => {
+                self.report(format!("{ctx}: {e}"));
+                return None;
+            }
+        };
+        let kind = head.kind;
+        if kind.is_table() != tree.table {
+            self.report(format!("{ctx}: unexpected {kind:?} page"));
+            return None;
+        }
+
+        let usable = self.fmt.usable;
+        let pointers = pad + kind.header_size();
+        let cells = head.cell_count as usize;
+        let content = head.cell_content() as usize;
+        if pointers + 2 * cells > usable.min(content) {
+            self.report(format!(
+                "{ctx}: {cells} cell pointers overlap the cell content area"
+            ));
+            return None;
+        }
+        // Every byte of the cell content area belongs to exactly one cell,
+        // freeblock or fragment.
+        let mut covered = vec![false; usable];
+        let mut overlap = false;
+        let mut cover = |start: usize, end: usize| {
+            let clash = covered[start..end].iter().any(|b| *b);
+            covered[start..end].iter_mut().for_each(|b| *b = true);
+            clash
+        };
+
+        let mut keys = Vec::with_capacity(cells);
+        let mut children = Vec::with_capacity(cells + 1);
+        let mut entries = Vec::with_capacity(cells);
+        for i in 0..cells {
+            let cctx = format!("{ctx} cell {i}");
+            let offset = u16_at(&data, pointers + 2 * i);
+            if offset < content || offset >= usable {
+                self.report(format!("{cctx}: offset {offset} out of range"));
+                continue;
+            }
+            let io = &data[offset..usable];
+            let cell = match kind {
+                Kind::TableInterior => take_interior_cell(io)
+                    .map(|(r, c)| (r, Some(c.lhs), vec![Value::Int(c.row)], None)),
+                Kind::TableLeaf => take_leaf_cell(io, self.fmt)
+                    .map(|(r, c)| (r, None, vec![Value::Int(c.id)], Some(c.payload))),
+                k => take_index_cell(io, k, self.fmt)
+                    .map(|(r, c)| (r, c.lhs, vec![], Some(c.payload))),
+            };
+            let Ok((rest, child, mut key, payload)) = cell else {
+                self.report(format!("{cctx}: extends off the end of the page"));
+                continue;
+            };
+            if cover(offset, usable - rest.len()) {
+                overlap = true;
+            }
+            let mut values = vec![];
+            if let Some(payload) = payload {
+                let Some(payload) = self.payload(&payload, &cctx) else {
+                    continue;
+                };
+                let Some(record) = self.values(&payload, &cctx) else {
+                    continue;
+                };
+                match kind.is_table() {
+                    true => values = record,
+                    false => key = record,
+                }
+            }
+            keys.push(key);
+            children.push(child);
+            if !tree.collect {
+                values.clear();
+            }
+            entries.push(values);
+        }
+        if overlap {
+            self.report(format!("{ctx}: cells overlap"));
+        }
+
+        let mut next = head.free_block as usize;
+        let mut freed = 0;
+        while next != 0 {
+            if next < content || next + 4 > usable {
+                self.report(format!("{ctx}: freeblock offset {next} out of range"));
+                overlap = true;
+                break;
+            }
+            let size = u16_at(&data, next + 2);
+            let following = u16_at(&data, next);
+            if size < 4 || next + size > usable {
+                self.report(format!("{ctx}: freeblock at {next} of size {size}"));
+                overlap = true;
+                break;
+            }
+            if cover(next, next + size) {
+                self.report(format!("{ctx}: freeblock at {next} overlaps a cell"));
+                overlap = true;
+            }
+            if following != 0 && following < next + size {
+                self.report(format!("{ctx}: freeblocks out of order at {next}"));
+                overlap = true;
+                break;
+            }
+            freed += size;
+            next = following;
+        }
+        if !overlap && content <= usable {
+            let fragments = covered[content..].iter().filter(|b| !**b).count();
+            if fragments != head.fragment_count as usize {
+                self.report(format!(
+                    "{ctx}: fragmentation of {fragments} bytes reported as {} ({freed} bytes in freeblocks)",
+                    head.fragment_count
+                ));
+            }
+        }
+
+        if let Some(collations) = &tree.collations {
+            self.check_order(tree, collations, &ctx, &keys, bounds);
+        }
+        if !kind.is_interior() {
+            out.extend(
+                keys.into_iter()
+                    .zip(entries)
+                    .map(|(key, values)| Entry { key, values }),
+            );
+            return Some(depth);
+        }
+
+        let Some(right) = head.right_leave else {
+            self.report(format!("{ctx}: missing right child"));
+            return None;
+        };
+        children.push(Some(right));
+        let mut leaves = None;
+        for (i, child) in children.into_iter().enumerate() {
+            let lower = match i {
+                0 => bounds.0,
+                i => keys.get(i - 1).map(Vec::as_slice),
+            };
+            let upper = keys.get(i).map(Vec::as_slice).or(bounds.1);
+            let depth =
+                child.and_then(|c| self.page(tree, c as i64, depth + 1, (lower, upper), out));
+            match (leaves, depth) {
+                (Some(a), Some(b)) if a != b => {
+                    self.report(format!("{ctx}: child page depth differs"));
+                }
+                (None, d) => leaves = d,
+                _ => {}
+            }
+            // Index interior cells hold entries of their own, sorting after
+            // their left subtree.
+            if !tree.table {
+                if let Some(key) = keys.get(i) {
+                    out.push(Entry {
+                        key: key.clone(),
+                        values: vec![],
+                    });
+                }
+            }
+        }
+        leaves
+    }
+
+    /// Checks that `keys` increase and fall within `bounds`, the upper bound
+    /// being inclusive in table trees where it is the largest rowid on the
+    /// left of a separator.
+    fn check_order(
+        &mut self,
+        tree: &Tree,
+        collations: &[Collation],
+        ctx: &str,
+        keys: &[Vec<Value>],
+        (lower, upper): (Option<&[Value]>, Option<&[Value]>),
+    ) {
+        let describe = |key: &[Value]| match (tree.table, key) {
+            (true, [Value::Int(id)]) => format!("rowid {id}"),
+            _ => "key".to_string(),
+        };
+        let mut prev = lower;
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(prev) = prev {
+                if compare_keys(prev, key, collations).is_ge() {
+                    self.report(format!("{ctx} cell {i}: {} out of order", describe(key)));
+                }
+            }
+            if let Some(upper) = upper {
+                let order = compare_keys(key, upper, collations);
+                if order.is_gt() || order.is_eq() && !tree.table {
+                    self.report(format!(
+                        "{ctx} cell {i}: {} above its parent separator",
+                        describe(key)
+                    ));
+                }
+            }
+            prev = Some(key);
+        }
+    }
+
+    fn tree(&mut self, tree: &Tree) -> Vec<Entry> {
+        let mut out = vec![];
+        self.page(tree, tree.root, 1, (None, None), &mut out);
+        out
+    }
+
+    /// Compares the entries of index `index` with those the rows of `table`
+    /// call for.
+    fn cross_check(
+        &mut self,
+        table: &Descriptor,
+        rows: &[Entry],
+        index: &Descriptor,
+        actual: &mut [Entry],
+    ) {
+        let Ok((_, cols)) = index.index_columns(table) else {
+            return;
+        };
+        let Ok(collations) = index.index_collations(table) else {
+            return;
+        };
+        let defs = codec::sql::columns(&table.sql);
+        let positions = cols
+            .iter()
+            .map(|c| defs.iter().position(|d| d.name.eq_ignore_ascii_case(c)))
+            .collect::<Option<Vec<_>>>();
+        let Some(positions) = positions else {
+            return;
+        };
+        let ipk = codec::sql::rowid_alias(&defs);
+        let default = |i: usize| {
+            defs[i]
+                .default
+                .map_or(Ok(Value::Null), str::parse::<Value>)
+                .unwrap_or(Value::Null)
+        };
+
+        let mut expected = rows
+            .iter()
+            .map(|row| {
+                let id = row.key[0].clone();
+                let mut key = positions
+                    .iter()
+                    .map(|p| match row.values.get(*p) {
+                        _ if Some(*p) == ipk => id.clone(),
+                        Some(v) => v.clone(),
+                        None => default(*p),
+                    })
+                    .collect::<Vec<_>>();
+                key.push(id);
+                key
+            })
+            .collect::<Vec<_>>();
+        expected.sort_by(|a, b| compare_keys(a, b, &collations));
+        actual.sort_by(|a, b| compare_keys(&a.key, &b.key, &collations));
+
+        let name = &index.name;
+        let (mut i, mut j) = (0, 0);
+        while i < expected.len() || j < actual.len() {
+            let order = match (expected.get(i), actual.get(j)) {
+                (Some(a), Some(b)) => compare_keys(a, &b.key, &collations),
+                (Some(_), None) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            match order {
+                Ordering::Less => {
+                    let id = expected[i].last().cloned().unwrap_or(Value::Null);
+                    self.report(format!("row {id} missing from index {name}"));
+                    i += 1;
+                }
+                Ordering::Greater => {
+                    self.report(format!("index {name} has an entry with no row"));
+                    j += 1;
+                }
+                Ordering::Equal => (i, j) = (i + 1, j + 1),
+            }
+        }
+        if expected.len() != actual.len() {
+            self.report(format!("wrong # of entries in index {name}"));
+        }
+    }
+}
+
+/// Checks the structure of every B-tree and of the freelist, that each page
+/// is used exactly once and that indexes agree with their tables. Returns
+/// at most `limit` problems, none for a sound database.
+pub fn integrity_check(db: &dyn SQL, pages: u32, limit: usize) -> Result<Vec<String>> {
+    let fmt = db.format();
+    let head = Header::read(&mut &db.raw_page(1)?[..])?;
+    let mut checker = Checker {
+        db,
+        fmt,
+        pages,
+        used: vec![false; pages as usize + 1],
+        problems: vec![],
+        limit,
+    };
+
+    let pending = (PENDING_BYTE / fmt.page_size as u64) as usize + 1;
+    if pending <= pages as usize {
+        checker.used[pending] = true;
+    }
+    // Auto-vacuum databases interleave pointer map pages with the others,
+    // each one covering the pages that follow it.
+    if head.largest_root() != 0 {
+        let period = fmt.usable / 5 + 1;
+        (2..=pages as usize)
+            .step_by(period)
+            .for_each(|p| checker.used[p] = true);
+    }
+    checker.freelist(head.freelist_trunk(), head.freelist_count());
+
+    checker.tree(&Tree {
+        root: 1,
+        table: true,
+        collations: Some(vec![]),
+        collect: false,
+    });
+    let schema = match db.schema() {
+        Ok(schema) => schema,
+        Err(e) => {
+            checker.report(format!("sqlite_schema: {e}"));
+            return Ok(checker.problems);
+        }
+    };
+    let mut entries = HashMap::new();
+    for desc in schema.desc.iter().filter(|d| d.root > 0) {
+        let table = desc.kind == schema::Type::Table && !codec::sql::without_rowid(&desc.sql);
+        let parent = schema.table_named(&desc.tbl_name).ok();
+        // Indexes sort by the collation of each column, WITHOUT ROWID
+        // tables are only checked when their key is plainly binary.
+        let collations = match desc.kind {
+            schema::Type::Table if table => Some(vec![]),
+            schema::Type::Table => {
+                let sql = desc.sql.to_uppercase();
+                (!sql.contains("COLLATE") && !sql.contains(" DESC")).then(Vec::new)
+            }
+            _ => parent.and_then(|t| desc.index_collations(t).ok()),
+        };
+        let collect = match desc.kind {
+            schema::Type::Table => table && schema.indexes_on(&desc.name).next().is_some(),
+            _ => collations.is_some() && parent.is_some_and(|t| !codec::sql::without_rowid(&t.sql)),
+        };
+        let tree = Tree {
+            root: desc.root,
+            table,
+            collations,
+            collect,
+        };
+        let found = checker.tree(&tree);
+        if tree.collect {
+            entries.insert(desc.root, found);
+        }
+    }
+
+    for table in schema.desc.iter().filter(|d| d.kind == schema::Type::Table) {
+        let Some(rows) = entries.remove(&table.root) else {
+            continue;
+        };
+        for index in schema.indexes_on(&table.name) {
+            if let Some(actual) = entries.get_mut(&index.root) {
+                checker.cross_check(table, &rows, index, actual);
+            }
+        }
+    }
+
+    let unused = (1..=pages as usize)
+        .filter(|p| !checker.used[*p])
+        .collect::<Vec<_>>();
+    for p in unused {
+        checker.report(format!("Page {p}: never used"));
+    }
+    Ok(checker.problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::btree;
+    use crate::db::transaction::tests::create_index;
+    use crate::db::{Options, SQLiteFile};
+    use crate::fixture::{TempDb, SAMPLE_DB};
+
+    #[test]
+    fn test_integrity_check_reports_every_problem() {
+        let db = SQLiteFile::open_with(SAMPLE_DB, Options::default()).unwrap();
+        assert!(db.integrity_check(100).unwrap().is_empty());
+
+        let file = TempDb::new("check");
+        let mut bytes = std::fs::read(SAMPLE_DB).unwrap();
+        let size = db.format().page_size;
+        let apples = db.schema().unwrap().table_named("apples").unwrap().root as usize;
+        let page = (apples - 1) * size;
+        // Swap the first two cell pointers of the apples leaf, then claim a
+        // freelist page that does not exist.
+        let (a, b) = (page + 8, page + 10);
+        let first = [bytes[a], bytes[a + 1]];
+        bytes.copy_within(b..b + 2, a);
+        bytes[b..b + 2].copy_from_slice(&first);
+        bytes[36..40].copy_from_slice(&1u32.to_be_bytes());
+        std::fs::write(file.path(), bytes).unwrap();
+
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let problems = db.integrity_check(100).unwrap();
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("size is 0 but should be 1"));
+        assert!(problems[1].contains("rowid 1 out of order"));
+        assert_eq!(db.integrity_check(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_integrity_check_orders_index_keys_by_collation() {
+        let file = TempDb::sample("check-collate");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let mut tx = db.begin().unwrap();
+        let sql = "CREATE TABLE fruits(id INTEGER PRIMARY KEY, name TEXT COLLATE NOCASE)";
+        tx.create_table("fruits", sql).unwrap();
+        let sql = "CREATE INDEX fruits_name ON fruits(name)";
+        create_index(&mut tx, "fruits_name", "fruits", sql).unwrap();
+        let rows = ["apple", "Banana", "cherry", "Date"]
+            .map(|name| vec![Value::Null, Value::Text(name.to_string())]);
+        tx.insert("fruits", None, rows.to_vec()).unwrap();
+        tx.commit().unwrap();
+        assert!(db.integrity_check(10).unwrap().is_empty());
+
+        // Rewrite the index in binary order, as if it ignored the collation.
+        let schema = db.schema().unwrap();
+        let root = schema
+            .desc
+            .iter()
+            .find(|d| d.name == "fruits_name")
+            .unwrap()
+            .root;
+        let keys = btree::IndexEntries::new(&db, root)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let mut tx = db.begin().unwrap();
+        for key in &keys {
+            btree::index_delete(&mut tx, root, key, &[Collation::NoCase]).unwrap();
+        }
+        for key in &keys {
+            btree::index_insert(&mut tx, root, key, &[]).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let problems = db.integrity_check(10).unwrap();
+        assert_eq!(problems.len(), 1, "{problems:?}");
+        assert!(problems[0].contains("key out of order"));
+    }
+}
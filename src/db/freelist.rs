@@ -0,0 +1,91 @@
+use crate::db::SQL;
+use anyhow::{ensure, Result};
+use std::collections::HashSet;
+
+/// Pages on the freelist, as found by walking the trunk chain that starts at
+/// header offset 32.
+#[derive(Debug, Default, Clone)]
+pub struct Freelist {
+    /// Trunk pages, in chain order.
+    pub trunks: Vec<i64>,
+    /// Leaf pages, in the order the trunks list them.
+    pub leaves: Vec<i64>,
+}
+
+impl Freelist {
+    /// Walks the chain from `trunk` in a database of `pages` pages.
+    pub fn read(db: &dyn SQL, trunk: u32, pages: u32) -> Result<Self> {
+        let mut list = Self::default();
+        let mut seen = HashSet::new();
+        let usable = db.format().usable;
+        let mut next = trunk as i64;
+        while next != 0 {
+            ensure!(
+                next > 0 && next <= pages as i64,
+                "freelist trunk {next} out of range"
+            );
+            ensure!(seen.insert(next), "freelist trunk {next} is a loop");
+            let data = db.raw_page(next)?;
+            let count = u32::from_be_bytes(data[4..8].try_into()?) as usize;
+            ensure!(
+                count <= usable / 4 - 2,
+                "freelist trunk {next} lists {count} leaves"
+            );
+            list.trunks.push(next);
+            for at in (0..count).map(|i| 8 + 4 * i) {
+                let leaf = u32::from_be_bytes(data[at..at + 4].try_into()?) as i64;
+                ensure!(
+                    leaf > 0 && leaf <= pages as i64,
+                    "freelist leaf {leaf} out of range on trunk {next}"
+                );
+                ensure!(seen.insert(leaf), "page {leaf} is on the freelist twice");
+                list.leaves.push(leaf);
+            }
+            next = u32::from_be_bytes(data[0..4].try_into()?) as i64;
+        }
+        Ok(list)
+    }
+
+    /// Free pages, trunks included.
+    pub fn len(&self) -> usize {
+        self.trunks.len() + self.leaves.len()
+    }
+
+    /// Every free page number, trunks included, in increasing order.
+    pub fn pages(&self) -> Vec<i64> {
+        let mut pages = [self.trunks.as_slice(), self.leaves.as_slice()].concat();
+        pages.sort_unstable();
+        pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{Options, SQLiteFile};
+    use crate::fixture::TempDb;
+    use crate::value::Value;
+
+    #[test]
+    fn test_freelist_matches_header() {
+        let file = TempDb::sample("freelist");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        assert_eq!(db.freelist().unwrap().len(), 0);
+
+        let rows = (0..3000)
+            .map(|_| vec![Value::Null, Value::Text("free".repeat(100)), Value::Null])
+            .collect();
+        let mut tx = db.begin().unwrap();
+        tx.insert("apples", None, rows).unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin().unwrap();
+        tx.delete("apples", None).unwrap();
+        tx.commit().unwrap();
+
+        let list = db.freelist().unwrap();
+        assert!(!list.trunks.is_empty() && list.leaves.len() > 100);
+        assert_eq!(list.len(), db.freelist_count() as usize);
+        let pages = list.pages();
+        assert!(pages.windows(2).all(|w| w[0] < w[1]));
+        assert!(pages[0] > 1 && pages[pages.len() - 1] <= db.page_count().unwrap() as i64);
+    }
+}
@@ -1,7 +1,13 @@
+use crate::codec::text::Encoding;
+use crate::error::{Error, Result};
 use std::io::Read;
 
 const HEADER_SIZE: usize = 100;
 
+/// Byte offset of the page SQLite never uses, reserved for file locks.
+pub const PENDING_BYTE: u64 = 0x4000_0000;
+
+#[derive(Debug, Copy, Clone)]
 pub struct Header([u8; HEADER_SIZE]);
 
 impl Header {
@@ -9,11 +15,26 @@ impl Header {
         HEADER_SIZE
     }
 
-    pub fn read(io: &mut impl Read) -> anyhow::Result<Self> {
+    pub fn read(io: &mut impl Read) -> Result<Self> {
         let mut buf = [0u8; 100];
-        io.read_exact(&mut buf)?;
-        anyhow::ensure!(buf.starts_with(b"SQLite format 3\0"));
-        Ok(Self(buf))
+        match io.read_exact(&mut buf) {
+            Ok(()) if buf.starts_with(b"SQLite format 3\0") => Ok(Self(buf)),
+            _ => Err(Error::corrupt("file is not a database")),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&self.0[offset..offset + 4]);
+        u32::from_be_bytes(buf)
+    }
+
+    fn set_u32_at(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
     }
 
     pub fn page_size(&self) -> u32 {
@@ -24,9 +45,64 @@ impl Header {
         }
     }
 
-    #[allow(dead_code)]
+    /// Encoding used by every TEXT value in the file, schema included.
+    pub fn text_encoding(&self) -> Result<Encoding> {
+        self.u32_at(56).try_into()
+    }
+
+    /// Whether the file format versions ask for write-ahead logging.
+    pub fn is_wal(&self) -> bool {
+        self.0[18] == 2 || self.0[19] == 2
+    }
+
     /// Reserved chunk at the end of each page.
     pub fn reserved_page_size(&self) -> u8 {
         self.0[20]
     }
+
+    pub fn change_counter(&self) -> u32 {
+        self.u32_at(24)
+    }
+
+    /// Size of the database in pages, only trustworthy when written by the
+    /// same transaction that last bumped the change counter.
+    pub fn page_count(&self) -> Option<u32> {
+        match self.u32_at(92) == self.change_counter() {
+            true => Some(self.u32_at(28)),
+            false => None,
+        }
+    }
+
+    pub fn freelist_trunk(&self) -> u32 {
+        self.u32_at(32)
+    }
+
+    pub fn freelist_count(&self) -> u32 {
+        self.u32_at(36)
+    }
+
+    /// Root page of the largest B-tree in an auto-vacuum database, zero when
+    /// the file has no pointer map.
+    pub fn largest_root(&self) -> u32 {
+        self.u32_at(52)
+    }
+
+    /// Tells other connections to read the schema again.
+    pub fn bump_schema_cookie(&mut self) {
+        let cookie = self.u32_at(40).wrapping_add(1);
+        self.set_u32_at(40, cookie);
+    }
+
+    pub fn set_freelist(&mut self, trunk: u32, count: u32) {
+        self.set_u32_at(32, trunk);
+        self.set_u32_at(36, count);
+    }
+
+    /// Bumps the change counter and records `pages` as the database size.
+    pub fn commit(&mut self, pages: u32) {
+        let counter = self.change_counter().wrapping_add(1);
+        self.set_u32_at(24, counter);
+        self.set_u32_at(28, pages);
+        self.set_u32_at(92, counter);
+    }
 }
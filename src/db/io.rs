@@ -0,0 +1,633 @@
+use crate::chunk::Chunk;
+use crate::db::header::PENDING_BYTE;
+use anyhow::{bail, ensure, Result};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Positional access to the bytes of a database file. Reads never share a
+/// cursor so a single handle serves concurrent readers.
+pub trait Storage: Send + Sync {
+    fn read_at(&self, offset: u64, len: usize) -> Result<Chunk>;
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<()>;
+    /// Flushes written data down to the disk.
+    fn sync(&self) -> Result<()>;
+    fn len(&self) -> Result<u64>;
+    fn truncate(&self, len: u64) -> Result<()>;
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    File,
+    Mmap,
+}
+
+impl Backend {
+    pub fn open(&self, file: File) -> Result<Box<dyn Storage>> {
+        let io: Box<dyn Storage> = match self {
+            Self::File => Box::new(Positional::new(file)),
+            Self::Mmap => Box::new(Mapped::new(file)?),
+        };
+        Ok(io)
+    }
+}
+
+/// Copies every read into a fresh heap buffer using `pread`, or a locked
+/// seek and read where positional reads are not available.
+pub struct Positional {
+    file: sys::File,
+}
+
+impl Positional {
+    pub fn new(file: File) -> Self {
+        Self {
+            file: sys::File::new(file),
+        }
+    }
+}
+
+impl Storage for Positional {
+    fn read_at(&self, offset: u64, len: usize) -> Result<Chunk> {
+        let mut data = vec![0u8; len];
+        self.file.read_exact_at(&mut data, offset)?;
+        Ok(data.into())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+        #[cfg(test)]
+        if !fault::allow() {
+            self.file.write_all_at(&data[..data.len() / 2], offset)?;
+            bail!(fault::CRASH);
+        }
+        Ok(self.file.write_all_at(data, offset)?)
+    }
+
+    fn sync(&self) -> Result<()> {
+        #[cfg(test)]
+        ensure!(fault::allow(), fault::CRASH);
+        Ok(self.file.sync_all()?)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.file.len()?)
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        #[cfg(test)]
+        ensure!(fault::allow(), fault::CRASH);
+        Ok(self.file.set_len(len)?)
+    }
+}
+
+/// Deletes a file, the last step of a commit.
+pub fn remove(path: &Path) -> Result<()> {
+    #[cfg(test)]
+    ensure!(fault::allow(), fault::CRASH);
+    Ok(std::fs::remove_file(path)?)
+}
+
+/// Value unlikely to repeat across runs, seeding journal checksums and WAL
+/// salts.
+pub fn nonce() -> u32 {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+        .unwrap_or_default();
+    seed ^ std::process::id().rotate_left(16)
+}
+
+/// Simulated power loss: once armed, file changes stop after a given number
+/// of writes, syncs and deletes, the failing write landing only halfway.
+#[cfg(test)]
+pub mod fault {
+    use std::cell::Cell;
+
+    pub const CRASH: &str = "simulated crash";
+
+    thread_local! {
+        static BUDGET: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    /// Lets `ops` more file changes through on this thread, all of them when
+    /// `None`.
+    pub fn arm(ops: Option<usize>) {
+        BUDGET.with(|b| b.set(ops));
+    }
+
+    pub(super) fn allow() -> bool {
+        BUDGET.with(|b| match b.get() {
+            None => true,
+            Some(0) => false,
+            Some(n) => {
+                b.set(Some(n - 1));
+                true
+            }
+        })
+    }
+}
+
+/// Maps the whole file once and hands out slices of the mapping.
+pub struct Mapped {
+    map: Arc<sys::Mmap>,
+    /// Kept open, closing it would drop the locks of the process on the file.
+    _file: File,
+}
+
+impl Mapped {
+    pub fn new(file: File) -> Result<Self> {
+        let map = Arc::new(sys::Mmap::new(&file)?);
+        Ok(Self { map, _file: file })
+    }
+}
+
+impl Storage for Mapped {
+    fn read_at(&self, offset: u64, len: usize) -> Result<Chunk> {
+        let start = offset as usize;
+        let end = start.saturating_add(len);
+        ensure!(end <= self.map.len(), "read past end of file at {offset}");
+        Ok(Chunk::new(self.map.clone(), start..end))
+    }
+
+    fn write_at(&self, _: u64, _: &[u8]) -> Result<()> {
+        bail!("The mmap backend is read-only")
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.map.len() as u64)
+    }
+
+    fn truncate(&self, _: u64) -> Result<()> {
+        bail!("The mmap backend is read-only")
+    }
+}
+
+/// Write lock on a single byte of a file, the way sqlite3 locks the slots
+/// of its wal-index, released on drop.
+pub struct ByteLock {
+    file: sys::File,
+    offset: u64,
+}
+
+impl ByteLock {
+    /// Takes the lock without waiting, None when another process holds it.
+    pub fn try_new(file: File, offset: u64) -> Result<Option<Self>> {
+        let file = sys::File::new(file);
+        match file.try_lock(true, offset, 1)? {
+            true => Ok(Some(Self { file, offset })),
+            false => Ok(None),
+        }
+    }
+
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+        Ok(self.file.write_all_at(data, offset)?)
+    }
+
+    pub fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        self.file.read_exact_at(&mut data, offset)?;
+        Ok(data)
+    }
+
+    /// Write locks `len` more bytes at `offset` without waiting, saying
+    /// whether it did. They are released by [`ByteLock::unlock`], or when
+    /// the lock is dropped at the latest.
+    pub fn try_lock(&self, offset: u64, len: u64) -> Result<bool> {
+        Ok(self.file.try_lock(true, offset, len)?)
+    }
+
+    pub fn unlock(&self, offset: u64, len: u64) -> Result<()> {
+        Ok(self.file.unlock(offset, len)?)
+    }
+
+    /// Whether another process holds a lock on `len` bytes at `offset`.
+    pub fn is_locked(&self, offset: u64, len: u64) -> Result<bool> {
+        Ok(self.file.is_locked(offset, len)?)
+    }
+}
+
+impl Drop for ByteLock {
+    fn drop(&mut self) {
+        // Closing the file would release it, and any other, as well.
+        let _ = self.file.unlock(self.offset, 1);
+    }
+}
+
+/// RESERVED is the byte after PENDING_BYTE, SHARED a range further on.
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+const SHARED_SIZE: u64 = 510;
+
+/// How far a connection is into reading or writing a database in rollback
+/// mode, as sqlite3 records it in locks on the file.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    #[default]
+    None,
+    /// Reading: no one writes the file meanwhile.
+    Shared,
+    /// Preparing a commit: readers carry on, other writers are kept out.
+    Reserved,
+    /// Writing the file, with no reader left.
+    Exclusive,
+}
+
+/// The locks sqlite3 takes on a database file, taken the same way so that
+/// sqlite3 connections and ours exclude each other. Like sqlite3's, they
+/// belong to the process: handles within it do not exclude each other, and
+/// closing any descriptor of the file drops them all.
+pub struct FileLock {
+    file: Positional,
+    writable: bool,
+    level: Level,
+}
+
+/// Where record locks are not available, every lock is granted.
+fn granted<T>(res: std::io::Result<T>, otherwise: T) -> std::io::Result<T> {
+    match res {
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => Ok(otherwise),
+        res => res,
+    }
+}
+
+impl FileLock {
+    /// Locks taken through `file`, open for writing when `writable`.
+    pub fn new(file: File, writable: bool) -> Self {
+        Self {
+            file: Positional::new(file),
+            writable,
+            level: Level::None,
+        }
+    }
+
+    /// Climbs to `level` without waiting. False when another process holds
+    /// a lock in the way, the level then staying as it was.
+    pub fn lock(&mut self, level: Level) -> Result<bool> {
+        let start = self.level;
+        let file = &self.file.file;
+        while self.level < level {
+            let (next, taken) = match self.level {
+                Level::None => {
+                    // A writer waiting for readers to leave holds PENDING.
+                    if !granted(file.try_lock(false, PENDING_BYTE, 1), true)? {
+                        return Ok(false);
+                    }
+                    let shared = granted(file.try_lock(false, SHARED_FIRST, SHARED_SIZE), true);
+                    granted(file.unlock(PENDING_BYTE, 1), ())?;
+                    (Level::Shared, shared?)
+                }
+                Level::Shared => (
+                    Level::Reserved,
+                    granted(file.try_lock(true, RESERVED_BYTE, 1), true)?,
+                ),
+                _ => {
+                    let pending = granted(file.try_lock(true, PENDING_BYTE, 1), true)?;
+                    let all =
+                        pending && granted(file.try_lock(true, SHARED_FIRST, SHARED_SIZE), true)?;
+                    if pending && !all {
+                        granted(file.unlock(PENDING_BYTE, 1), ())?;
+                    }
+                    (Level::Exclusive, all)
+                }
+            };
+            if !taken {
+                self.unlock(start)?;
+                return Ok(false);
+            }
+            self.level = next;
+        }
+        Ok(true)
+    }
+
+    /// Steps down to `level`.
+    pub fn unlock(&mut self, level: Level) -> Result<()> {
+        if self.level <= level {
+            return Ok(());
+        }
+        let file = &self.file.file;
+        if level == Level::None {
+            granted(file.unlock(PENDING_BYTE, 2 + SHARED_SIZE), ())?;
+        } else {
+            if self.level == Level::Exclusive {
+                granted(file.try_lock(false, SHARED_FIRST, SHARED_SIZE), true)?;
+                granted(file.unlock(PENDING_BYTE, 1), ())?;
+            }
+            if level == Level::Shared {
+                granted(file.unlock(RESERVED_BYTE, 1), ())?;
+            }
+        }
+        self.level = level;
+        Ok(())
+    }
+
+    /// Whether another process holds RESERVED or more: it is writing, and
+    /// a journal next to the database is its own rather than hot.
+    pub fn is_reserved(&self) -> Result<bool> {
+        Ok(granted(self.file.file.is_locked(RESERVED_BYTE, 1), false)?)
+    }
+
+    /// The locked file, to play a journal back into under EXCLUSIVE. None
+    /// when it was opened read-only.
+    pub fn file(&self) -> Option<&dyn Storage> {
+        match self.writable {
+            true => Some(&self.file),
+            false => None,
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use anyhow::{bail, Result};
+    use std::io::ErrorKind;
+    use std::os::raw::{c_int, c_short, c_void};
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+
+    pub struct File(std::fs::File);
+
+    impl File {
+        pub fn new(file: std::fs::File) -> Self {
+            Self(file)
+        }
+
+        pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+            self.0.read_exact_at(buf, offset)
+        }
+
+        pub fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+            self.0.write_all_at(buf, offset)
+        }
+
+        pub fn sync_all(&self) -> std::io::Result<()> {
+            self.0.sync_all()
+        }
+
+        pub fn set_len(&self, len: u64) -> std::io::Result<()> {
+            self.0.set_len(len)
+        }
+
+        pub fn len(&self) -> std::io::Result<u64> {
+            Ok(self.0.metadata()?.len())
+        }
+
+        /// Locks `len` bytes at `offset` for writing, or reading, unless
+        /// another process holds a lock in the way, saying whether it did.
+        pub fn try_lock(&self, write: bool, offset: u64, len: u64) -> std::io::Result<bool> {
+            let kind = if write { F_WRLCK } else { F_RDLCK };
+            match self.fcntl_lock(F_SETLK, kind, offset, len) {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+                // EACCES, which some systems report for a held lock.
+                Err(e) if e.raw_os_error() == Some(13) => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+
+        pub fn unlock(&self, offset: u64, len: u64) -> std::io::Result<()> {
+            self.fcntl_lock(F_SETLK, F_UNLCK, offset, len).map(drop)
+        }
+
+        /// Whether another process holds a lock on `len` bytes at `offset`
+        /// that keeps us from write locking them.
+        pub fn is_locked(&self, offset: u64, len: u64) -> std::io::Result<bool> {
+            Ok(self.fcntl_lock(F_GETLK, F_WRLCK, offset, len)? != F_UNLCK)
+        }
+
+        /// Runs `cmd` on a record lock of type `kind`, returning the type
+        /// `fcntl` leaves in it.
+        #[cfg(any(
+            all(target_os = "linux", target_pointer_width = "64"),
+            target_os = "macos"
+        ))]
+        fn fcntl_lock(
+            &self,
+            cmd: c_int,
+            kind: c_short,
+            offset: u64,
+            len: u64,
+        ) -> std::io::Result<c_short> {
+            let mut lock = Flock {
+                l_type: kind,
+                l_whence: SEEK_SET,
+                l_start: offset as i64,
+                l_len: len as i64,
+                l_pid: 0,
+            };
+            // Safety: `lock` is a valid record lock for the whole call.
+            let res = unsafe { fcntl(self.0.as_raw_fd(), cmd, &mut lock as *mut Flock) };
+            match res {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(lock.l_type),
+            }
+        }
+
+        #[cfg(not(any(
+            all(target_os = "linux", target_pointer_width = "64"),
+            target_os = "macos"
+        )))]
+        fn fcntl_lock(&self, _: c_int, _: c_short, _: u64, _: u64) -> std::io::Result<c_short> {
+            Err(ErrorKind::Unsupported.into())
+        }
+    }
+
+    const PROT_READ: c_int = 1;
+    const MAP_SHARED: c_int = 1;
+
+    /// Record lock of `fcntl`, laid out as the platform declares it.
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[repr(C)]
+    struct Flock {
+        l_type: c_short,
+        l_whence: c_short,
+        l_start: i64,
+        l_len: i64,
+        l_pid: c_int,
+    }
+
+    #[cfg(target_os = "macos")]
+    #[repr(C)]
+    struct Flock {
+        l_start: i64,
+        l_len: i64,
+        l_pid: c_int,
+        l_type: c_short,
+        l_whence: c_short,
+    }
+
+    #[cfg(target_os = "linux")]
+    const F_GETLK: c_int = 5;
+    #[cfg(target_os = "linux")]
+    const F_SETLK: c_int = 6;
+    #[cfg(target_os = "linux")]
+    const F_RDLCK: c_short = 0;
+    #[cfg(target_os = "linux")]
+    const F_WRLCK: c_short = 1;
+    #[cfg(target_os = "macos")]
+    const F_GETLK: c_int = 7;
+    #[cfg(target_os = "macos")]
+    const F_SETLK: c_int = 8;
+    #[cfg(target_os = "macos")]
+    const F_RDLCK: c_short = 1;
+    #[cfg(target_os = "macos")]
+    const F_WRLCK: c_short = 3;
+    // Placeholders where `fcntl_lock` reports locks as unsupported.
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    const F_GETLK: c_int = 0;
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    const F_SETLK: c_int = 0;
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    const F_RDLCK: c_short = 0;
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    const F_WRLCK: c_short = 0;
+    const F_UNLCK: c_short = 2;
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    const SEEK_SET: c_short = 0;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+        fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+
+    pub struct Mmap {
+        ptr: *mut c_void,
+        len: usize,
+    }
+
+    // Safety: the mapping is read-only and lives as long as the value.
+    unsafe impl Send for Mmap {}
+    unsafe impl Sync for Mmap {}
+
+    impl Mmap {
+        pub fn new(file: &std::fs::File) -> Result<Self> {
+            let len = file.metadata()?.len() as usize;
+            if len == 0 {
+                bail!("Cannot map an empty file");
+            }
+            // Safety: read-only shared mapping of a file we keep open for the
+            // duration of the call; the kernel keeps the mapping valid after.
+            let ptr = unsafe {
+                mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    PROT_READ,
+                    MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr as isize == -1 {
+                bail!(std::io::Error::last_os_error());
+            }
+            Ok(Self { ptr, len })
+        }
+
+        pub fn len(&self) -> usize {
+            self.len
+        }
+    }
+
+    impl AsRef<[u8]> for Mmap {
+        fn as_ref(&self) -> &[u8] {
+            // Safety: `ptr` maps `len` readable bytes until dropped.
+            unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            // Safety: unmaps exactly the region returned by `mmap`.
+            unsafe { munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use anyhow::{bail, Result};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::sync::Mutex;
+
+    pub struct File(Mutex<std::fs::File>);
+
+    impl File {
+        pub fn new(file: std::fs::File) -> Self {
+            Self(Mutex::new(file))
+        }
+
+        pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+            let mut file = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(buf)
+        }
+
+        pub fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+            let mut file = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(buf)
+        }
+
+        pub fn sync_all(&self) -> std::io::Result<()> {
+            self.0.lock().unwrap_or_else(|e| e.into_inner()).sync_all()
+        }
+
+        pub fn set_len(&self, len: u64) -> std::io::Result<()> {
+            self.0
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .set_len(len)
+        }
+
+        pub fn len(&self) -> std::io::Result<u64> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .metadata()?
+                .len())
+        }
+
+        pub fn try_lock(&self, _: bool, _: u64, _: u64) -> std::io::Result<bool> {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+
+        pub fn unlock(&self, _: u64, _: u64) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+
+        pub fn is_locked(&self, _: u64, _: u64) -> std::io::Result<bool> {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    pub struct Mmap;
+
+    impl Mmap {
+        pub fn new(_: &std::fs::File) -> Result<Self> {
+            bail!("Memory mapped I/O is only supported on unix")
+        }
+
+        pub fn len(&self) -> usize {
+            0
+        }
+    }
+
+    impl AsRef<[u8]> for Mmap {
+        fn as_ref(&self) -> &[u8] {
+            &[]
+        }
+    }
+}
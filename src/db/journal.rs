@@ -0,0 +1,196 @@
+use crate::db::io::{self, FileLock, Level, Positional, Storage};
+use anyhow::{bail, ensure, Context, Result};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+const MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+/// Room taken by the journal header, rounded up to a disk sector.
+const SECTOR_SIZE: u32 = 512;
+/// Record count of a journal whose size tells how many records it holds.
+const UNKNOWN_RECORDS: u32 = u32::MAX;
+
+/// Rollback journal of `db`, the file next to it named `<db>-journal`.
+pub fn path(db: &Path) -> PathBuf {
+    let mut name = OsString::from(db.as_os_str());
+    name.push("-journal");
+    PathBuf::from(name)
+}
+
+/// Sum of every 200th byte of a page, counting down from its end, seeded
+/// with the journal nonce.
+fn checksum(nonce: u32, data: &[u8]) -> u32 {
+    (1..)
+        .map(|k| data.len() as i64 - 200 * k)
+        .take_while(|i| *i > 0)
+        .fold(nonce, |sum, i| sum.wrapping_add(data[i as usize] as u32))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+/// Original content of the pages a commit overwrites. The database file is
+/// only touched once the journal is sealed, and the commit is done when the
+/// journal is removed: a journal left behind is hot and gets played back by
+/// the next [`rollback`].
+pub struct Journal {
+    path: PathBuf,
+    io: Positional,
+    page_size: usize,
+    nonce: u32,
+    records: u32,
+    len: u64,
+}
+
+impl Journal {
+    /// Starts a journal for a database of `pages` pages, its record count
+    /// left at zero until [`Journal::seal`].
+    pub fn create(path: PathBuf, pages: u32, page_size: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        // Make the new directory entry durable, where directories can be
+        // synced at all.
+        if let Some(dir) = path.parent().and_then(|d| File::open(d).ok()) {
+            let _ = dir.sync_all();
+        }
+        let nonce = io::nonce();
+
+        let mut head = vec![0u8; SECTOR_SIZE as usize];
+        head[..8].copy_from_slice(&MAGIC);
+        head[12..16].copy_from_slice(&nonce.to_be_bytes());
+        head[16..20].copy_from_slice(&pages.to_be_bytes());
+        head[20..24].copy_from_slice(&SECTOR_SIZE.to_be_bytes());
+        head[24..28].copy_from_slice(&(page_size as u32).to_be_bytes());
+        let io = Positional::new(file);
+        io.write_at(0, &head)?;
+        Ok(Self {
+            path,
+            io,
+            page_size,
+            nonce,
+            records: 0,
+            len: SECTOR_SIZE as u64,
+        })
+    }
+
+    /// Saves the original image of page `idx`.
+    pub fn append(&mut self, idx: i64, data: &[u8]) -> Result<()> {
+        ensure!(data.len() == self.page_size, "invalid page size");
+        let mut record = Vec::with_capacity(self.page_size + 8);
+        record.extend((idx as u32).to_be_bytes());
+        record.extend_from_slice(data);
+        record.extend(checksum(self.nonce, data).to_be_bytes());
+        self.io.write_at(self.len, &record)?;
+        self.len += record.len() as u64;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Makes the journal hot: the records reach the disk before the count
+    /// that makes them visible, which itself lands before any database page.
+    pub fn seal(&mut self) -> Result<()> {
+        self.io.sync()?;
+        self.io.write_at(8, &self.records.to_be_bytes())?;
+        self.io.sync()
+    }
+
+    /// Commits the transaction by deleting the journal.
+    pub fn remove(self) -> Result<()> {
+        drop(self.io);
+        io::remove(&self.path)
+    }
+}
+
+/// Puts back the pages saved in the journal at `path`, if any, shrinks `db`
+/// to its size before the interrupted commit and deletes the journal. A
+/// journal never sealed left the database untouched and is simply deleted.
+pub fn rollback(path: &Path, db: &dyn Storage) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    let journal = Positional::new(file);
+    let len = journal.len()?;
+
+    let mut offset = 0;
+    let mut size = None;
+    while offset + 28 <= len {
+        let head = journal.read_at(offset, 28)?;
+        if head[..8] != MAGIC {
+            break;
+        }
+        let mut records = u32_at(&head, 8);
+        let nonce = u32_at(&head, 12);
+        let sector = u32_at(&head, 20);
+        let page_size = u32_at(&head, 24) as usize;
+        let valid = |n: usize, min| n.is_power_of_two() && (min..=65536).contains(&n);
+        if !valid(sector as usize, 32) || !valid(page_size, 512) {
+            break;
+        }
+        size.get_or_insert((u32_at(&head, 16), page_size));
+        offset += sector.max(SECTOR_SIZE) as u64;
+
+        let record = page_size as u64 + 8;
+        if records == UNKNOWN_RECORDS {
+            records = ((len - offset) / record) as u32;
+        }
+        let (pages, _) = size.unwrap_or_default();
+        for _ in 0..records {
+            if offset + record > len {
+                break;
+            }
+            let data = journal.read_at(offset, record as usize)?;
+            offset += record;
+            let idx = u32_at(&data, 0);
+            let page = &data[4..4 + page_size];
+            if checksum(nonce, page) != u32_at(&data, 4 + page_size) {
+                // A torn record, nothing after it was written either.
+                offset = len;
+                break;
+            }
+            if idx == 0 || idx > pages {
+                continue;
+            }
+            db.write_at((idx as u64 - 1) * page_size as u64, page)?;
+        }
+        // Further segments start on a sector boundary.
+        offset = offset.div_ceil(sector as u64) * sector as u64;
+    }
+
+    if let Some((pages, page_size)) = size {
+        let end = pages as u64 * page_size as u64;
+        if db.len()? > end {
+            db.truncate(end)?;
+        }
+        db.sync()?;
+    }
+    drop(journal);
+    io::remove(path)
+}
+
+/// Plays back the journal of database `db` if it is hot, left behind by a
+/// writer that is gone since no process holds RESERVED. Called with SHARED
+/// held, it takes EXCLUSIVE for the rollback and says whether it did one.
+pub fn recover(db: &Path, lock: &mut FileLock) -> Result<bool> {
+    let path = path(db);
+    if !path.exists() || lock.is_reserved()? {
+        return Ok(false);
+    }
+    ensure!(
+        lock.file().is_some(),
+        "hot journal {} needs a read-write open to roll back",
+        path.display()
+    );
+    if !lock.lock(Level::Exclusive)? {
+        bail!("database is locked");
+    }
+    // Another connection may have played it back in the meantime.
+    let done = match (path.exists(), lock.file()) {
+        (true, Some(file)) => rollback(&path, file).map(|()| true),
+        _ => Ok(false),
+    };
+    lock.unlock(Level::Shared)?;
+    done
+}
@@ -0,0 +1,441 @@
+use crate::args::{Condition, Operator};
+use crate::codec::{self, sql::ColumnDef};
+use crate::db::stat::Stats;
+use crate::db::SQL;
+use crate::schema::{Descriptor, Schema};
+use crate::value::{Affinity, Collation, Value};
+use anyhow::Result;
+use std::fmt;
+
+/// How the rows of a table are reached.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    /// Every leaf, in rowid order.
+    Scan,
+    /// The leaf holding a single rowid.
+    Rowid(i64),
+    /// The entries of an index starting with `value`, then the rows they
+    /// point to unless the entries alone answer the query.
+    Index {
+        name: String,
+        root: i64,
+        column: String,
+        value: Value,
+        covering: Option<Covering>,
+    },
+    /// Every entry of an index holding all the query needs, in key order.
+    IndexScan {
+        name: String,
+        root: i64,
+        covering: Covering,
+    },
+}
+
+/// Where the values a query needs sit in the entries of an index, the
+/// rowid being last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Covering {
+    /// Entry position of each column returned.
+    pub columns: Vec<usize>,
+    /// Entry position of the column filtered on.
+    pub filter: Option<usize>,
+}
+
+/// Strategy chosen for a query over a single table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub table: String,
+    pub access: Access,
+}
+
+/// Operator of a plan, as EXPLAIN QUERY PLAN lists it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub detail: String,
+    pub children: Vec<Step>,
+}
+
+/// Literal of `cond` converted to the affinity of the column it is compared
+/// with, as sqlite3 does for `=`. LIKE matches it as text, unconverted.
+pub fn operand(defs: &[ColumnDef], cond: &Condition) -> Value {
+    let affinity = match defs
+        .iter()
+        .find(|d| d.name.eq_ignore_ascii_case(&cond.name))
+    {
+        _ if cond.name == "id" => Affinity::Integer,
+        Some(def) => Affinity::from(def.kind),
+        None => return cond.value.clone(),
+    };
+    match cond.op {
+        Operator::Equal => cond.value.clone().with_affinity(affinity),
+        Operator::Like => cond.value.clone(),
+    }
+}
+
+/// Pages visited going down a B-tree of `rows` entries.
+fn seek_cost(rows: f64) -> f64 {
+    rows.max(2.0).log2()
+}
+
+impl Plan {
+    /// Picks the cheapest way to read table `desc` for rows matching
+    /// `cond`, `columns` being the ones the query returns, none when it
+    /// only counts rows. A scan costs a visit per row; an index seek the
+    /// descent, then one per matching entry and, unless the entries answer
+    /// the query alone, a descent of the table for each. An index holding
+    /// every column needed is scanned instead of the table when its entries
+    /// are narrower than the rows.
+    pub fn choose(
+        db: &dyn SQL,
+        schema: &Schema,
+        desc: &Descriptor,
+        columns: &[String],
+        cond: Option<&Condition>,
+    ) -> Result<Plan> {
+        let plan = |access| {
+            Ok(Plan {
+                table: desc.name.clone(),
+                access,
+            })
+        };
+        let defs = codec::sql::columns(&desc.sql);
+        let ipk = codec::sql::rowid_alias(&defs);
+        let cond = cond.map(|c| Condition {
+            name: c.name.clone(),
+            op: c.op,
+            value: operand(&defs, c),
+        });
+        let cond = cond.as_ref();
+        let pos = cond.map(|c| {
+            defs.iter()
+                .position(|d| d.name.eq_ignore_ascii_case(&c.name))
+        });
+        match (cond, pos) {
+            (Some(c), Some(None)) => {
+                return match (c.name.as_str(), c.op, &c.value) {
+                    ("id", Operator::Equal, Value::Int(id)) => plan(Access::Rowid(*id)),
+                    _ => plan(Access::Scan),
+                };
+            }
+            (Some(c), Some(pos)) if pos == ipk => {
+                return match (c.op, &c.value) {
+                    (Operator::Equal, Value::Int(id)) => plan(Access::Rowid(*id)),
+                    _ => plan(Access::Scan),
+                };
+            }
+            _ => {}
+        }
+        // Index entries are in BINARY order, useless to find other matches.
+        let seek = cond
+            .filter(|c| c.op == Operator::Equal)
+            .zip(pos.flatten())
+            .filter(|(_, pos)| {
+                let collate = defs[*pos].collate;
+                collate.is_none_or(|c| c.eq_ignore_ascii_case("BINARY"))
+            })
+            .map(|(c, _)| c);
+
+        // Entry position of the column `name` in an index keyed on `key`.
+        let position = |key: &[String], name: &str| {
+            let def = defs.iter().position(|d| d.name.eq_ignore_ascii_case(name));
+            match name == "id" || def.is_some() && def == ipk {
+                true => Some(key.len()),
+                false => key.iter().position(|k| k.eq_ignore_ascii_case(name)),
+            }
+        };
+        let covering = |key: &[String]| {
+            Some(Covering {
+                columns: columns
+                    .iter()
+                    .map(|c| position(key, c))
+                    .collect::<Option<_>>()?,
+                filter: match cond {
+                    Some(c) => Some(position(key, &c.name)?),
+                    None => None,
+                },
+            })
+        };
+
+        // Statistics are read with a plain scan, only seeks need them.
+        let stats = match seek {
+            Some(_) => Stats::read(db)?,
+            None => Stats::default(),
+        };
+        let rows = stats.table_rows(&desc.name);
+        // Stored columns, the one aliasing the rowid being left out.
+        let width = (defs.len() - ipk.map_or(0, |_| 1)) as f64;
+        let index = schema
+            .indexes_on(&desc.name)
+            .filter_map(|index| Some((index, index.index_columns(desc).ok()?)))
+            .filter_map(|(index, (unique, key))| {
+                let covering = covering(&key);
+                // Entries under another collation are not found by BINARY seeks.
+                let binary = index
+                    .index_collations(desc)
+                    .is_ok_and(|c| c.first() == Some(&Collation::Binary));
+                let first = key.first().filter(|_| binary);
+                match seek.filter(|c| first.is_some_and(|k| k.eq_ignore_ascii_case(&c.name))) {
+                    Some(c) => {
+                        let unique = unique && key.len() == 1;
+                        let found = stats.rows_equal(&index.name, &c.value, unique);
+                        let per_entry = match covering {
+                            Some(_) => 1.0,
+                            None => 1.0 + seek_cost(rows),
+                        };
+                        let access = Access::Index {
+                            name: index.name.clone(),
+                            root: index.root,
+                            column: defs[pos.flatten()?].name.to_string(),
+                            value: c.value.clone(),
+                            covering,
+                        };
+                        Some((access, seek_cost(rows) + found * per_entry))
+                    }
+                    None => {
+                        let access = Access::IndexScan {
+                            name: index.name.clone(),
+                            root: index.root,
+                            covering: covering?,
+                        };
+                        let cost = rows * (key.len() as f64 + 1.0) / (width + 1.0);
+                        Some((access, cost))
+                    }
+                }
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+        match index {
+            Some((access, cost)) if cost < rows => plan(access),
+            _ => plan(Access::Scan),
+        }
+    }
+
+    pub fn steps(&self) -> Vec<Step> {
+        let table = &self.table;
+        let detail = match &self.access {
+            Access::Scan => format!("SCAN {table}"),
+            Access::Rowid(_) => format!("SEARCH {table} USING INTEGER PRIMARY KEY (rowid=?)"),
+            Access::Index {
+                name,
+                column,
+                covering,
+                ..
+            } => {
+                let kind = match covering {
+                    Some(_) => "COVERING INDEX",
+                    None => "INDEX",
+                };
+                format!("SEARCH {table} USING {kind} {name} ({column}=?)")
+            }
+            Access::IndexScan { name, .. } => format!("SCAN {table} USING COVERING INDEX {name}"),
+        };
+        vec![Step {
+            detail,
+            children: vec![],
+        }]
+    }
+}
+
+/// Tree of the steps, drawn the way sqlite3 prints EXPLAIN QUERY PLAN.
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn draw(f: &mut fmt::Formatter<'_>, steps: &[Step], indent: &str) -> fmt::Result {
+            for (i, step) in steps.iter().enumerate() {
+                let last = i + 1 == steps.len();
+                let (branch, nested) = if last { ("`--", "   ") } else { ("|--", "|  ") };
+                writeln!(f, "{indent}{branch}{}", step.detail)?;
+                draw(f, &step.children, &format!("{indent}{nested}"))?;
+            }
+            Ok(())
+        }
+        writeln!(f, "QUERY PLAN")?;
+        draw(f, &self.steps(), "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::ColumnNames;
+    use crate::db::transaction::tests::create_index;
+    use crate::db::{Options, SQLiteFile};
+    use crate::fixture::TempDb;
+    use crate::page;
+    use itertools::Itertools;
+
+    /// The sample apples with 400 more, over seven colors, and an index on
+    /// their color.
+    fn apples_by_color(name: &str) -> (TempDb, SQLiteFile) {
+        let file = TempDb::sample(name);
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let mut tx = db.begin().unwrap();
+        create_index(
+            &mut tx,
+            "apples_color",
+            "apples",
+            "CREATE INDEX apples_color ON apples(color)",
+        )
+        .unwrap();
+        let rows = (0..400)
+            .map(|i| {
+                vec![
+                    Value::Null,
+                    Value::Text(format!("apple {i}")),
+                    Value::Text((i % 7).to_string()),
+                ]
+            })
+            .collect_vec();
+        tx.insert("apples", None, rows).unwrap();
+        tx.commit().unwrap();
+        (file, db)
+    }
+
+    fn cond(name: &str, value: Value) -> Condition {
+        Condition {
+            name: name.to_string(),
+            op: Operator::Equal,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_plans_follow_indexes() {
+        let (_file, db) = apples_by_color("plan");
+        let table = db.table("apples").unwrap();
+        let names = ColumnNames::from("id,name");
+        let plan = |cols: &ColumnNames, c: Option<&Condition>| table.plan(cols, c).unwrap();
+        assert_eq!(
+            plan(&names, None).to_string(),
+            "QUERY PLAN\n`--SCAN apples\n"
+        );
+        assert_eq!(
+            plan(&names, Some(&cond("id", Value::Int(3)))).steps()[0].detail,
+            "SEARCH apples USING INTEGER PRIMARY KEY (rowid=?)"
+        );
+        let three = || cond("color", Value::Text("3".into()));
+        assert_eq!(
+            plan(&names, Some(&three())).steps()[0].detail,
+            "SEARCH apples USING INDEX apples_color (color=?)"
+        );
+        assert_eq!(
+            plan(&ColumnNames::from(vec![]), Some(&three())).steps()[0].detail,
+            "SEARCH apples USING COVERING INDEX apples_color (color=?)"
+        );
+
+        // Seeks find the same rows, in the same order, as a scan would.
+        let found = table
+            .select(&names, Some(three()))
+            .unwrap()
+            .map(|r| r.unwrap().into_iter().collect_vec())
+            .collect_vec();
+        assert_eq!(found.len(), 57);
+        assert_eq!(found[0], [Value::Int(8), Value::Text("apple 3".into())]);
+        assert!(found
+            .iter()
+            .map(|r| r[0].clone())
+            .tuple_windows()
+            .all(|(a, b)| a.compare(&b).is_lt()));
+        let table = db.table("apples").unwrap();
+        assert_eq!(table.count(Some(three())).unwrap(), 57);
+        assert_eq!(table.count(Some(cond("color", Value::Int(3)))).unwrap(), 57);
+        assert_eq!(table.count(Some(cond("id", Value::Int(404)))).unwrap(), 1);
+        assert_eq!(table.par_count(Some(three()), 4).unwrap(), 57);
+    }
+
+    #[test]
+    fn test_covering_index_answers_without_the_table() {
+        let (file, db) = apples_by_color("plan-covering");
+        let root = db.table("apples").unwrap().desc.root as usize;
+        let size = db.format().page_size;
+        drop(db);
+        // Past an unknown page type at its root, the table cannot be read.
+        let mut bytes = std::fs::read(file.path()).unwrap();
+        bytes[(root - 1) * size] = 0xff;
+        std::fs::write(file.path(), bytes).unwrap();
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+
+        let table = db.table("apples").unwrap();
+        let plan = |cols: &ColumnNames, c: Option<&Condition>| table.plan(cols, c).unwrap();
+        let covered = ColumnNames::from("color,id");
+        let three = || cond("color", Value::Text("3".into()));
+        let like = || Condition {
+            name: "color".to_string(),
+            op: Operator::Like,
+            value: Value::Text("3%".into()),
+        };
+        assert_eq!(
+            plan(&covered, Some(&three())).steps()[0].detail,
+            "SEARCH apples USING COVERING INDEX apples_color (color=?)"
+        );
+        assert_eq!(
+            plan(&covered, Some(&like())).steps()[0].detail,
+            "SCAN apples USING COVERING INDEX apples_color"
+        );
+        let rows = |cols: &ColumnNames, cond| -> Result<Vec<Vec<Value>>> {
+            let table = db.table("apples").unwrap();
+            table
+                .select(cols, Some(cond))?
+                .map_ok(|r| r.into_iter().collect_vec())
+                .collect()
+        };
+        let found = rows(&covered, like()).unwrap();
+        assert_eq!(found, rows(&covered, three()).unwrap());
+        assert_eq!(found.len(), 57);
+        assert_eq!(found[0], [Value::Text("3".into()), Value::Int(8)]);
+        assert_eq!(table.count(Some(like())).unwrap(), 57);
+        assert_eq!(table.count(None).unwrap(), 404);
+
+        // A column the index lacks sends the query to the table.
+        let names = ColumnNames::from("color,name");
+        assert_eq!(
+            plan(&names, Some(&three())).steps()[0].detail,
+            "SEARCH apples USING INDEX apples_color (color=?)"
+        );
+        assert_eq!(plan(&names, Some(&like())).steps()[0].detail, "SCAN apples");
+        assert!(rows(&names, three()).is_err());
+        assert!(rows(&names, like()).is_err());
+    }
+
+    #[test]
+    fn test_plans_weigh_statistics() {
+        let (_file, db) = apples_by_color("plan-stat");
+        let names = ColumnNames::from("id,name");
+        let three = || cond("color", Value::Text("3".into()));
+        let table = db.table("apples").unwrap();
+        let access = |c: &Condition| table.plan(&names, Some(c)).unwrap().access;
+        let two = cond("color", Value::Text("2".into()));
+        assert!(matches!(access(&two), Access::Index { .. }));
+
+        // Columns are named in any case, as in sqlite3.
+        let upper = cond("COLOR", Value::Text("3".into()));
+        assert!(matches!(access(&upper), Access::Index { .. }));
+        let rows = table.count(Some(upper)).unwrap();
+        assert_eq!(rows, table.count(Some(three())).unwrap());
+
+        // Statistics showing most rows share a color make the index a loss,
+        // unless the value sought is a rare sampled one.
+        let mut tx = db.begin().unwrap();
+        let stat1 = "CREATE TABLE sqlite_stat1(tbl,idx,stat)";
+        tx.create_table("sqlite_stat1", stat1).unwrap();
+        let stat = vec![
+            Value::Text("apples".into()),
+            Value::Text("apples_color".into()),
+            Value::Text("404 300".into()),
+        ];
+        tx.insert("sqlite_stat1", None, vec![stat]).unwrap();
+        let stat4 = "CREATE TABLE sqlite_stat4(tbl,idx,neq,nlt,ndlt,sample)";
+        tx.create_table("sqlite_stat4", stat4).unwrap();
+        let key = [Value::Text("3".into()), Value::Int(8)];
+        let sample = vec![
+            Value::Text("apples".into()),
+            Value::Text("apples_color".into()),
+            Value::Text("5 1".into()),
+            Value::Text("0 0".into()),
+            Value::Text("0 0".into()),
+            Value::Blob(page::encode::record(&key, db.format().enc)),
+        ];
+        tx.insert("sqlite_stat4", None, vec![sample]).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(access(&two), Access::Scan);
+        assert!(matches!(access(&three()), Access::Index { .. }));
+    }
+}
@@ -0,0 +1,344 @@
+use crate::args::{ColumnNames, Condition, Operator};
+use crate::codec;
+use crate::db::table::Table;
+use crate::db::{btree, Transaction, SQL};
+use crate::error;
+use crate::page::Record;
+use crate::schema::{self, Descriptor, STAT1_TABLE, STAT4_TABLE};
+use crate::value::{Collation, Value};
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use std::collections::HashMap;
+
+/// Rows a table is assumed to hold when sqlite_stat1 does not tell, the
+/// figure sqlite3 uses.
+const DEFAULT_ROWS: f64 = 1_048_576.0;
+
+/// Rows assumed to share a key of an index without statistics.
+const DEFAULT_ROWS_PER_KEY: f64 = 10.0;
+
+/// Counts ANALYZE leaves in `sqlite_stat1` and `sqlite_stat4`, by lower
+/// case table and index name.
+#[derive(Debug, Default)]
+pub struct Stats {
+    tables: HashMap<String, f64>,
+    /// Rows in the index, then the average rows sharing each key prefix.
+    indexes: HashMap<String, Vec<f64>>,
+    /// First values of the sampled keys, with the rows sharing them.
+    samples: HashMap<String, Vec<(Value, f64)>>,
+}
+
+impl Stats {
+    /// Statistics of the database, none when it was never analyzed.
+    pub fn read(db: &dyn SQL) -> Result<Self> {
+        let schema = db.schema()?;
+        let mut stats = Stats::default();
+        let rows = |name: &str, cols: &str| -> Result<Vec<Vec<Value>>> {
+            let Some(desc) = schema.desc.iter().find(|d| d.name == name) else {
+                return Ok(vec![]);
+            };
+            Table::new(db, desc.clone())
+                .select(&ColumnNames::from(cols), None)?
+                .map(|row| Ok(row?.into_iter().collect_vec()))
+                .collect()
+        };
+        for row in rows(STAT1_TABLE, "tbl,idx,stat")? {
+            let [Value::Text(tbl), idx, Value::Text(stat)] = row.as_slice() else {
+                continue;
+            };
+            let counts = numbers(stat);
+            let Some(&rows) = counts.first() else {
+                continue;
+            };
+            stats.tables.insert(tbl.to_lowercase(), rows);
+            if let Value::Text(idx) = idx {
+                stats.indexes.insert(idx.to_lowercase(), counts);
+            }
+        }
+        for row in rows(STAT4_TABLE, "idx,neq,sample")? {
+            let [Value::Text(idx), Value::Text(neq), Value::Blob(sample)] = row.as_slice() else {
+                continue;
+            };
+            let key = Record::parse(sample, db.format().enc)?.values()?;
+            let (Some(first), Some(rows)) = (key.into_iter().next(), numbers(neq).first().copied())
+            else {
+                continue;
+            };
+            let samples = stats.samples.entry(idx.to_lowercase()).or_default();
+            samples.push((first, rows));
+        }
+        Ok(stats)
+    }
+
+    pub fn table_rows(&self, table: &str) -> f64 {
+        let rows = self.tables.get(&table.to_lowercase());
+        rows.copied().unwrap_or(DEFAULT_ROWS)
+    }
+
+    /// Entries of `index` whose first value is `value`: as sampled when it
+    /// is one of the samples, on average otherwise.
+    pub fn rows_equal(&self, index: &str, value: &Value, unique: bool) -> f64 {
+        let index = index.to_lowercase();
+        let samples = self.samples.get(&index).into_iter().flatten();
+        let sampled = samples
+            .filter(|(key, _)| key == value)
+            .map(|(_, rows)| rows)
+            .next();
+        let average = self.indexes.get(&index).and_then(|counts| counts.get(1));
+        match (sampled, average) {
+            (Some(rows), _) | (None, Some(rows)) => *rows,
+            (None, None) if unique => 1.0,
+            (None, None) => DEFAULT_ROWS_PER_KEY,
+        }
+    }
+}
+
+/// Leading whole numbers of a statistics column, before flags like
+/// `unordered`.
+fn numbers(stat: &str) -> Vec<f64> {
+    stat.split_whitespace()
+        .map_while(|n| n.parse::<u64>().ok())
+        .map(|n| n as f64)
+        .collect()
+}
+
+/// Row of `sqlite_stat1`: the entries of an index, or the rows of a table
+/// without one, followed for an index by the average entries sharing each
+/// prefix of its key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat1 {
+    pub tbl: String,
+    pub idx: Option<String>,
+    pub stat: String,
+}
+
+impl Stat1 {
+    pub fn values(&self) -> Vec<Value> {
+        vec![
+            Value::Text(self.tbl.clone()),
+            self.idx.clone().map_or(Value::Null, Value::Text),
+            Value::Text(self.stat.clone()),
+        ]
+    }
+}
+
+/// Counts ANALYZE stores for the table or index `name`, or for every table,
+/// in schema order. Empty tables and indexes get none, as with sqlite3.
+pub fn gather(db: &dyn SQL, name: Option<&str>) -> Result<Vec<Stat1>> {
+    let schema = db.schema()?;
+    let named = |d: &Descriptor| name.is_none_or(|n| d.name.eq_ignore_ascii_case(n));
+    let tables = schema
+        .desc
+        .iter()
+        .filter(|d| d.kind == schema::Type::Table && !d.internal)
+        .filter(|d| named(d) || schema.indexes_on(&d.name).any(named))
+        .collect_vec();
+    if let (Some(name), []) = (name, tables.as_slice()) {
+        let kind = "table or index";
+        bail!(error::Error::NotFound {
+            kind,
+            name: name.to_string()
+        });
+    }
+
+    let mut stats = Vec::new();
+    for table in tables {
+        // Virtual tables have no B-tree, WITHOUT ROWID ones keep their rows
+        // in an index B-tree.
+        if table.root == 0 || codec::sql::without_rowid(&table.sql) {
+            continue;
+        }
+        let indexes = schema.indexes_on(&table.name).collect_vec();
+        if indexes.is_empty() {
+            let rows = Table::new(db, table.clone()).count(None)?;
+            if rows > 0 {
+                stats.push(Stat1 {
+                    tbl: table.name.clone(),
+                    idx: None,
+                    stat: rows.to_string(),
+                });
+            }
+            continue;
+        }
+        // A table is analyzed whole, an index alone when it is the one named.
+        let whole = named(table);
+        for index in indexes.into_iter().filter(|i| whole || named(i)) {
+            let collations = index.index_collations(table)?;
+            let Some(stat) = index_stat(db, index.root, &collations)? else {
+                continue;
+            };
+            stats.push(Stat1 {
+                tbl: table.name.clone(),
+                idx: Some(index.name.clone()),
+                stat,
+            });
+        }
+    }
+    Ok(stats)
+}
+
+/// Entries of the index under `root`, then for each prefix of the key the
+/// entries per distinct value under `collations`, rounded up. NULLs count
+/// as one value.
+fn index_stat(db: &dyn SQL, root: i64, collations: &[Collation]) -> Result<Option<String>> {
+    let mut entries = 0u64;
+    let mut distinct: Vec<u64> = Vec::new();
+    let mut prev: Option<Vec<Value>> = None;
+    for key in btree::IndexEntries::new(db, root) {
+        let key = key?;
+        // The rowid ending every entry is not part of the key.
+        let columns = key.len().saturating_sub(1);
+        distinct.resize(distinct.len().max(columns), 0);
+        let changed = prev.as_ref().map_or(0, |prev| {
+            let same = prev
+                .iter()
+                .zip(&key[..columns])
+                .zip(
+                    collations
+                        .iter()
+                        .copied()
+                        .chain(std::iter::repeat(Collation::Binary)),
+                )
+                .take_while(|((a, b), collation)| a.collate(b, *collation).is_eq());
+            same.count()
+        });
+        distinct[changed..columns].iter_mut().for_each(|d| *d += 1);
+        entries += 1;
+        prev = Some(key);
+    }
+    if entries == 0 {
+        return Ok(None);
+    }
+    let averages = distinct.iter().map(|d| {
+        let d = (*d).max(1);
+        // Like sqlite3, a nearly unique prefix counts as unique.
+        match entries.div_ceil(d) {
+            2 if entries * 10 <= d * 11 => 1,
+            average => average,
+        }
+    });
+    Ok(Some(std::iter::once(entries).chain(averages).join(" ")))
+}
+
+/// Replaces the statistics of what `name` names, or all of them, with
+/// `stats`, creating `sqlite_stat1` when needed. Samples in `sqlite_stat4`
+/// for the same tables are dropped, stale next to the new counts. Returns
+/// the number of rows written.
+pub fn store(tx: &mut Transaction, name: Option<&str>, stats: Vec<Stat1>) -> Result<usize> {
+    let schema = tx.schema()?;
+    let exists = |table: &str| schema.desc.iter().any(|d| d.name == table);
+    if !exists(STAT1_TABLE) {
+        let sql = format!("CREATE TABLE {STAT1_TABLE}(tbl,idx,stat)");
+        tx.create_table(STAT1_TABLE, &sql)?;
+    }
+    let named = name.and_then(|n| schema.desc.iter().find(|d| d.name.eq_ignore_ascii_case(n)));
+    let stale = named.map(|d| match d.kind {
+        schema::Type::Index => ("idx", d.name.clone()),
+        _ => ("tbl", d.name.clone()),
+    });
+    let cond = || {
+        stale.as_ref().map(|(column, name)| Condition {
+            name: column.to_string(),
+            op: Operator::Equal,
+            value: Value::Text(name.clone()),
+        })
+    };
+    tx.delete(STAT1_TABLE, cond())?;
+    if exists(STAT4_TABLE) {
+        tx.delete(STAT4_TABLE, cond())?;
+    }
+    let rows = stats.iter().map(Stat1::values).collect_vec();
+    tx.insert(STAT1_TABLE, None, rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::transaction::tests::create_index;
+    use crate::db::{Options, SQLiteFile};
+    use crate::fixture::TempDb;
+
+    #[test]
+    fn test_analyze_counts_rows_per_key_prefix() {
+        let file = TempDb::sample("stat");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let mut tx = db.begin().unwrap();
+        let sql = "CREATE INDEX apples_color_name ON apples(color, name)";
+        create_index(&mut tx, "apples_color_name", "apples", sql).unwrap();
+        let rows = (0..96)
+            .map(|i| {
+                let color = [Value::Null, Value::Text("Red".into())][i % 2].clone();
+                vec![Value::Null, Value::Text(format!("apple {}", i % 12)), color]
+            })
+            .collect_vec();
+        tx.insert("apples", None, rows).unwrap();
+        tx.commit().unwrap();
+
+        let stats = gather(&db, None).unwrap();
+        let stat = |tbl: &str, idx: Option<&str>, stat: &str| Stat1 {
+            tbl: tbl.to_string(),
+            idx: idx.map(String::from),
+            stat: stat.to_string(),
+        };
+        // NULL and the 4 colors of the first apples, each of the 12 new
+        // names with a single color: 5 colors and 16 pairs over 100 rows.
+        let color = stat("apples", Some("apples_color_name"), "100 20 7");
+        assert_eq!(stats, [color.clone(), stat("oranges", None, "6")]);
+        assert!(gather(&db, Some("pears")).is_err());
+
+        let mut tx = db.begin().unwrap();
+        assert_eq!(store(&mut tx, None, stats).unwrap(), 2);
+        let again = gather(&tx, Some("APPLES_COLOR_NAME")).unwrap();
+        assert_eq!(store(&mut tx, Some("apples_color_name"), again).unwrap(), 1);
+        tx.commit().unwrap();
+        let rows = db
+            .table(STAT1_TABLE)
+            .unwrap()
+            .select(&ColumnNames::from("tbl,idx,stat"), None)
+            .unwrap()
+            .map(|r| r.unwrap().into_iter().collect_vec())
+            .collect_vec();
+        assert_eq!(rows, [stat("oranges", None, "6").values(), color.values()]);
+        let stats = Stats::read(&db).unwrap();
+        assert_eq!(stats.table_rows("Apples"), 100.0);
+    }
+
+    #[test]
+    fn test_analyze_matches_sqlite3() {
+        let file = TempDb::sample("stat-sqlite3");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let mut tx = db.begin().unwrap();
+        let sql = "CREATE TABLE codes(id INTEGER PRIMARY KEY, code TEXT COLLATE NOCASE, n INTEGER)";
+        tx.create_table("codes", sql).unwrap();
+        let rows = (0..2000)
+            .map(|i| {
+                let code = format!("{} {}", ["code", "CODE"][i % 2], i / 2);
+                vec![Value::Null, Value::Text(code), Value::Int(i as i64 % 1964)]
+            })
+            .collect_vec();
+        for sql in [
+            "CREATE INDEX codes_code ON codes(code)",
+            "CREATE INDEX codes_n ON codes(n)",
+            "CREATE INDEX codes_code_n ON codes(code COLLATE BINARY, n)",
+        ] {
+            let name = sql.split_whitespace().nth(2).unwrap();
+            create_index(&mut tx, name, "codes", sql).unwrap();
+        }
+        tx.insert("codes", None, rows).unwrap();
+        tx.commit().unwrap();
+
+        // As sqlite3 3.51 writes them for the same table: codes differing in
+        // case are one value under NOCASE, and 1964 values over 2000 rows
+        // are nearly unique.
+        let stats = gather(&db, Some("codes")).unwrap();
+        let stats = stats.iter().map(|s| (s.idx.as_deref(), s.stat.as_str()));
+        assert_eq!(
+            stats.collect_vec(),
+            [
+                (Some("codes_code"), "2000 2"),
+                (Some("codes_n"), "2000 1"),
+                (Some("codes_code_n"), "2000 1 1"),
+            ]
+        );
+    }
+}
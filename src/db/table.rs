@@ -1,16 +1,73 @@
-use crate::args::{ColumnNames, Condition};
-use crate::db::{SQLiteFile, SQL};
-use crate::page;
-use crate::page::{Column, Page, TableLeafPage};
-use crate::schema::Descriptor;
-use crate::value::Value;
+use crate::args::{ColumnNames, Condition, Operator};
+use crate::codec;
+use crate::db::btree::{self, Leaves};
+use crate::db::plan::{self, Access, Covering, Plan};
+use crate::db::SQL;
+use crate::page::{Column, Record, TableLeafCell, TableLeafPage};
+use crate::schema::{self, Descriptor};
+use crate::value::{Value, ValueRef};
+use anyhow::Result;
 use itertools::Itertools;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Mutex, PoisonError};
+
+/// Leaf batches buffered per partition before a worker waits on the consumer.
+const PARTITION_BACKLOG: usize = 8;
+
+/// Partitions handed out per worker, so uneven subtrees still balance out.
+const PARTITIONS_PER_THREAD: usize = 4;
+
+/// A column read out of records, with the value of rows written before an
+/// ALTER TABLE added it.
+#[derive(Clone)]
+struct Field {
+    col: Column,
+    default: Value,
+}
+
+impl Field {
+    fn value<'r>(&'r self, record: &Record<'r>, id: i64) -> Result<ValueRef<'r>> {
+        match self.col {
+            Column::Content(i) if i >= record.len() => Ok(ValueRef::from(&self.default)),
+            col => Ok(record.value(id, &col)?),
+        }
+    }
+}
 
 #[derive(Clone)]
-struct Filter(Column, Value);
+struct Filter(Field, Operator, Value);
+
+impl Filter {
+    fn accepts(&self, value: ValueRef<'_>) -> bool {
+        match self.1 {
+            Operator::Equal => value == self.2,
+            // NULL is like nothing, other values are matched as text.
+            Operator::Like => match value {
+                ValueRef::Null => false,
+                value => schema::like(&self.2.to_string(), &value.to_string()),
+            },
+        }
+    }
+}
+
+/// Index entries a query is answered from.
+type Entries<'a> = Box<dyn Iterator<Item = Result<Vec<Value>>> + 'a>;
+
+/// Leaf pages holding the rows of a query.
+type Pages<'a> = Box<dyn Iterator<Item = Result<TableLeafPage>> + 'a>;
+
+/// Rows a query returns, or why the next one could not be read.
+pub type Rows<'a> = Box<dyn Iterator<Item = Result<Row>> + 'a>;
 
 pub struct Row(Vec<Value>);
 
+impl Row {
+    pub fn as_slice(&self) -> &[Value] {
+        self.0.as_slice()
+    }
+}
+
 impl IntoIterator for Row {
     type Item = Value;
     type IntoIter = std::vec::IntoIter<Value>;
@@ -21,106 +78,474 @@ impl IntoIterator for Row {
 }
 
 pub struct Table<'a> {
-    db: &'a SQLiteFile,
+    db: &'a dyn SQL,
     pub desc: Descriptor,
-    pub root: Page,
 }
 
 impl<'a> Table<'a> {
-    pub fn new(db: &'a SQLiteFile, desc: Descriptor, root: Page) -> Self {
-        Self { db, desc, root }
+    pub fn new(db: &'a dyn SQL, desc: Descriptor) -> Self {
+        Self { db, desc }
     }
 
-    fn find_columns(&self, names: &[String]) -> Vec<Column> {
-        let cols = self.desc.column_names();
+    fn find_columns(&self, names: &[String]) -> Vec<Field> {
+        let cols = codec::sql::columns(&self.desc.sql);
+        // The record holds NULL for the column aliasing the rowid.
+        let ipk = codec::sql::rowid_alias(&cols);
+        let rowid = || Field {
+            col: Column::ID,
+            default: Value::Null,
+        };
         names
             .iter()
             .flat_map(|name| match name.as_str() {
-                "id" => Some(Column::ID),
+                "id" => Some(rowid()),
                 name => cols
                     .iter()
-                    .find_position(|c| **c == name)
-                    .map(|c| Column::Content(c.0)),
+                    .position(|c| c.name.eq_ignore_ascii_case(name))
+                    .map(|i| match Some(i) == ipk {
+                        true => rowid(),
+                        false => Field {
+                            col: Column::Content(i),
+                            default: cols[i]
+                                .default
+                                .and_then(|v| v.parse().ok())
+                                .unwrap_or(Value::Null),
+                        },
+                    }),
             })
             .collect_vec()
     }
 
     fn filter_from(&self, c: Condition) -> Option<Filter> {
+        let value = plan::operand(&codec::sql::columns(&self.desc.sql), &c);
         self.find_columns(&[c.name])
-            .first()
-            .map(|i| Filter(*i, c.value))
+            .pop()
+            .map(|field| Filter(field, c.op, value))
     }
 
-    fn leaves(self) -> Box<dyn Iterator<Item = TableLeafPage> + 'a> {
-        let kind = self.root.head.kind;
-        match kind {
-            page::Kind::TableLeaf => {
-                let leave = self.root.into_leaf().unwrap();
-                let iter = std::iter::once(leave);
-                Box::new(iter)
+    /// Strategy [`Table::select`] follows for `cols` and `cond`, or
+    /// [`Table::count`] with no columns.
+    pub fn plan(&self, cols: &ColumnNames, cond: Option<&Condition>) -> Result<Plan> {
+        let schema = self.db.schema()?;
+        Plan::choose(self.db, &schema, &self.desc, cols.as_slice(), cond)
+    }
+
+    fn access(&self, cols: &ColumnNames, cond: Option<&Condition>) -> Result<Access> {
+        Ok(self.plan(cols, cond)?.access)
+    }
+
+    /// Leaves holding the rows `access` reaches, in rowid order. Other rows
+    /// on them are left to the filter.
+    fn pages(&self, access: &Access) -> Result<Pages<'a>> {
+        let (db, root) = (self.db, self.desc.root);
+        let mut pages = match access {
+            Access::Scan | Access::IndexScan { .. } => return Ok(Box::new(Leaves::new(db, root))),
+            Access::Rowid(id) => vec![btree::leaf(db, root, *id)?],
+            Access::Index {
+                root: index, value, ..
+            } => {
+                let mut ids = btree::index_rowids(db, *index, value)?;
+                ids.sort_unstable();
+                ids.into_iter()
+                    .map(|id| btree::leaf(db, root, id))
+                    .collect::<Result<Vec<_>>>()?
+            }
+        };
+        pages.dedup();
+        Ok(Box::new(
+            pages.into_iter().flat_map(move |idx| Leaves::new(db, idx)),
+        ))
+    }
+
+    /// Entries of the index `access` answers the query from alone, with
+    /// where the values needed sit in them.
+    fn entries(&self, access: &Access) -> Result<Option<(Entries<'a>, Covering)>> {
+        let db = self.db;
+        let (entries, covering): (Entries<'a>, _) = match access {
+            Access::Index {
+                root,
+                value,
+                covering: Some(covering),
+                ..
+            } => {
+                let entries = btree::index_seek(db, *root, value)?;
+                (Box::new(entries.into_iter().map(Ok)), covering)
             }
-            page::Kind::TableInterior => {
-                let interior = self.root.into_interior().unwrap();
-                let rhs = std::iter::once(interior.rhs())
-                    .flatten()
-                    .flat_map(|i| self.db.page_at(i as i64));
-                let iter = interior
-                    .cells()
-                    .into_iter()
-                    .flat_map(|c| self.db.page_at(c.lhs as i64))
-                    .chain(rhs)
-                    .flat_map(|p| p.into_leaf());
-                Box::new(iter)
+            Access::IndexScan { root, covering, .. } => {
+                (Box::new(btree::IndexEntries::new(db, *root)), covering)
             }
-            _ => Box::new(std::iter::empty()),
+            _ => return Ok(None),
+        };
+        Ok(Some((entries, covering.clone())))
+    }
+
+    pub fn select(self, cols: &ColumnNames, cond: Option<Condition>) -> Result<Rows<'a>> {
+        let access = self.access(cols, cond.as_ref())?;
+        let filter = cond.and_then(|c| self.filter_from(c));
+        if let Some((entries, covering)) = self.entries(&access)? {
+            return Ok(Box::new(entries.filter_map(move |entry| {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e)),
+                };
+                let accepted = entry_matches(&entry, &covering, &filter);
+                let value = |i: &usize| entry.get(*i).cloned().unwrap_or(Value::Null);
+                let values = covering.columns.iter().map(value);
+                accepted.then(|| Ok(Row(values.collect())))
+            })));
         }
+        let leaves = self.pages(&access)?;
+        let cols = self.find_columns(cols.as_slice());
+        let db = self.db;
+        Ok(Box::new(
+            leaves
+                .map(move |page| {
+                    let fetcher = SelectFetcher {
+                        db,
+                        page: page?,
+                        cols: cols.clone(),
+                        filter: filter.clone(),
+                    };
+                    fetcher.fetch()
+                })
+                .flat_map(|rows| match rows {
+                    Ok(rows) => rows.into_iter().map(Ok).collect_vec(),
+                    Err(e) => vec![Err(e)],
+                }),
+        ))
     }
 
-    pub fn select(
-        self,
+    pub fn count(&self, cond: Option<Condition>) -> Result<usize> {
+        let access = self.access(&ColumnNames::from(vec![]), cond.as_ref())?;
+        let filter = cond.and_then(|c| self.filter_from(c));
+        if let (Access::IndexScan { root, .. }, None) = (&access, &filter) {
+            return btree::index_len(self.db, *root);
+        }
+        if let Some((entries, covering)) = self.entries(&access)? {
+            return entries
+                .map(|entry| Ok(usize::from(entry_matches(&entry?, &covering, &filter))))
+                .sum();
+        }
+        self.pages(&access)?
+            .map(|page| count_leaf(self.db, &page?, &filter))
+            .sum()
+    }
+
+    /// Same rows, in the same order, as [`Table::select`] but with leaf
+    /// pages decoded and filtered on `threads` workers. Rows are handed to
+    /// `sink` on the calling thread as soon as every row before them is.
+    pub fn par_select(
+        &self,
         cols: &ColumnNames,
         cond: Option<Condition>,
-    ) -> impl Iterator<Item = Row> + 'a {
-        let filter = cond.map(|c| self.filter_from(c)).flatten();
+        threads: usize,
+        mut sink: impl FnMut(Row),
+    ) -> Result<()> {
+        // Only table scans are shared out, a seek reads too few pages to be
+        // worth it and index entries come in key order.
+        if self.access(cols, cond.as_ref())? != Access::Scan {
+            let table = Table::new(self.db, self.desc.clone());
+            for row in table.select(cols, cond)? {
+                sink(row?);
+            }
+            return Ok(());
+        }
+        let filter = cond.and_then(|c| self.filter_from(c));
         let cols = self.find_columns(cols.as_slice());
-        let leaves = self.leaves();
-        leaves
-            .map(move |page| SelectFetcher {
-                page,
-                cols: cols.clone(),
-                filter: filter.clone(),
-            })
-            .map(|f| f.fetch())
-            .filter(|res| !res.is_empty())
-            .map(|r| r.into_iter())
-            .flatten()
+        let parts = btree::partitions(self.db, self.desc.root, threads * PARTITIONS_PER_THREAD)?;
+        let (txs, rxs): (Vec<_>, Vec<_>) = parts
+            .iter()
+            .map(|_| sync_channel(PARTITION_BACKLOG))
+            .map(|(tx, rx)| (Mutex::new(Some(tx)), rx))
+            .unzip();
+
+        self.on_workers(
+            &parts,
+            threads,
+            |i, leaves| {
+                // Dropping the sender, even on unwind, tells the consumer this
+                // partition is complete.
+                let tx: Option<SyncSender<Result<Vec<Row>>>> =
+                    txs[i].lock().unwrap_or_else(PoisonError::into_inner).take();
+                let Some(tx) = tx else {
+                    return;
+                };
+                for page in leaves {
+                    let rows = page.and_then(|page| {
+                        let fetcher = SelectFetcher {
+                            db: self.db,
+                            page,
+                            cols: cols.clone(),
+                            filter: filter.clone(),
+                        };
+                        fetcher.fetch()
+                    });
+                    let failed = rows.is_err();
+                    if tx.send(rows).is_err() || failed {
+                        break;
+                    }
+                }
+            },
+            // Returning early drops the receivers left, which stops the
+            // workers still sending.
+            move || {
+                for rx in rxs {
+                    for rows in rx {
+                        rows?.into_iter().for_each(&mut sink);
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+
+    /// Counts matching rows with partial counts per partition summed up.
+    pub fn par_count(&self, cond: Option<Condition>, threads: usize) -> Result<usize> {
+        if self.access(&ColumnNames::from(vec![]), cond.as_ref())? != Access::Scan {
+            return self.count(cond);
+        }
+        let filter = cond.and_then(|c| self.filter_from(c));
+        let parts = btree::partitions(self.db, self.desc.root, threads * PARTITIONS_PER_THREAD)?;
+        let total = AtomicUsize::new(0);
+        let failed = Mutex::new(None);
+        self.on_workers(
+            &parts,
+            threads,
+            |_, leaves| {
+                let count = leaves
+                    .map(|page| count_leaf(self.db, &page?, &filter))
+                    .sum::<Result<usize>>();
+                match count {
+                    Ok(count) => _ = total.fetch_add(count, Ordering::Relaxed),
+                    Err(e) => {
+                        let mut failed = failed.lock().unwrap_or_else(PoisonError::into_inner);
+                        failed.get_or_insert(e);
+                    }
+                }
+            },
+            || {},
+        );
+        match failed.into_inner().unwrap_or_else(PoisonError::into_inner) {
+            Some(e) => Err(e),
+            None => Ok(total.into_inner()),
+        }
     }
+
+    /// Runs `work` for every partition on a pool of `threads` scoped workers,
+    /// partitions being picked up in order, while `consume` runs on the
+    /// calling thread.
+    fn on_workers<W, C, T>(&self, parts: &[i64], threads: usize, work: W, consume: C) -> T
+    where
+        W: Fn(usize, Pages<'_>) + Sync,
+        C: FnOnce() -> T,
+    {
+        let next = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..threads.clamp(1, parts.len().max(1)) {
+                s.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(root) = parts.get(i) else {
+                        break;
+                    };
+                    work(i, Box::new(Leaves::new(self.db, *root)));
+                });
+            }
+            consume()
+        })
+    }
+}
+
+/// Decodes the record of a cell, following its overflow chain if needed,
+/// and hands it to `f`.
+fn with_record<T>(
+    db: &dyn SQL,
+    page: &TableLeafPage,
+    cell: &TableLeafCell<'_>,
+    f: impl FnOnce(&Record<'_>) -> Result<T>,
+) -> Result<T> {
+    let payload = btree::payload(db, &cell.payload)?;
+    let record = Record::parse(&payload, page.enc())?;
+    f(&record)
 }
 
-struct SelectFetcher {
+fn matches(record: &Record<'_>, id: i64, filter: &Option<Filter>) -> Result<bool> {
+    let Some(filter) = filter else {
+        return Ok(true);
+    };
+    Ok(filter.accepts(filter.0.value(record, id)?))
+}
+
+/// Whether an index entry passes `filter`, on the value at the position
+/// `covering` gives.
+fn entry_matches(entry: &[Value], covering: &Covering, filter: &Option<Filter>) -> bool {
+    match (filter, covering.filter) {
+        (Some(filter), Some(i)) => entry.get(i).is_some_and(|v| filter.accepts(v.into())),
+        _ => true,
+    }
+}
+
+fn count_leaf(db: &dyn SQL, page: &TableLeafPage, filter: &Option<Filter>) -> Result<usize> {
+    if filter.is_none() {
+        return Ok(page.len());
+    }
+    page.cells()?
+        .iter()
+        .map(|cell| with_record(db, page, cell, |r| matches(r, cell.id, filter)).map(usize::from))
+        .sum()
+}
+
+struct SelectFetcher<'a> {
+    db: &'a dyn SQL,
     page: TableLeafPage,
-    cols: Vec<Column>,
+    cols: Vec<Field>,
     filter: Option<Filter>,
 }
 
-impl SelectFetcher {
-    fn fetch(self) -> Vec<Row> {
-        let cols = &self.cols;
-        let filter = &self.filter;
-        let cells = self.page.cells();
+impl SelectFetcher<'_> {
+    fn fetch(self) -> Result<Vec<Row>> {
+        let cells = self.page.cells()?;
         cells
             .iter()
-            .filter(|cell| {
-                let Some(ref filter) = filter else {
-                    return true;
-                };
-                let Ok(ref val) = cell.value(&filter.0) else {
-                    return true;
-                };
-                val == &filter.1
+            .filter_map(|cell| {
+                with_record(self.db, &self.page, cell, |record| {
+                    if !matches(record, cell.id, &self.filter)? {
+                        return Ok(None);
+                    }
+                    let values = self
+                        .cols
+                        .iter()
+                        .map(|field| Ok(field.value(record, cell.id)?.into_owned()))
+                        .collect::<Result<Vec<_>>>()?;
+                    Ok(Some(Row(values)))
+                })
+                .transpose()
             })
-            .map(|cell| cols.iter().flat_map(|i| cell.value(i)).collect_vec())
-            .map(Row)
-            .collect_vec()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Condition;
+    use crate::db::{Options, SQLiteFile};
+    use crate::error::Error;
+    use crate::fixture::TempDb;
+
+    #[test]
+    fn test_corrupt_leaf_fails_the_query() {
+        let file = TempDb::sample("corrupt-leaf");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let rows = (0..1996)
+            .map(|i| vec![Value::Null, Value::Text(format!("apple {i}")), Value::Null])
+            .collect();
+        let mut tx = db.begin().unwrap();
+        tx.insert("apples", None, rows).unwrap();
+        tx.commit().unwrap();
+        let table = db.table("apples").unwrap();
+        let leaf = btree::leaf(&db, table.desc.root, 1000).unwrap();
+        let size = db.format().page_size;
+        drop(db);
+
+        // Point the first cell of a leaf in the middle past the page end.
+        let mut bytes = std::fs::read(file.path()).unwrap();
+        let at = (leaf as usize - 1) * size + 8;
+        bytes[at..at + 2].copy_from_slice(&[0xff, 0xff]);
+        std::fs::write(file.path(), bytes).unwrap();
+
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        fn corrupt<T>(res: Result<T>) -> bool {
+            let e = res.err().expect("corrupt leaf went unnoticed");
+            matches!(e.downcast_ref(), Some(Error::Corrupt { .. }))
+        }
+        let cols = ColumnNames::from("id,name");
+        let table = || db.table("apples").unwrap();
+        let like = || Condition::try_from("name like 'apple%'").ok();
+        assert!(corrupt(
+            table()
+                .select(&cols, None)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+        ));
+        assert!(corrupt(table().count(like())));
+        assert!(corrupt(table().par_count(like(), 4)));
+        assert!(corrupt(table().par_select(&cols, None, 4, drop)));
+        let mut tx = db.begin().unwrap();
+        assert!(corrupt(tx.delete("apples", like())));
+    }
+
+    #[test]
+    fn test_parallel_scans_match_sequential_ones() {
+        let file = TempDb::sample("par-scan");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let rows = (0..3000)
+            .map(|i| {
+                let name = format!("apple {i} ").repeat(i % 7 + 1);
+                let color = ["Red", "Green", "Yellow"][i % 3].to_string();
+                vec![Value::Null, Value::Text(name), Value::Text(color)]
+            })
+            .collect();
+        let mut tx = db.begin().unwrap();
+        tx.insert("apples", None, rows).unwrap();
+        tx.commit().unwrap();
+
+        let cols = ColumnNames::from("id,name,color");
+        let table = || db.table("apples").unwrap();
+        for filtered in [false, true] {
+            let cond = || {
+                Condition::try_from("color = 'Red'")
+                    .ok()
+                    .filter(|_| filtered)
+            };
+            let rows = table().select(&cols, cond()).unwrap();
+            let rows = rows
+                .map_ok(Vec::from_iter)
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            let count = table().count(cond()).unwrap();
+            assert_eq!(count, rows.len());
+            assert!(filtered || count == 3004);
+            for threads in [2, 3, 8] {
+                let root = table().desc.root;
+                let parts = btree::partitions(&db, root, threads * PARTITIONS_PER_THREAD);
+                assert!(parts.unwrap().len() > 1);
+                let mut found = vec![];
+                table()
+                    .par_select(&cols, cond(), threads, |row| {
+                        found.push(Vec::from_iter(row))
+                    })
+                    .unwrap();
+                assert_eq!(found, rows, "{threads} threads");
+                assert_eq!(table().par_count(cond(), threads).unwrap(), count);
+            }
+        }
+    }
+
+    #[test]
+    fn test_scans_compare_literals_under_column_affinity() {
+        let file = TempDb::sample("affinity-scan");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let mut tx = db.begin().unwrap();
+        tx.create_table("t", "CREATE TABLE t(n INTEGER, r REAL, s TEXT)")
+            .unwrap();
+        let row = ["7", "2.5", "3"].map(|v| Value::Text(v.to_string()));
+        tx.insert("t", None, vec![row.to_vec()]).unwrap();
+        tx.commit().unwrap();
+
+        let count = |cond: &str| {
+            let table = db.table("t").unwrap();
+            table.count(Condition::try_from(cond).ok()).unwrap()
+        };
+        for cond in [
+            "n = '7'",
+            "r = '2.5'",
+            "r = 2.5",
+            "s = 3",
+            "s LIKE 3",
+            "id = '1'",
+        ] {
+            assert_eq!(count(cond), 1, "{cond}");
+        }
+        assert_eq!(count("n = '7.5'"), 0);
     }
 }
@@ -0,0 +1,667 @@
+use crate::args::{ColumnNames, Condition};
+use crate::chunk::Chunk;
+use crate::codec;
+use crate::db::btree;
+use crate::db::header::{Header, PENDING_BYTE};
+use crate::db::table::Table;
+use crate::db::{FileGuard, SQLiteFile, SQL};
+use crate::error;
+use crate::page::{self, Format, Kind};
+use crate::schema::{self, Schema};
+use crate::value::{Affinity, Collation, Value};
+use anyhow::{bail, ensure, Context, Result};
+use itertools::Itertools;
+use std::collections::BTreeMap;
+use std::sync::{Arc, MutexGuard};
+
+struct ColumnInfo {
+    name: String,
+    affinity: Affinity,
+    not_null: bool,
+    default: Value,
+}
+
+struct IndexInfo {
+    root: i64,
+    unique: bool,
+    /// Positions of the indexed columns in the table.
+    columns: Vec<usize>,
+    /// How the text of each indexed column is ordered.
+    collations: Vec<Collation>,
+}
+
+impl IndexInfo {
+    /// Entry of row `id`: the indexed values followed by the rowid.
+    fn key(&self, values: &[Value], id: i64) -> Vec<Value> {
+        let mut key = self
+            .columns
+            .iter()
+            .map(|i| values[*i].clone())
+            .collect_vec();
+        key.push(Value::Int(id));
+        key
+    }
+}
+
+struct Target {
+    name: String,
+    root: i64,
+    /// Column aliasing the rowid.
+    ipk: Option<usize>,
+    autoincrement: bool,
+    columns: Vec<ColumnInfo>,
+    indexes: Vec<IndexInfo>,
+}
+
+impl Target {
+    fn position(&self, col: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|c| c.name.eq_ignore_ascii_case(col))
+    }
+
+    fn convert(&self, values: &mut [Value]) {
+        for (v, c) in values.iter_mut().zip(&self.columns) {
+            *v = std::mem::replace(v, Value::Null).with_affinity(c.affinity);
+        }
+    }
+}
+
+/// Pending changes to a database. Pages are copied on first write and only
+/// reach the file on [`Transaction::commit`]; dropping the transaction
+/// discards them.
+pub struct Transaction<'a> {
+    db: &'a SQLiteFile,
+    /// Released before the writer lock, while no other writer can start.
+    _file: FileGuard<'a>,
+    _lock: MutexGuard<'a, ()>,
+    head: Header,
+    pages: u32,
+    dirty: BTreeMap<i64, Chunk>,
+    /// Schema as changed by this transaction, if it did.
+    schema: Option<Arc<Schema>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(super) fn new(
+        db: &'a SQLiteFile,
+        file: FileGuard<'a>,
+        lock: MutexGuard<'a, ()>,
+    ) -> Result<Self> {
+        let pages = db.page_count()?;
+        Ok(Self {
+            db,
+            _file: file,
+            _lock: lock,
+            head: db.header(),
+            pages,
+            dirty: BTreeMap::new(),
+            schema: None,
+        })
+    }
+
+    pub fn write_page(&mut self, idx: i64, data: Vec<u8>) -> Result<()> {
+        ensure!(
+            idx > 0 && idx <= self.pages as i64,
+            "page {idx} out of range"
+        );
+        ensure!(data.len() == self.format().page_size, "invalid page size");
+        self.dirty.insert(idx, data.into());
+        Ok(())
+    }
+
+    fn pending_page(&self) -> i64 {
+        (PENDING_BYTE / self.format().page_size as u64) as i64 + 1
+    }
+
+    /// Hands out a page off the freelist, or grows the file by one page.
+    /// The caller owns the page and must write all of it.
+    pub fn allocate(&mut self) -> Result<i64> {
+        let trunk = self.head.freelist_trunk() as i64;
+        let count = self.head.freelist_count();
+        if trunk == 0 {
+            self.pages += 1;
+            if self.pages as i64 == self.pending_page() {
+                self.pages += 1;
+            }
+            return Ok(self.pages as i64);
+        }
+
+        let mut data = self.raw_page(trunk)?.to_vec();
+        let next = u32::from_be_bytes(data[0..4].try_into()?);
+        let leaves = u32::from_be_bytes(data[4..8].try_into()?);
+        if leaves == 0 {
+            self.head.set_freelist(next, count.saturating_sub(1));
+            return Ok(trunk);
+        }
+        let at = 8 + 4 * (leaves as usize - 1);
+        let leaf = u32::from_be_bytes(data[at..at + 4].try_into()?) as i64;
+        ensure!(
+            leaf > 0 && leaf <= self.pages as i64,
+            "freelist leaf {leaf} out of range"
+        );
+        data[4..8].copy_from_slice(&(leaves - 1).to_be_bytes());
+        self.write_page(trunk, data)?;
+        self.head
+            .set_freelist(trunk as u32, count.saturating_sub(1));
+        Ok(leaf)
+    }
+
+    /// Puts page `idx` on the freelist, as a leaf of the first trunk while it
+    /// has room, as the new first trunk otherwise.
+    pub fn free(&mut self, idx: i64) -> Result<()> {
+        let trunk = self.head.freelist_trunk();
+        let count = self.head.freelist_count() + 1;
+        let size = self.format().page_size;
+        if trunk != 0 {
+            let mut data = self.raw_page(trunk as i64)?.to_vec();
+            let leaves = u32::from_be_bytes(data[4..8].try_into()?) as usize;
+            // sqlite3 before 3.6.0 rejects trunks filled to the last slot.
+            if leaves < self.format().usable / 4 - 8 {
+                let at = 8 + 4 * leaves;
+                data[at..at + 4].copy_from_slice(&(idx as u32).to_be_bytes());
+                data[4..8].copy_from_slice(&(leaves as u32 + 1).to_be_bytes());
+                self.write_page(trunk as i64, data)?;
+                self.head.set_freelist(trunk, count);
+                return Ok(());
+            }
+        }
+        let mut data = vec![0u8; size];
+        data[..4].copy_from_slice(&trunk.to_be_bytes());
+        self.write_page(idx, data)?;
+        self.head.set_freelist(idx as u32, count);
+        Ok(())
+    }
+
+    /// Stores `payload` behind a table leaf cell for `id`, spilling what does
+    /// not fit on the page into a chain of overflow pages.
+    pub fn leaf_cell(&mut self, id: i64, payload: &[u8]) -> Result<Vec<u8>> {
+        let fmt = self.format();
+        let local = fmt.local_size(Kind::TableLeaf, payload.len());
+        let overflow = self.write_overflow(&payload[local..])?;
+        Ok(page::encode::leaf_cell(
+            id,
+            payload.len(),
+            &payload[..local],
+            overflow,
+        ))
+    }
+
+    fn write_overflow(&mut self, rest: &[u8]) -> Result<Option<u32>> {
+        let fmt = self.format();
+        let chunks = rest.chunks(fmt.overflow_size()).collect::<Vec<_>>();
+        let pages = chunks
+            .iter()
+            .map(|_| self.allocate())
+            .collect::<Result<Vec<_>>>()?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or_default() as u32;
+            let mut data = vec![0u8; fmt.page_size];
+            data[..4].copy_from_slice(&next.to_be_bytes());
+            data[4..4 + chunk.len()].copy_from_slice(chunk);
+            self.write_page(pages[i], data)?;
+        }
+        Ok(pages.first().map(|p| *p as u32))
+    }
+
+    /// Stores `payload` behind an index leaf cell, spilling what does not fit
+    /// on the page into a chain of overflow pages.
+    pub fn index_cell(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        let fmt = self.format();
+        let local = fmt.local_size(Kind::IndexLeaf, payload.len());
+        let overflow = self.write_overflow(&payload[local..])?;
+        Ok(page::encode::index_cell(
+            payload.len(),
+            &payload[..local],
+            overflow,
+        ))
+    }
+
+    /// Layout of a table about to be modified, with the indexes to keep in
+    /// step with it.
+    fn target(&self, name: &str) -> Result<Target> {
+        let schema = self.schema()?;
+        let desc = schema.table_named(name)?;
+        ensure!(
+            desc.kind == schema::Type::Table,
+            "cannot modify {name} because it is a view"
+        );
+        if codec::sql::without_rowid(&desc.sql) {
+            let table = format!("WITHOUT ROWID table {name}");
+            bail!(error::Error::Unsupported(table));
+        }
+        let defs = codec::sql::columns(&desc.sql);
+        let columns = defs
+            .iter()
+            .map(|d| {
+                Ok(ColumnInfo {
+                    name: d.name.to_string(),
+                    affinity: Affinity::from(d.kind),
+                    not_null: d.not_null,
+                    default: d.default.map_or(Ok(Value::Null), str::parse)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let position = |col: &str| {
+            defs.iter()
+                .position(|d| d.name.eq_ignore_ascii_case(col))
+                .with_context(|| format!("table {name} has no column named {col}"))
+        };
+
+        let mut indexes = Vec::new();
+        for index in schema.indexes_on(&desc.name) {
+            let (unique, cols) = index.index_columns(desc)?;
+            indexes.push(IndexInfo {
+                root: index.root,
+                unique,
+                columns: cols.iter().map(|c| position(c)).collect::<Result<_>>()?,
+                collations: index.index_collations(desc)?,
+            });
+        }
+
+        Ok(Target {
+            name: desc.name.clone(),
+            root: desc.root,
+            ipk: codec::sql::rowid_alias(&defs),
+            autoincrement: codec::sql::rowid_alias(&defs).is_some_and(|i| defs[i].autoincrement),
+            columns,
+            indexes,
+        })
+    }
+
+    /// Rowids of the rows matching `cond`, all of them without one.
+    fn matching(&self, target: &Target, cond: Option<Condition>) -> Result<Vec<i64>> {
+        if let Some(cond) = &cond {
+            let known = cond.name == "id" || target.position(&cond.name).is_some();
+            ensure!(known, "no such column: {}", cond.name);
+        }
+        let schema = self.schema()?;
+        let desc = schema.table_named(&target.name)?.clone();
+        Table::new(self, desc)
+            .select(&ColumnNames::from("id"), cond)?
+            .map(|row| match row?.into_iter().next() {
+                Some(Value::Int(id)) => Ok(id),
+                v => bail!("Invalid rowid {}", v.unwrap_or(Value::Null)),
+            })
+            .collect()
+    }
+
+    /// Current values of row `id`, its INTEGER PRIMARY KEY column included.
+    fn row(&self, target: &Target, id: i64) -> Result<Vec<Value>> {
+        let mut values = btree::row(self, target.root, id)?.context("row vanished")?;
+        // Columns added after the row was written read as their default.
+        let missing = target.columns.iter().skip(values.len());
+        values.extend(missing.map(|c| c.default.clone()).collect::<Vec<_>>());
+        if let Some(ipk) = target.ipk {
+            values[ipk] = Value::Int(id);
+        }
+        Ok(values)
+    }
+
+    /// Writes row `id` and its index entries, `values` holding every column.
+    fn write_row(
+        &mut self,
+        target: &Target,
+        id: i64,
+        mut values: Vec<Value>,
+        replace: bool,
+    ) -> Result<()> {
+        for (i, (v, c)) in values.iter().zip(&target.columns).enumerate() {
+            if c.not_null && *v == Value::Null && Some(i) != target.ipk {
+                bail!("NOT NULL constraint failed: {}.{}", target.name, c.name);
+            }
+        }
+        for index in &target.indexes {
+            let key = index.key(&values, id);
+            let values = &key[..key.len() - 1];
+            if index.unique
+                && !values.contains(&Value::Null)
+                && btree::index_contains(self, index.root, values, &index.collations)?
+            {
+                let cols = index
+                    .columns
+                    .iter()
+                    .map(|i| format!("{}.{}", target.name, target.columns[*i].name))
+                    .join(", ");
+                bail!("UNIQUE constraint failed: {cols}");
+            }
+            btree::index_insert(self, index.root, &key, &index.collations)?;
+        }
+
+        if let Some(ipk) = target.ipk {
+            values[ipk] = Value::Null;
+        }
+        let payload = page::encode::record(&values, self.format().enc);
+        let cell = self.leaf_cell(id, &payload)?;
+        match replace {
+            true => btree::replace(self, target.root, id, cell),
+            false => btree::insert(self, target.root, id, cell),
+        }
+    }
+
+    fn remove_index_entries(&mut self, target: &Target, id: i64, values: &[Value]) -> Result<()> {
+        for index in &target.indexes {
+            let key = index.key(values, id);
+            btree::index_delete(self, index.root, &key, &index.collations)?;
+        }
+        Ok(())
+    }
+
+    /// Runs a single statement, undoing what it changed when it fails midway
+    /// so the transaction is left as it was before.
+    fn statement<T>(&mut self, run: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let saved = (
+            self.head,
+            self.pages,
+            self.dirty.clone(),
+            self.schema.clone(),
+        );
+        let res = run(self);
+        if res.is_err() {
+            (self.head, self.pages, self.dirty, self.schema) = saved;
+        }
+        res
+    }
+
+    /// Creates an empty rowid table `name` as declared by `sql`.
+    pub fn create_table(&mut self, name: &str, sql: &str) -> Result<()> {
+        self.statement(|tx| tx.create(schema::Type::Table, name, name, sql))
+            .map(drop)
+    }
+
+    /// Adds an empty B-tree for a table or an index to the schema,
+    /// returning its root.
+    fn create(&mut self, kind: schema::Type, name: &str, table: &str, sql: &str) -> Result<i64> {
+        let (label, plural, page) = match kind {
+            schema::Type::Index => ("index", "indexes", Kind::IndexLeaf),
+            _ => ("table", "tables", Kind::TableLeaf),
+        };
+        let schema = self.schema()?;
+        if schema
+            .desc
+            .iter()
+            .any(|d| d.name.eq_ignore_ascii_case(name))
+        {
+            bail!("{label} {name} already exists");
+        }
+        if self.head.largest_root() != 0 {
+            let feature = format!("creating {plural} in auto-vacuum databases");
+            bail!(error::Error::Unsupported(feature));
+        }
+        let root = btree::create(self, page)?;
+        let values = [
+            Value::Text(label.to_string()),
+            Value::Text(name.to_string()),
+            Value::Text(table.to_string()),
+            Value::Int(root),
+            Value::Text(sql.to_string()),
+        ];
+        let payload = page::encode::record(&values, self.format().enc);
+        let id = btree::max_rowid(self, 1)? + 1;
+        let cell = self.leaf_cell(id, &payload)?;
+        btree::insert(self, 1, id, cell)?;
+        self.head.bump_schema_cookie();
+        self.schema = Some(Arc::new(Schema::read(self)?));
+        Ok(root)
+    }
+
+    /// Inserts rows into a rowid table, returning how many were written.
+    /// Columns not listed get their default, values are converted to the
+    /// column affinity and an INTEGER PRIMARY KEY column becomes the rowid.
+    pub fn insert(
+        &mut self,
+        name: &str,
+        columns: Option<&[String]>,
+        rows: Vec<Vec<Value>>,
+    ) -> Result<usize> {
+        self.statement(|tx| tx.insert_rows(name, columns, rows))
+    }
+
+    fn insert_rows(
+        &mut self,
+        name: &str,
+        columns: Option<&[String]>,
+        rows: Vec<Vec<Value>>,
+    ) -> Result<usize> {
+        let target = self.target(name)?;
+        let slots = match columns {
+            None => (0..target.columns.len()).collect(),
+            Some(cols) => cols
+                .iter()
+                .map(|c| {
+                    target
+                        .position(c)
+                        .with_context(|| format!("table {name} has no column named {c}"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+        };
+
+        let mut last = btree::max_rowid(self, target.root)?;
+        if target.autoincrement {
+            last = last.max(self.sequence(&target.name)?.unwrap_or_default());
+        }
+        let count = rows.len();
+        for row in rows {
+            ensure!(
+                row.len() == slots.len(),
+                "{} values for {} columns",
+                row.len(),
+                slots.len()
+            );
+            let mut values = target
+                .columns
+                .iter()
+                .map(|c| c.default.clone())
+                .collect::<Vec<_>>();
+            for (i, v) in slots.iter().zip(row) {
+                values[*i] = v;
+            }
+            target.convert(&mut values);
+
+            let id = match target.ipk.map(|i| &values[i]) {
+                None | Some(Value::Null) => {
+                    last.checked_add(1).context("database or disk is full")?
+                }
+                Some(Value::Int(id)) => *id,
+                Some(_) => bail!("datatype mismatch"),
+            };
+            if let Some(ipk) = target.ipk {
+                values[ipk] = Value::Int(id);
+            }
+            last = last.max(id);
+            self.write_row(&target, id, values, false)?;
+        }
+        if target.autoincrement {
+            self.set_sequence(&target.name, last)?;
+        }
+        Ok(count)
+    }
+
+    /// Sets columns of the rows matching `cond` to new values, returning how
+    /// many rows changed. Giving the INTEGER PRIMARY KEY a new value moves
+    /// the row to that rowid.
+    pub fn update(
+        &mut self,
+        name: &str,
+        set: &[(String, Value)],
+        cond: Option<Condition>,
+    ) -> Result<usize> {
+        self.statement(|tx| tx.update_rows(name, set, cond))
+    }
+
+    fn update_rows(
+        &mut self,
+        name: &str,
+        set: &[(String, Value)],
+        cond: Option<Condition>,
+    ) -> Result<usize> {
+        let target = self.target(name)?;
+        let set = set
+            .iter()
+            .map(|(col, v)| {
+                let i = target
+                    .position(col)
+                    .with_context(|| format!("no such column: {col}"))?;
+                Ok((i, v.clone()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let ids = self.matching(&target, cond)?;
+        for id in &ids {
+            let old = self.row(&target, *id)?;
+            let mut values = old.clone();
+            for (i, v) in &set {
+                values[*i] = v.clone();
+            }
+            target.convert(&mut values);
+            let new_id = match target.ipk.map(|i| &values[i]) {
+                None => *id,
+                Some(Value::Int(new)) => *new,
+                Some(_) => bail!("datatype mismatch"),
+            };
+
+            self.remove_index_entries(&target, *id, &old)?;
+            if new_id == *id {
+                self.write_row(&target, *id, values, true)?;
+                continue;
+            }
+            if btree::row(self, target.root, new_id)?.is_some() {
+                let col = target.ipk.map(|i| &target.columns[i].name);
+                bail!(
+                    "UNIQUE constraint failed: {}.{}",
+                    target.name,
+                    col.unwrap_or(&target.name)
+                );
+            }
+            btree::delete(self, target.root, *id)?;
+            self.write_row(&target, new_id, values, false)?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Removes the rows matching `cond`, returning how many there were.
+    pub fn delete(&mut self, name: &str, cond: Option<Condition>) -> Result<usize> {
+        self.statement(|tx| tx.delete_rows(name, cond))
+    }
+
+    fn delete_rows(&mut self, name: &str, cond: Option<Condition>) -> Result<usize> {
+        let target = self.target(name)?;
+        let ids = self.matching(&target, cond)?;
+        for id in &ids {
+            if !target.indexes.is_empty() {
+                let old = self.row(&target, *id)?;
+                self.remove_index_entries(&target, *id, &old)?;
+            }
+            btree::delete(self, target.root, *id)?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Value of the AUTOINCREMENT counter kept in `sqlite_sequence`.
+    fn sequence(&self, name: &str) -> Result<Option<i64>> {
+        let Some((_, seq)) = self.sequence_row(name)? else {
+            return Ok(None);
+        };
+        Ok(Some(seq))
+    }
+
+    fn sequence_row(&self, name: &str) -> Result<Option<(i64, i64)>> {
+        let schema = self.schema()?;
+        let root = schema.table_named(schema::SEQUENCE_TABLE)?.root;
+        for page in btree::Leaves::new(self, root) {
+            let page = page?;
+            for cell in page.cells()? {
+                let payload = btree::payload(self, &cell.payload)?;
+                let rec = page::Record::parse(&payload, page.enc())?;
+                if rec.get(0)?.to_string() == name {
+                    return Ok(Some((cell.id, rec.get(1)?.try_into()?)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_sequence(&mut self, name: &str, seq: i64) -> Result<()> {
+        let schema = self.schema()?;
+        let root = schema.table_named(schema::SEQUENCE_TABLE)?.root;
+        let row = self.sequence_row(name)?;
+        let id = match row {
+            Some((id, _)) => id,
+            None => btree::max_rowid(self, root)? + 1,
+        };
+        let values = [Value::Text(name.to_string()), Value::Int(seq)];
+        let payload = page::encode::record(&values, self.format().enc);
+        let cell = self.leaf_cell(id, &payload)?;
+        match row {
+            Some(_) => btree::replace(self, root, id, cell),
+            None => btree::insert(self, root, id, cell),
+        }
+    }
+
+    /// Writes every changed page, then the header with the new file size.
+    pub fn commit(mut self) -> Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        self.head.commit(self.pages);
+        let mut first = self.raw_page(1)?.to_vec();
+        first[..Header::size()].copy_from_slice(self.head.as_bytes());
+        self.dirty.insert(1, first.into());
+        let pages = std::mem::take(&mut self.dirty)
+            .into_iter()
+            .collect::<Vec<_>>();
+        self.db.apply(self.head, &pages)
+    }
+
+    /// Discards every change, same as dropping the transaction.
+    pub fn rollback(self) {}
+}
+
+impl SQL for Transaction<'_> {
+    fn format(&self) -> Format {
+        self.db.format()
+    }
+
+    fn raw_page(&self, idx: i64) -> Result<Chunk> {
+        match self.dirty.get(&idx) {
+            Some(page) => Ok(page.clone()),
+            None => self.db.raw_page(idx),
+        }
+    }
+
+    fn schema(&self) -> Result<Arc<Schema>> {
+        match &self.schema {
+            Some(schema) => Ok(schema.clone()),
+            None => self.db.schema(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Creates index `name` on `table` as declared by `sql`, with an entry
+    /// for every row already there.
+    pub(in crate::db) fn create_index(
+        tx: &mut Transaction,
+        name: &str,
+        table: &str,
+        sql: &str,
+    ) -> Result<()> {
+        tx.statement(|tx| {
+            // Listed before the index exists, the rowids could come from it.
+            let ids = tx.matching(&tx.target(table)?, None)?;
+            let root = tx.create(schema::Type::Index, name, table, sql)?;
+            let target = tx.target(table)?;
+            let index = target.indexes.iter().find(|i| i.root == root);
+            let index = index.context("index not found")?;
+            for id in ids {
+                let values = tx.row(&target, id)?;
+                let key = index.key(&values, id);
+                btree::index_insert(tx, root, &key, &index.collations)?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,672 @@
+use crate::chunk::Chunk;
+use crate::db::io::{self, Backend, ByteLock, Positional, Storage};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const HEADER_SIZE: usize = 32;
+const FRAME_HEADER_SIZE: usize = 24;
+/// WAL magic, its low bit set when checksums read words big-endian.
+const MAGIC: u32 = 0x377f_0682;
+const VERSION: u32 = 3_007_000;
+/// Both copies of the sqlite3 wal-index header, at the start of `-shm`.
+const INDEX_HEADERS_SIZE: usize = 96;
+
+/// Write-ahead log of `db`, the file next to it named `<db>-wal`.
+pub fn path(db: &Path) -> PathBuf {
+    let mut name = OsString::from(db.as_os_str());
+    name.push("-wal");
+    PathBuf::from(name)
+}
+
+/// Running checksum over `data`, two 32-bit words at a time, continuing from
+/// `seed`.
+fn checksum(data: &[u8], big_endian: bool, seed: (u32, u32)) -> (u32, u32) {
+    let word = |b: &[u8]| {
+        let b = [b[0], b[1], b[2], b[3]];
+        match big_endian {
+            true => u32::from_be_bytes(b),
+            false => u32::from_le_bytes(b),
+        }
+    };
+    data.chunks_exact(8).fold(seed, |(s0, s1), w| {
+        let s0 = s0.wrapping_add(word(&w[..4])).wrapping_add(s1);
+        let s1 = s1.wrapping_add(word(&w[4..])).wrapping_add(s0);
+        (s0, s1)
+    })
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+/// How hard `PRAGMA wal_checkpoint` tries. Every mode copies what readers
+/// of older snapshots allow, FULL and up report when that is not the whole
+/// log, RESTART and TRUNCATE also when readers are still in it. TRUNCATE
+/// then empties the file.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Checkpoint {
+    #[default]
+    Passive,
+    Full,
+    Restart,
+    Truncate,
+}
+
+impl FromStr for Checkpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "PASSIVE" => Ok(Self::Passive),
+            "FULL" => Ok(Self::Full),
+            "RESTART" => Ok(Self::Restart),
+            "TRUNCATE" => Ok(Self::Truncate),
+            e => bail!("Unknown checkpoint mode: {e}"),
+        }
+    }
+}
+
+/// Byte of `-shm` sqlite3 writers lock while they append to the log or
+/// change the wal-index header, its WAL_WRITE_LOCK.
+const WRITE_LOCK: u64 = 120;
+/// Byte a checkpoint locks while it copies frames, WAL_CKPT_LOCK.
+const CHECKPOINT_LOCK: u64 = 121;
+/// Bytes readers read lock, one per slot, while their snapshot lasts.
+const READ_LOCKS: u64 = 123;
+const READ_SLOTS: u64 = 5;
+/// Frames the snapshot of each reader slot ends at, native endian words.
+const READ_MARKS: u64 = 100;
+/// Read mark of a slot no reader uses.
+const READ_MARK_UNUSED: u32 = u32::MAX;
+
+/// Right to write the log next to a database, held on its sqlite3
+/// wal-index the way sqlite3 writers hold it: no sqlite3 connection appends
+/// frames, or rebuilds the index after reading a header we are clearing,
+/// until it is dropped. Without a `-shm`, no sqlite3 connection has the
+/// database open, and one opening meanwhile builds its index from the
+/// committed frames of the log.
+pub struct WriteLock(Option<ByteLock>);
+
+impl WriteLock {
+    /// Takes the lock, failing when a sqlite3 connection holds it.
+    pub fn acquire(db: &Path) -> Result<Self> {
+        let mut name = OsString::from(db.as_os_str());
+        name.push("-shm");
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(PathBuf::from(name))
+        {
+            Ok(file) => match ByteLock::try_new(file, WRITE_LOCK)? {
+                Some(lock) => Ok(Self(Some(lock))),
+                None => bail!("database is locked"),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self(None)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Drops the header of the wal-index, if any, so that its next reader
+    /// rebuilds the index from the log instead of missing the frames we
+    /// wrote.
+    pub fn invalidate_index(&self) -> Result<()> {
+        match &self.0 {
+            Some(lock) => lock.write_at(0, &[0u8; INDEX_HEADERS_SIZE]),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the lock of checkpoints as well, failing when a sqlite3
+    /// connection is running one.
+    pub fn lock_checkpoint(&self) -> Result<()> {
+        match &self.0 {
+            Some(lock) if !lock.try_lock(CHECKPOINT_LOCK, 1)? => bail!("database is locked"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Frames, of the first `frames`, a checkpoint may copy without changing
+    /// what sqlite3 readers see: those their snapshots all hold. A reader of
+    /// slot 0 reads the database file alone, nothing may be copied under it.
+    pub fn safe_frames(&self, frames: u64) -> Result<u64> {
+        let Some(lock) = &self.0 else {
+            return Ok(frames);
+        };
+        let mut safe = frames;
+        for slot in 0..READ_SLOTS {
+            if !lock.is_locked(READ_LOCKS + slot, 1)? {
+                continue;
+            }
+            let mark = match slot {
+                0 => 0,
+                _ => {
+                    let word = lock.read_at(READ_MARKS + 4 * slot, 4)?;
+                    u32::from_ne_bytes(word[..].try_into()?)
+                }
+            };
+            if mark != READ_MARK_UNUSED {
+                safe = safe.min(mark as u64);
+            }
+        }
+        Ok(safe)
+    }
+
+    /// Keeps sqlite3 readers of the log out, so that it can start over or
+    /// be emptied, false when one is reading it. Slot 0 readers only read
+    /// the database file and may stay.
+    pub fn exclude_readers(&self) -> Result<bool> {
+        match &self.0 {
+            Some(lock) => lock.try_lock(READ_LOCKS + 1, READ_SLOTS - 1),
+            None => Ok(true),
+        }
+    }
+
+    /// Lets readers in again after [`WriteLock::exclude_readers`].
+    pub fn admit_readers(&self) -> Result<()> {
+        match &self.0 {
+            Some(lock) => lock.unlock(READ_LOCKS + 1, READ_SLOTS - 1),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Committed content of a write-ahead log: for each page changed since the
+/// last checkpoint, the frame holding its latest committed version. Frames
+/// after the last valid commit frame are ignored, as sqlite3 does, and new
+/// commits overwrite them.
+pub struct Wal {
+    io: Box<dyn Storage>,
+    page_size: usize,
+    big_endian: bool,
+    salt: [u8; 8],
+    sequence: u32,
+    frames: HashMap<i64, u64>,
+    /// Page held by each frame up to the last commit, in log order.
+    order: Vec<i64>,
+    /// Database size in pages after the last commit.
+    pages: Option<u32>,
+    /// End of the last commit frame, zero while the file has no header.
+    end: u64,
+    /// Running checksum up to `end`.
+    sum: (u32, u32),
+    /// Every frame is in the database file too, the next commit can start
+    /// the log over.
+    backfilled: bool,
+}
+
+impl Wal {
+    /// Reads the log at `path`, if there is one with a valid header.
+    pub fn open(path: &Path, backend: Backend, writable: bool) -> Result<Option<Self>> {
+        let file = match OpenOptions::new().read(true).write(writable).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if file.metadata()?.len() < HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+        let io = backend.open(file)?;
+        let head = io.read_at(0, HEADER_SIZE)?;
+        let magic = u32_at(&head, 0);
+        let page_size = u32_at(&head, 8) as usize;
+        let big_endian = magic & 1 == 1;
+        let valid = magic & !1 == MAGIC
+            && u32_at(&head, 4) == VERSION
+            && page_size.is_power_of_two()
+            && (512..=65536).contains(&page_size)
+            && checksum(&head[..24], big_endian, (0, 0)) == (u32_at(&head, 24), u32_at(&head, 28));
+        if !valid {
+            return Ok(None);
+        }
+
+        let mut wal = Self {
+            io,
+            page_size,
+            big_endian,
+            salt: head[16..24].try_into()?,
+            sequence: u32_at(&head, 12),
+            frames: HashMap::new(),
+            order: Vec::new(),
+            pages: None,
+            end: HEADER_SIZE as u64,
+            sum: (u32_at(&head, 24), u32_at(&head, 28)),
+            backfilled: false,
+        };
+        wal.scan()?;
+        Ok(Some(wal))
+    }
+
+    /// Reads the frames committed after `end`, saying whether there were.
+    fn scan(&mut self) -> Result<bool> {
+        let mut pending = Vec::new();
+        let mut sum = self.sum;
+        let frame_size = self.frame_size();
+        let mut offset = self.end;
+        let mut found = false;
+        while offset + frame_size <= self.io.len()? {
+            let frame = self.io.read_at(offset, frame_size as usize)?;
+            let (head, data) = frame.split_at(FRAME_HEADER_SIZE);
+            let idx = u32_at(head, 0);
+            if idx == 0 || head[8..16] != self.salt {
+                break;
+            }
+            sum = checksum(&head[..8], self.big_endian, sum);
+            sum = checksum(data, self.big_endian, sum);
+            if sum != (u32_at(head, 16), u32_at(head, 20)) {
+                break;
+            }
+            pending.push((idx as i64, offset + FRAME_HEADER_SIZE as u64));
+            offset += frame_size;
+            let commit = u32_at(head, 4);
+            if commit > 0 {
+                self.order.extend(pending.iter().map(|(idx, _)| *idx));
+                self.frames.extend(pending.drain(..));
+                self.pages = Some(commit);
+                (self.end, self.sum) = (offset, sum);
+                self.backfilled = false;
+                found = true;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Whether the log at `path` is still the one read: there, with the
+    /// salts of our header. sqlite3 salts a log anew when it starts it over.
+    fn is_current(&self, path: &Path) -> Result<bool> {
+        let file = match File::open(path) {
+            Ok(file) => Positional::new(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        if self.end == 0 || file.len()? < HEADER_SIZE as u64 {
+            return Ok(false);
+        }
+        Ok(file.read_at(16, 8)?[..] == self.salt)
+    }
+
+    /// Starts an empty log at `path`, its header written by the first commit.
+    pub fn create(path: &Path, page_size: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut salt = [0u8; 8];
+        salt[..4].copy_from_slice(&io::nonce().to_be_bytes());
+        Ok(Self {
+            io: Box::new(Positional::new(file)),
+            page_size,
+            big_endian: cfg!(target_endian = "big"),
+            salt,
+            sequence: 0,
+            frames: HashMap::new(),
+            order: Vec::new(),
+            pages: None,
+            end: 0,
+            sum: (0, 0),
+            backfilled: false,
+        })
+    }
+
+    fn frame_size(&self) -> u64 {
+        (FRAME_HEADER_SIZE + self.page_size) as u64
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Database size as of the last commit, if the log holds one.
+    pub fn page_count(&self) -> Option<u32> {
+        self.pages
+    }
+
+    /// Frames up to the last commit.
+    pub fn frame_count(&self) -> u64 {
+        self.end.saturating_sub(HEADER_SIZE as u64) / self.frame_size()
+    }
+
+    /// Latest committed version of page `idx`, when the log has one.
+    pub fn page(&self, idx: i64) -> Result<Option<Chunk>> {
+        let Some(offset) = self.frames.get(&idx) else {
+            return Ok(None);
+        };
+        Ok(Some(self.io.read_at(*offset, self.page_size)?))
+    }
+
+    /// Latest version of every page in the first `frames` frames.
+    pub fn pages(&self, frames: u64) -> Result<Vec<(i64, Chunk)>> {
+        let offset = |i: usize| HEADER_SIZE as u64 + i as u64 * self.frame_size();
+        let latest = self
+            .order
+            .iter()
+            .take(frames as usize)
+            .enumerate()
+            .map(|(i, idx)| (*idx, offset(i) + FRAME_HEADER_SIZE as u64))
+            .collect::<HashMap<_, _>>();
+        latest
+            .into_iter()
+            .map(|(idx, offset)| Ok((idx, self.io.read_at(offset, self.page_size)?)))
+            .collect()
+    }
+
+    /// Writes a fresh header with new salts, orphaning every frame.
+    fn restart(&mut self) -> Result<()> {
+        let salt = u32_at(&self.salt, 0).wrapping_add(1);
+        self.salt[..4].copy_from_slice(&salt.to_be_bytes());
+        self.salt[4..].copy_from_slice(&io::nonce().to_be_bytes());
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let mut head = Vec::with_capacity(HEADER_SIZE);
+        head.extend((MAGIC | self.big_endian as u32).to_be_bytes());
+        head.extend(VERSION.to_be_bytes());
+        head.extend((self.page_size as u32).to_be_bytes());
+        head.extend(self.sequence.to_be_bytes());
+        head.extend(self.salt);
+        let sum = checksum(&head, self.big_endian, (0, 0));
+        head.extend(sum.0.to_be_bytes());
+        head.extend(sum.1.to_be_bytes());
+        self.io.write_at(0, &head)?;
+        self.io.sync()?;
+
+        self.frames.clear();
+        self.order.clear();
+        self.pages = None;
+        (self.end, self.sum) = (HEADER_SIZE as u64, sum);
+        self.backfilled = false;
+        Ok(())
+    }
+
+    /// Appends `pages` as one transaction, the last frame marking the commit
+    /// with the new database size. It is durable once this returns. A
+    /// backfilled log starts over instead when `restart` says no reader
+    /// depends on its frames anymore.
+    pub fn commit(&mut self, pages: &[(i64, Chunk)], size: u32, restart: bool) -> Result<()> {
+        if self.end == 0 || self.backfilled && restart {
+            self.restart()?;
+        }
+        let mut sum = self.sum;
+        let mut offset = self.end;
+        let mut written = Vec::with_capacity(pages.len());
+        for (i, (idx, data)) in pages.iter().enumerate() {
+            let commit = match i + 1 == pages.len() {
+                true => size,
+                false => 0,
+            };
+            let mut frame = Vec::with_capacity(self.frame_size() as usize);
+            frame.extend((*idx as u32).to_be_bytes());
+            frame.extend(commit.to_be_bytes());
+            frame.extend(self.salt);
+            sum = checksum(&frame[..8], self.big_endian, sum);
+            sum = checksum(data, self.big_endian, sum);
+            frame.extend(sum.0.to_be_bytes());
+            frame.extend(sum.1.to_be_bytes());
+            frame.extend_from_slice(data);
+            self.io.write_at(offset, &frame)?;
+            written.push((*idx, offset + FRAME_HEADER_SIZE as u64));
+            offset += frame.len() as u64;
+        }
+        self.io.sync()?;
+        self.order.extend(written.iter().map(|(idx, _)| *idx));
+        self.frames.extend(written);
+        self.pages = Some(size);
+        (self.end, self.sum) = (offset, sum);
+        Ok(())
+    }
+
+    /// Records that the database file now holds every committed page.
+    pub fn backfilled(&mut self) {
+        self.backfilled = true;
+    }
+
+    pub fn is_backfilled(&self) -> bool {
+        self.backfilled
+    }
+
+    /// Empties the file, once backfilled.
+    pub fn truncate(&mut self) -> Result<()> {
+        self.io.truncate(0)?;
+        self.io.sync()?;
+        self.frames.clear();
+        self.order.clear();
+        self.pages = None;
+        (self.end, self.sum) = (0, (0, 0));
+        Ok(())
+    }
+}
+
+/// Brings `wal` up to date with the log at `path` as other connections
+/// left it: frames they committed after ours are read, and a log they
+/// started over or removed is read anew. Says whether anything changed.
+pub fn refresh(
+    wal: &mut Option<Wal>,
+    path: &Path,
+    backend: Backend,
+    writable: bool,
+) -> Result<bool> {
+    if let Some(log) = wal.as_mut() {
+        if log.is_current(path)? {
+            return log.scan();
+        }
+    }
+    let had_frames = wal.as_ref().is_some_and(|log| log.end > HEADER_SIZE as u64);
+    *wal = Wal::open(path, backend, writable)?;
+    Ok(had_frames || wal.as_ref().is_some_and(|log| log.page_count().is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::ColumnNames;
+    use crate::db::{Checkpoint, Options, SQLiteFile, SQL};
+    use crate::fixture::{TempDb, SAMPLE_DB};
+    use crate::value::Value;
+
+    /// Log of `pages`, committed at the frames listed in `commits`.
+    fn build_log(pages: &[(u32, Vec<u8>)], commits: &[usize], size: u32) -> Vec<u8> {
+        let page_size = pages[0].1.len() as u32;
+        let mut log = Vec::new();
+        log.extend((MAGIC | 1).to_be_bytes());
+        log.extend(VERSION.to_be_bytes());
+        log.extend(page_size.to_be_bytes());
+        log.extend(0u32.to_be_bytes());
+        log.extend([1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut sum = checksum(&log, true, (0, 0));
+        log.extend(sum.0.to_be_bytes());
+        log.extend(sum.1.to_be_bytes());
+        for (i, (idx, data)) in pages.iter().enumerate() {
+            let mut head = idx.to_be_bytes().to_vec();
+            let commit = if commits.contains(&i) { size } else { 0 };
+            head.extend(commit.to_be_bytes());
+            head.extend([1, 2, 3, 4, 5, 6, 7, 8]);
+            sum = checksum(&head[..8], true, sum);
+            sum = checksum(data, true, sum);
+            head.extend(sum.0.to_be_bytes());
+            head.extend(sum.1.to_be_bytes());
+            log.extend(head);
+            log.extend(data);
+        }
+        log
+    }
+
+    #[test]
+    fn test_wal_keeps_last_committed_frames() {
+        let file = TempDb::new("frames");
+        let wal = path(file.path());
+        let page = |fill: u8| vec![fill; 4096];
+
+        // Frames 0-1 commit, frame 2 overrides page 2 and commits, frame 3 is
+        // never committed.
+        let pages = [(2, page(1)), (3, page(2)), (2, page(3)), (3, page(4))];
+        let mut log = build_log(&pages, &[1, 2], 4);
+        std::fs::write(&wal, &log).unwrap();
+        let read = Wal::open(&wal, Backend::File, false).unwrap().unwrap();
+        assert_eq!(read.page_count(), Some(4));
+        assert_eq!(read.page(2).unwrap().unwrap().to_vec(), page(3));
+        assert_eq!(read.page(3).unwrap().unwrap().to_vec(), page(2));
+        assert!(read.page(1).unwrap().is_none());
+
+        // A bad checksum ends the log before the second commit.
+        let at = 32 + 2 * (24 + 4096) + 24 + 100;
+        log[at] ^= 0xff;
+        std::fs::write(&wal, &log).unwrap();
+        let read = Wal::open(&wal, Backend::File, false).unwrap().unwrap();
+        assert_eq!(read.page(2).unwrap().unwrap().to_vec(), page(1));
+
+        std::fs::write(&wal, [0u8; 10]).unwrap();
+        assert!(Wal::open(&wal, Backend::File, false).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_through_wal() {
+        let (old, new) = (TempDb::sample("wal-old"), TempDb::sample("wal-new"));
+        let db = SQLiteFile::open_with(new.name(), Options::default()).unwrap();
+        let rows = (0..200)
+            .map(|i| {
+                vec![
+                    Value::Null,
+                    Value::Text(format!("apple {i}").repeat(10)),
+                    Value::Null,
+                ]
+            })
+            .collect();
+        let mut tx = db.begin().unwrap();
+        tx.insert("apples", None, rows).unwrap();
+        tx.commit().unwrap();
+
+        // The pages the commit changed, as sqlite3 would log them.
+        let before = std::fs::read(old.path()).unwrap();
+        let after = std::fs::read(new.path()).unwrap();
+        let frames = after
+            .chunks(4096)
+            .zip(1..)
+            .filter(|(data, i)| before.chunks(4096).nth(*i as usize - 1) != Some(*data))
+            .map(|(data, i)| (i, data.to_vec()))
+            .collect::<Vec<_>>();
+        let count = (after.len() / 4096) as u32;
+        std::fs::write(
+            path(old.path()),
+            build_log(&frames, &[frames.len() - 1], count),
+        )
+        .unwrap();
+
+        let logged = SQLiteFile::open_with(old.name(), Options::default()).unwrap();
+        assert_eq!(logged.page_count().unwrap(), count);
+        let cols = ColumnNames::from("id,name");
+        let read = |db: &SQLiteFile| {
+            db.table("apples")
+                .unwrap()
+                .select(&cols, None)
+                .unwrap()
+                .map(|row| row.unwrap().into_iter().collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(read(&logged), read(&db));
+        assert_eq!(read(&logged).len(), 204);
+    }
+
+    #[test]
+    fn test_write_and_checkpoint() {
+        let file = TempDb::new("wal-write");
+        let (path, name) = (file.path(), file.name());
+        let mut data = std::fs::read(SAMPLE_DB).unwrap();
+        data[18..20].copy_from_slice(&[2, 2]);
+        std::fs::write(path, &data).unwrap();
+
+        let insert = |count: usize| {
+            let db = SQLiteFile::open_with(name, Options::default()).unwrap();
+            let rows = (0..count)
+                .map(|i| {
+                    vec![
+                        Value::Null,
+                        Value::Text(format!("wal {i}").repeat(20)),
+                        Value::Null,
+                    ]
+                })
+                .collect();
+            let mut tx = db.begin().unwrap();
+            tx.insert("apples", None, rows).unwrap();
+            tx.commit().unwrap();
+            db
+        };
+        let count = |db: &SQLiteFile| db.table("apples").unwrap().count(None).unwrap();
+
+        // Commits land in the log, the database file is left alone.
+        let db = insert(100);
+        assert_eq!(count(&db), 104);
+        drop(db);
+        let db = insert(50);
+        assert_eq!(count(&db), 154);
+        assert_eq!(std::fs::read(path).unwrap(), data);
+        let frames = std::fs::metadata(self::path(path)).unwrap().len();
+
+        let (busy, log, done) = db.checkpoint(Checkpoint::Passive).unwrap();
+        assert_eq!((busy, done), (false, log));
+        assert_eq!(log as u64, (frames - 32) / (24 + 4096));
+        let reopened = SQLiteFile::open_with(name, Options::default()).unwrap();
+        assert_eq!(count(&reopened), 154);
+        drop(reopened);
+
+        // The log starts over once backfilled, the frames left past the new
+        // commit no longer count.
+        let row = vec![vec![Value::Null, Value::Text("last".into()), Value::Null]];
+        let mut tx = db.begin().unwrap();
+        tx.insert("apples", None, row).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(std::fs::metadata(self::path(path)).unwrap().len(), frames);
+        let reopened = SQLiteFile::open_with(name, Options::default()).unwrap();
+        assert_eq!(count(&reopened), 155);
+        drop(reopened);
+        assert_eq!(db.checkpoint(Checkpoint::Truncate).unwrap(), (false, 0, 0));
+        assert_eq!(std::fs::metadata(self::path(path)).unwrap().len(), 0);
+        drop(db);
+        std::fs::remove_file(self::path(path)).unwrap();
+        let reopened = SQLiteFile::open_with(name, Options::default()).unwrap();
+        assert_eq!(count(&reopened), 155);
+    }
+
+    #[test]
+    fn test_writes_catch_up_with_the_log() {
+        let file = TempDb::new("wal-writers");
+        let mut data = std::fs::read(SAMPLE_DB).unwrap();
+        data[18..20].copy_from_slice(&[2, 2]);
+        std::fs::write(file.path(), &data).unwrap();
+        let ours = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let theirs = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let insert = |db: &SQLiteFile, name: &str| {
+            let mut tx = db.begin().unwrap();
+            let row = vec![Value::Null, Value::Text(name.into()), Value::Null];
+            tx.insert("apples", None, vec![row]).unwrap();
+            tx.commit().unwrap();
+        };
+        let names = |db: &SQLiteFile| {
+            let _file = db.lock_shared().unwrap();
+            db.table("apples")
+                .unwrap()
+                .select(&ColumnNames::from("name"), None)
+                .unwrap()
+                .map(|r| r.unwrap().into_iter().next().unwrap())
+                .skip(4)
+                .collect::<Vec<_>>()
+        };
+
+        insert(&ours, "ours 1");
+        insert(&theirs, "theirs 1");
+        insert(&ours, "ours 2");
+        let expected = ["ours 1", "theirs 1", "ours 2"].map(|n| Value::Text(n.into()));
+        assert_eq!(names(&theirs), expected);
+
+        // A checkpoint by one handle lets the other restart the log under it.
+        assert!(!theirs.checkpoint(Checkpoint::Passive).unwrap().0);
+        insert(&ours, "ours 3");
+        insert(&theirs, "theirs 2");
+        assert_eq!(names(&ours).len(), 5);
+        assert_eq!(names(&theirs), names(&ours));
+        assert!(theirs.integrity_check(10).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use crate::codec::sql::{self, quote_identifier};
+use crate::db::table::Table;
+use crate::db::SQL;
+use crate::error;
+use crate::schema::{self, Descriptor, SEQUENCE_TABLE, STAT1_TABLE};
+use crate::value::Value;
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use std::io::Write;
+
+/// Writes SQL text rebuilding the tables `LIKE` `pattern`, or every one,
+/// when run by sqlite3: the tables with their rows, then their indexes,
+/// views and triggers. Of the internal tables, the AUTOINCREMENT counters
+/// and `sqlite_stat1` are kept, other statistics are left to a new ANALYZE.
+pub fn dump(src: &impl SQL, pattern: Option<&str>, mut out: impl Write) -> Result<()> {
+    let schema = src.schema()?;
+    let matching = schema
+        .desc
+        .iter()
+        .filter(|d| !d.sql.is_empty())
+        .filter(|d| pattern.is_none_or(|p| schema::like(p, &d.tbl_name)))
+        .collect_vec();
+    let (tables, others): (Vec<_>, Vec<_>) = matching
+        .into_iter()
+        .partition(|d| d.kind == schema::Type::Table);
+    if let Some(t) = tables.iter().find(|t| sql::without_rowid(&t.sql)) {
+        let table = format!("WITHOUT ROWID table {}", t.name);
+        bail!(error::Error::Unsupported(table));
+    }
+    let (internal, tables): (Vec<_>, Vec<_>) = tables.into_iter().partition(|t| t.internal);
+
+    writeln!(out, "PRAGMA foreign_keys=OFF;")?;
+    writeln!(out, "BEGIN TRANSACTION;")?;
+    for table in tables {
+        writeln!(out, "{};", table.sql)?;
+        rows(src, table, &mut out)?;
+    }
+    for table in internal {
+        // sqlite3 refuses to create these by name.
+        match table.name.as_str() {
+            SEQUENCE_TABLE => writeln!(out, "DELETE FROM {SEQUENCE_TABLE};")?,
+            STAT1_TABLE => writeln!(out, "ANALYZE sqlite_schema;")?,
+            _ => continue,
+        }
+        rows(src, table, &mut out)?;
+    }
+    for desc in others {
+        writeln!(out, "{};", desc.sql)?;
+    }
+    writeln!(out, "COMMIT;")?;
+    out.flush()?;
+    Ok(())
+}
+
+/// An INSERT for each row of `desc`.
+fn rows(src: &impl SQL, desc: &Descriptor, out: &mut impl Write) -> Result<()> {
+    // Virtual tables keep their rows elsewhere.
+    if desc.root == 0 {
+        return Ok(());
+    }
+    let columns = desc
+        .column_names()
+        .into_iter()
+        .map(String::from)
+        .collect_vec();
+    let name = quote_identifier(&desc.name);
+    let table = Table::new(src, desc.clone());
+    for row in table.select(&columns.into(), None)? {
+        let row = row?;
+        let literals = row.as_slice().iter().map(Value::literal).join(",");
+        writeln!(out, "INSERT INTO {name} VALUES({literals});")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Options, SQLiteFile};
+    use crate::fixture::TempDb;
+
+    #[test]
+    fn test_dump_writes_literals_that_read_back() {
+        let file = TempDb::sample("dump");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+        let mut tx = db.begin().unwrap();
+        let rows = vec![
+            vec![
+                Value::Null,
+                Value::Text("it's\na\r\n".to_string()),
+                Value::Null,
+            ],
+            vec![Value::Null, Value::Blob(vec![0, 0xab]), Value::Null],
+        ];
+        tx.insert("apples", None, rows).unwrap();
+        tx.commit().unwrap();
+
+        let mut out = vec![];
+        dump(&db, None, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines = text.lines().collect_vec();
+        assert_eq!(
+            lines[..2],
+            ["PRAGMA foreign_keys=OFF;", "BEGIN TRANSACTION;"]
+        );
+        assert!(lines[2].starts_with("CREATE TABLE apples"));
+        assert!(text.contains("INSERT INTO apples VALUES(2,'Fuji','Red');\n"));
+        assert!(text.contains("VALUES(5,'it''s'||char(10)||'a'||char(13)||char(10),NULL);\n"));
+        assert!(text.contains("VALUES(6,X'00ab',NULL);\n"));
+        assert!(text.contains("DELETE FROM sqlite_sequence;\n"));
+        assert!(text.contains("INSERT INTO sqlite_sequence VALUES('apples',6);\n"));
+        assert!(text.ends_with("COMMIT;\n"));
+
+        let mut out = vec![];
+        dump(&db, Some("ORANGE_"), &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("INSERT INTO oranges VALUES(1,"));
+        assert!(!text.contains("apples") && !text.contains("sqlite_sequence"));
+        assert_eq!(Value::Float(1e15).literal(), "1000000000000000.0");
+        assert_eq!(Value::Float(1e300).literal(), "1e300");
+        assert_eq!(Value::Float(-0.1).literal(), "-0.1");
+        assert_eq!(quote_identifier("order"), "\"order\"");
+        assert_eq!(quote_identifier("a \"b\""), "\"a \"\"b\"\"\"");
+    }
+}
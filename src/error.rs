@@ -0,0 +1,44 @@
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Failures callers may want to tell apart. Code above the file format
+/// layer still reports through `anyhow`, which keeps these downcastable.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The file contradicts the format, on page `page` when known.
+    #[error("database disk image is malformed{}: {reason}", on(.page))]
+    Corrupt { page: Option<i64>, reason: String },
+    /// Valid SQLite, but beyond what this engine handles.
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+    #[error("no such {kind}: {name}")]
+    NotFound { kind: &'static str, name: String },
+    /// A command or statement that does not parse.
+    #[error("Parse error: {0}")]
+    Parse(String),
+}
+
+fn on(page: &Option<i64>) -> String {
+    page.map(|p| format!(" (page {p})")).unwrap_or_default()
+}
+
+impl Error {
+    pub fn corrupt(reason: impl Into<String>) -> Self {
+        Self::Corrupt {
+            page: None,
+            reason: reason.into(),
+        }
+    }
+
+    /// Ties a corruption found while decoding to page `idx`.
+    pub fn on_page(self, idx: i64) -> Self {
+        match self {
+            Self::Corrupt { page: None, reason } => Self::Corrupt {
+                page: Some(idx),
+                reason,
+            },
+            e => e,
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Scratch databases for tests.
+
+use std::path::{Path, PathBuf};
+
+pub const SAMPLE_DB: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/sample.db");
+
+/// A database file in the temp directory, removed with its journal and log
+/// on drop.
+pub struct TempDb(PathBuf);
+
+impl TempDb {
+    /// Names a file that does not exist yet.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{name}-{}.db", std::process::id()));
+        let db = Self(path);
+        db.remove();
+        db
+    }
+
+    /// A fresh copy of the sample database.
+    pub fn sample(name: &str) -> Self {
+        let db = Self::new(name);
+        std::fs::copy(SAMPLE_DB, db.path()).unwrap();
+        db
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn name(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    fn remove(&self) {
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
@@ -0,0 +1,235 @@
+use crate::codec::sql::quote_identifier;
+use crate::db::{Transaction, SQL};
+use crate::value::Value;
+use anyhow::{bail, ensure, Result};
+use itertools::Itertools;
+use std::io::BufRead;
+
+/// Rows handed to the transaction at once.
+const BATCH_SIZE: usize = 1000;
+
+/// Reads RFC 4180 records: fields between separators, in double quotes
+/// when they hold the separator, quotes or line breaks.
+pub struct Reader<R> {
+    input: R,
+    separator: String,
+    /// Lines read so far, for messages about the record just read.
+    pub line: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R, separator: &str) -> Self {
+        Self {
+            input,
+            separator: separator.to_string(),
+            line: 0,
+        }
+    }
+
+    /// Next record, `None` at the end of the input. Blank lines are skipped.
+    pub fn record(&mut self) -> Result<Option<Vec<String>>> {
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if self.input.read_line(&mut buf)? == 0 {
+                ensure!(!quoted, "line {}: unterminated quoted field", self.line);
+                if fields.is_empty() && field.is_empty() {
+                    return Ok(None);
+                }
+                fields.push(field);
+                return Ok(Some(fields));
+            }
+            self.line += 1;
+            let mut rest = buf.as_str();
+            while let Some(c) = rest.chars().next() {
+                match c {
+                    '"' if quoted && rest.starts_with("\"\"") => {
+                        field.push('"');
+                        rest = &rest[2..];
+                        continue;
+                    }
+                    '"' if quoted => quoted = false,
+                    _ if quoted => field.push(c),
+                    '"' if field.is_empty() => quoted = true,
+                    _ if rest.starts_with(&self.separator) => {
+                        fields.push(std::mem::take(&mut field));
+                        rest = &rest[self.separator.len()..];
+                        continue;
+                    }
+                    _ if rest == "\n" || rest == "\r\n" => break,
+                    _ => field.push(c),
+                }
+                rest = &rest[c.len_utf8()..];
+            }
+            if quoted {
+                continue;
+            }
+            if fields.is_empty() && field.is_empty() {
+                continue;
+            }
+            fields.push(field);
+            return Ok(Some(fields));
+        }
+    }
+}
+
+/// Appends the records of `input` to table `name`, creating it with a TEXT
+/// column per field of the first record when it does not exist. Otherwise
+/// every record is a row, its values converted to the column affinity.
+/// Rows go in `BATCH_SIZE` at a time. Returns the number of rows inserted.
+pub fn import(
+    tx: &mut Transaction,
+    input: impl BufRead,
+    file: &str,
+    name: &str,
+    separator: &str,
+    skip: usize,
+) -> Result<usize> {
+    if separator.is_empty() {
+        bail!("separator must not be empty");
+    }
+    let mut reader = Reader::new(input, separator);
+    for _ in 0..skip {
+        reader.record()?;
+    }
+    let schema = tx.schema()?;
+    let columns = match schema.table_named(name) {
+        Ok(desc) => desc.column_names().len(),
+        Err(_) => {
+            let Some(header) = reader.record()? else {
+                bail!("{file}: empty file");
+            };
+            let defs = header
+                .iter()
+                .map(|c| format!("{} TEXT", quote_identifier(c)))
+                .join(", ");
+            tx.create_table(
+                name,
+                &format!("CREATE TABLE {}(\n{defs})", quote_identifier(name)),
+            )?;
+            header.len()
+        }
+    };
+
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while let Some(record) = reader.record()? {
+        let found = record.len();
+        let mut row = record.into_iter().map(Value::Text).collect_vec();
+        if found < columns {
+            eprintln!(
+                "{file}:{}: expected {columns} columns but found {found} - filling the rest with NULL",
+                reader.line
+            );
+        } else if found > columns {
+            eprintln!(
+                "{file}:{}: expected {columns} columns but found {found} - extras ignored",
+                reader.line
+            );
+        }
+        row.resize(columns, Value::Null);
+        batch.push(row);
+        if batch.len() == BATCH_SIZE {
+            count += tx.insert(name, None, std::mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        count += tx.insert(name, None, batch)?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::ColumnNames;
+    use crate::db::{Options, SQLiteFile};
+    use crate::fixture::TempDb;
+
+    #[test]
+    fn test_import_creates_then_appends() {
+        let file = TempDb::sample("import");
+        let db = SQLiteFile::open_with(file.name(), Options::default()).unwrap();
+
+        let csv = "name;\"qty x\"\r\n\"multi\nline; \"\"quoted\"\"\";1\n\nshort\n";
+        let mut tx = db.begin().unwrap();
+        assert_eq!(
+            import(&mut tx, csv.as_bytes(), "a.csv", "stock", ";", 0).unwrap(),
+            2
+        );
+        tx.commit().unwrap();
+        let table = db.table("stock").unwrap();
+        assert_eq!(
+            table.desc.sql,
+            "CREATE TABLE stock(\nname TEXT, \"qty x\" TEXT)"
+        );
+        let rows = table
+            .select(
+                &ColumnNames::from(vec!["name".into(), "qty x".into()]),
+                None,
+            )
+            .unwrap()
+            .map(|r| r.unwrap().into_iter().collect_vec())
+            .collect_vec();
+        assert_eq!(
+            rows,
+            [
+                vec![
+                    Value::Text("multi\nline; \"quoted\"".into()),
+                    Value::Text("1".into())
+                ],
+                vec![Value::Text("short".into()), Value::Null],
+            ]
+        );
+
+        // An existing table takes every record, converted to its columns' types.
+        let mut tx = db.begin().unwrap();
+        let csv = "7,Cox,\"Red\"\n8,Gala,Pink,extra\n";
+        assert_eq!(
+            import(&mut tx, csv.as_bytes(), "b.csv", "Apples", ",", 0).unwrap(),
+            2
+        );
+        tx.commit().unwrap();
+        let ids = db
+            .table("apples")
+            .unwrap()
+            .select(&ColumnNames::from("id,color"), None)
+            .unwrap()
+            .map(|r| r.unwrap().into_iter().collect_vec())
+            .collect_vec();
+        assert_eq!(ids[4], [Value::Int(7), Value::Text("Red".into())]);
+        assert_eq!(ids[5], [Value::Int(8), Value::Text("Pink".into())]);
+
+        // Only SQLite's number syntax converts; "inf" and "nan" stay TEXT.
+        let mut tx = db.begin().unwrap();
+        tx.create_table("m", "CREATE TABLE m(x REAL)").unwrap();
+        let csv = "inf\nNaN\n-Infinity\n2.5e1\n.5\n7.\n1e\n";
+        assert_eq!(
+            import(&mut tx, csv.as_bytes(), "c.csv", "m", ",", 0).unwrap(),
+            7
+        );
+        tx.commit().unwrap();
+        let xs = db
+            .table("m")
+            .unwrap()
+            .select(&ColumnNames::from("x"), None)
+            .unwrap()
+            .map(|r| r.unwrap().into_iter().next().unwrap())
+            .collect_vec();
+        assert_eq!(
+            xs,
+            [
+                Value::Text("inf".into()),
+                Value::Text("NaN".into()),
+                Value::Text("-Infinity".into()),
+                Value::Float(25.0),
+                Value::Float(0.5),
+                Value::Float(7.0),
+                Value::Text("1e".into()),
+            ]
+        );
+    }
+}
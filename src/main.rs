@@ -1,61 +1,388 @@
 mod args;
+mod chunk;
 mod codec;
 mod db;
+mod dump;
+mod error;
+mod export;
+#[cfg(test)]
+mod fixture;
+mod import;
 mod offset;
+mod output;
 mod page;
 mod schema;
+mod shell;
 mod value;
 
-use anyhow::Result;
-use args::{Command, Select};
-use db::{SQLiteFile, SQL};
+use anyhow::{bail, ensure, Context, Result};
+use args::{ColumnNames, Command, Export, Import, Select};
+use db::{stat, SQLiteFile, Transaction, SQL};
 use itertools::Itertools;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, IsTerminal};
+use value::Value;
 
-/// Notes :
-/// * Page is loaded into memory in a single read in accordance with SQLite mem approach.
-/// * TODO: Improve unit test coverage
-/// * TODO: Do not duplicate content for big chunks (str, blob) on cells.
+// Notes :
+// * Page is loaded into memory in a single read in accordance with SQLite mem approach,
+//   or sliced out of a read-only mapping of the whole file with the mmap backend.
+// * TODO: Improve unit test coverage
+// * Writes are buffered in a transaction and reach the file on commit, through a
+//   rollback journal so a crash never leaves a half written database behind.
+// * Records are decoded lazily, only the columns a query touches are copied out of the page.
 
-fn main() -> Result<()> {
-    // Commands
-    let args = args::build()?;
+/// Problems reported by `.check` unless PRAGMA integrity_check asks for
+/// another count.
+const CHECK_LIMIT: usize = 100;
 
-    // Parse command and act accordingly
-    let db = SQLiteFile::open_at(&args.filename)?;
-    for cmd in args.cmds {
+fn run_select(
+    src: &impl SQL,
+    select: Select,
+    threads: usize,
+    output: &output::Settings,
+) -> Result<()> {
+    let out = BufWriter::new(io::stdout().lock());
+    match select {
+        Select::Count { table, cond } => {
+            let table = src.table(&table)?;
+            let count = match threads {
+                1 => table.count(cond)?,
+                n => table.par_count(cond, n)?,
+            };
+            let mut printer = output.printer(vec!["count(*)".to_string()], out);
+            printer.row(&[Value::Int(count as i64)])?;
+            printer.finish()?;
+        }
+        Select::Column {
+            table,
+            columns,
+            cond,
+        } => {
+            let table = src.table(&table)?;
+            let mut printer = output.printer(columns.as_slice().to_vec(), out);
+            match threads {
+                1 => {
+                    for row in table.select(&columns, cond)? {
+                        printer.row(row?.as_slice())?;
+                    }
+                }
+                n => {
+                    let mut res = Ok(());
+                    table.par_select(&columns, cond, n, |row| {
+                        if res.is_ok() {
+                            res = printer.row(row.as_slice());
+                        }
+                    })?;
+                    res?;
+                }
+            }
+            printer.finish()?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the plan a query would run with.
+fn run_explain(src: &impl SQL, select: Select) -> Result<()> {
+    let (table, columns, cond) = match select {
+        Select::Count { table, cond } => (table, ColumnNames::from(vec![]), cond),
+        Select::Column {
+            table,
+            columns,
+            cond,
+        } => (table, columns, cond),
+    };
+    print!("{}", src.table(&table)?.plan(&columns, cond.as_ref())?);
+    Ok(())
+}
+
+/// Exports a table to the file named, or to standard output.
+fn run_export(src: &impl SQL, export: Export) -> Result<()> {
+    let Export {
+        table,
+        path,
+        format,
+        blobs,
+    } = export;
+    match path {
+        Some(path) => {
+            let file = File::create(&path).with_context(|| format!("cannot open {path}"))?;
+            export::table(src, &table, format, blobs, BufWriter::new(file))?
+        }
+        None => export::table(src, &table, format, blobs, io::stdout().lock())?,
+    };
+    Ok(())
+}
+
+/// Runs a write in the open transaction, or in one of its own committed
+/// right away.
+fn write<'a>(
+    db: &'a SQLiteFile,
+    tx: &mut Option<Transaction<'a>>,
+    op: impl FnOnce(&mut Transaction<'a>) -> Result<usize>,
+) -> Result<()> {
+    match tx {
+        Some(tx) => op(tx).map(drop),
+        None => {
+            let mut tx = db.begin()?;
+            op(&mut tx)?;
+            tx.commit()
+        }
+    }
+}
+
+/// Prints the problems found in the database, or `ok` when there are none.
+fn integrity_check(db: &SQLiteFile, limit: usize) -> Result<()> {
+    let problems = db.integrity_check(limit.max(1))?;
+    match problems.is_empty() {
+        true => println!("ok"),
+        false => problems.iter().for_each(|p| println!("{p}")),
+    }
+    Ok(())
+}
+
+/// State carried from one command to the next.
+struct Session<'a> {
+    db: &'a SQLiteFile,
+    threads: usize,
+    /// How query results are printed.
+    output: output::Settings,
+    /// Open from BEGIN to COMMIT, statements outside one commit on their
+    /// own. Left open at the end, it is rolled back.
+    tx: Option<Transaction<'a>>,
+}
+
+impl<'a> Session<'a> {
+    fn new(db: &'a SQLiteFile, threads: usize, output: output::Settings) -> Self {
+        Self {
+            db,
+            threads,
+            output,
+            tx: None,
+        }
+    }
+
+    fn run(&mut self, cmd: Command) -> Result<()> {
+        let db = self.db;
+        // Each command reads the database as of a single commit.
+        let _file = db.lock_shared()?;
         match cmd {
             Command::Info => {
-                println!("database page size: {}", db.head.page_size());
-                println!("number of tables: {}", db.schema()?.head.cell_count);
+                println!("database page size: {}", db.header().page_size());
+                println!("number of tables: {}", db.schema()?.desc.len());
             }
-            Command::Tables => {
+            Command::Tables(pattern) => {
                 let schema = db.schema()?;
-                let tables = schema.tables();
-                let msg = tables
-                    .filter(|c| !c.internal)
-                    .map(|t| t.name.as_str())
+                // Internal objects only show up for a pattern naming them.
+                let internal = pattern
+                    .as_ref()
+                    .is_some_and(|p| p.to_ascii_lowercase().starts_with("sqlite"));
+                let msg = schema
+                    .desc
+                    .iter()
+                    .filter(|d| matches!(d.kind, schema::Type::Table | schema::Type::View))
+                    .filter(|d| internal || !d.internal)
+                    .filter(|d| pattern.as_ref().is_none_or(|p| schema::like(p, &d.name)))
+                    .map(|d| d.name.as_str())
                     .join(" ");
                 println!("{msg}");
             }
-            Command::Select(Select::Count { table }) => {
-                let page = db.table(&table)?.root;
-                println!("{}", page.head.cell_count);
+            Command::Stats => {
+                let stats = db.cache_stats();
+                println!("page cache hits: {}", stats.hits);
+                println!("page cache misses: {}", stats.misses);
+                println!("page cache evictions: {}", stats.evictions);
+                println!(
+                    "page cache size: {} pages, {} bytes",
+                    stats.pages, stats.bytes
+                );
             }
-            Command::Select(Select::Column {
-                table,
-                columns,
-                cond,
-            }) => {
-                let table = db.table(&table)?;
-                let res = table.select(&columns, cond);
-
-                for row in res {
-                    let line = row.into_iter().map(|v| v.to_string()).join("|");
-                    println!("{line}");
+            Command::Freelist => {
+                let list = db.freelist()?;
+                let pages = db.page_count()?;
+                let join = |pages: &[i64]| pages.iter().join(" ");
+                println!("freelist count: {}", list.len());
+                println!("trunk pages: {}", join(&list.trunks));
+                println!("free pages: {}", join(&list.pages()));
+                println!(
+                    "free space: {} of {pages} pages, {} bytes ({:.1}%)",
+                    list.len(),
+                    list.len() * db.header().page_size() as usize,
+                    100.0 * list.len() as f64 / pages.max(1) as f64
+                );
+                let count = db.freelist_count();
+                if count as usize != list.len() {
+                    println!(
+                        "mismatch: header freelist count is {count}, the chain holds {} pages",
+                        list.len()
+                    );
+                }
+            }
+            Command::Analyze => {
+                let usage = db::analyze::analyze(db)?;
+                let pages = db.page_count()?;
+                let free = db.freelist()?.len();
+                println!("page size: {}", db.header().page_size());
+                println!("pages: {pages}, freelist: {free}");
+                for usage in usage {
+                    let share = 100.0 * usage.total_pages() as f64 / pages.max(1) as f64;
+                    println!("\n{usage}\nshare of file: {share:.1}%");
+                }
+            }
+            Command::Schema(pattern) => {
+                let schema = db.schema()?;
+                let matching = schema
+                    .desc
+                    .iter()
+                    .filter(|d| !d.sql.is_empty())
+                    .filter(|d| {
+                        pattern
+                            .as_ref()
+                            .is_none_or(|p| schema::like(p, &d.tbl_name))
+                    });
+                for desc in matching {
+                    println!("{};", desc.sql);
+                }
+            }
+            Command::Indexes(pattern) => {
+                let schema = db.schema()?;
+                let names = schema
+                    .desc
+                    .iter()
+                    .filter(|d| d.kind == schema::Type::Index)
+                    .filter(|d| {
+                        pattern
+                            .as_ref()
+                            .is_none_or(|p| schema::like(p, &d.tbl_name))
+                    })
+                    .map(|d| d.name.as_str())
+                    .join(" ");
+                println!("{names}");
+            }
+            Command::Headers(on) => self.output.headers = on,
+            Command::Mode(mode) => self.output.set_mode(mode),
+            Command::Separator(col, row) => {
+                self.output.separator = col;
+                if let Some(row) = row {
+                    self.output.row_separator = row;
+                }
+            }
+            Command::NullValue(null) => self.output.null = null,
+            Command::Check => integrity_check(db, CHECK_LIMIT)?,
+            Command::Select(select) => match &self.tx {
+                Some(tx) => run_select(tx, select, self.threads, &self.output)?,
+                None => run_select(db, select, self.threads, &self.output)?,
+            },
+            Command::Explain(select) => match &self.tx {
+                Some(tx) => run_explain(tx, select)?,
+                None => run_explain(db, select)?,
+            },
+            Command::Dump(pattern) => {
+                let out = BufWriter::new(io::stdout().lock());
+                match &self.tx {
+                    Some(tx) => dump::dump(tx, pattern.as_deref(), out)?,
+                    None => dump::dump(db, pattern.as_deref(), out)?,
+                }
+            }
+            Command::Export(export) => match &self.tx {
+                Some(tx) => run_export(tx, export)?,
+                None => run_export(db, export)?,
+            },
+            Command::Import(import) => {
+                let Import {
+                    path,
+                    table,
+                    separator,
+                    skip,
+                } = import;
+                let separator = separator.unwrap_or_else(|| match self.output.mode {
+                    output::Mode::Csv | output::Mode::Tsv => self.output.separator.clone(),
+                    _ => ",".to_string(),
+                });
+                let file = File::open(&path).with_context(|| format!("cannot open {path}"))?;
+                let input = BufReader::new(file);
+                write(db, &mut self.tx, |tx| {
+                    import::import(tx, input, &path, &table, &separator, skip)
+                })?
+            }
+            Command::Insert(insert) => write(db, &mut self.tx, |tx| {
+                tx.insert(&insert.table, insert.columns.as_deref(), insert.rows)
+            })?,
+            Command::Update(update) => write(db, &mut self.tx, |tx| {
+                tx.update(&update.table, &update.set, update.cond)
+            })?,
+            Command::Delete(delete) => {
+                write(db, &mut self.tx, |tx| tx.delete(&delete.table, delete.cond))?
+            }
+            Command::Begin => {
+                ensure!(
+                    self.tx.is_none(),
+                    "cannot start a transaction within a transaction"
+                );
+                self.tx = Some(db.begin()?);
+            }
+            Command::Commit => self
+                .tx
+                .take()
+                .context("cannot commit - no transaction is active")?
+                .commit()?,
+            Command::Rollback => self
+                .tx
+                .take()
+                .context("cannot rollback - no transaction is active")?
+                .rollback(),
+            // Read-only, the statistics are shown instead of stored.
+            Command::Statistics(name) if db.is_read_only() => {
+                let stats = stat::gather(db, name.as_deref())?;
+                let columns = ["tbl", "idx", "stat"].map(String::from).to_vec();
+                let mut printer = self.output.printer(columns, io::stdout().lock());
+                for row in stats {
+                    printer.row(&row.values())?;
                 }
+                printer.finish()?;
             }
+            Command::Statistics(name) => write(db, &mut self.tx, |tx| {
+                let stats = stat::gather(tx, name.as_deref())?;
+                stat::store(tx, name.as_deref(), stats)
+            })?,
+            Command::Pragma(pragma) => match pragma.name.as_str() {
+                "wal_checkpoint" => {
+                    ensure!(self.tx.is_none(), "database table is locked");
+                    let mode = pragma.arg.as_deref().map(str::parse).transpose()?;
+                    let (busy, log, done) = db.checkpoint(mode.unwrap_or_default())?;
+                    println!("{}|{log}|{done}", busy as u8);
+                }
+                "integrity_check" => {
+                    let limit = pragma.arg.as_deref().map(str::parse).transpose()?;
+                    integrity_check(db, limit.unwrap_or(CHECK_LIMIT))?;
+                }
+                e => bail!(error::Error::Unsupported(format!("pragma {e}"))),
+            },
         }
+        Ok(())
     }
+}
+
+fn main() -> Result<()> {
+    // Commands
+    let args = args::build()?;
 
+    // Parse command and act accordingly
+    let db = SQLiteFile::open_with(&args.filename, args.opts)?;
+    let mut output = output::Settings::default();
+    if let Some(mode) = args.mode {
+        output.set_mode(mode);
+    }
+    let mut session = Session::new(&db, args.threads, output);
+    if args.cmds.is_empty() {
+        let stdin = io::stdin();
+        let interactive = stdin.is_terminal();
+        return shell::run(stdin.lock(), interactive, |line| {
+            session.run(Command::try_from(line.to_string())?)
+        });
+    }
+    for cmd in args.cmds {
+        session.run(cmd)?;
+    }
     Ok(())
 }
@@ -21,9 +21,9 @@ impl From<i64> for Offset {
     }
 }
 
-impl Into<SeekFrom> for Offset {
-    fn into(self) -> SeekFrom {
-        SeekFrom::Start(self.0 as u64)
+impl From<Offset> for SeekFrom {
+    fn from(o: Offset) -> Self {
+        SeekFrom::Start(o.0 as u64)
     }
 }
 
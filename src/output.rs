@@ -0,0 +1,552 @@
+use crate::error::Error;
+use crate::value::Value;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// How query results are laid out, as chosen by `.mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    List,
+    Csv,
+    Tsv,
+    Json,
+    /// JSON Lines: an object per row, each on a line of its own.
+    Jsonl,
+    Line,
+    Markdown,
+    Table,
+    Box,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "list" => Ok(Self::List),
+            "csv" => Ok(Self::Csv),
+            "tsv" | "tabs" => Ok(Self::Tsv),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            "line" => Ok(Self::Line),
+            "markdown" => Ok(Self::Markdown),
+            "table" => Ok(Self::Table),
+            "box" => Ok(Self::Box),
+            e => Err(Error::Parse(format!(
+                "mode should be one of: box csv json jsonl line list markdown table tabs tsv, not {e}"
+            ))),
+        }
+    }
+}
+
+/// Output settings carried across commands.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub mode: Mode,
+    /// Whether list, csv and tsv output starts with the column names. The
+    /// other modes always show them.
+    pub headers: bool,
+    /// Between the columns of list, csv and tsv output.
+    pub separator: String,
+    /// After each row of list, csv and tsv output.
+    pub row_separator: String,
+    /// Printed in place of NULL, except in json.
+    pub null: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            mode: Mode::List,
+            headers: false,
+            separator: "|".to_string(),
+            row_separator: "\n".to_string(),
+            null: String::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Switches to `mode` along with the separators it implies.
+    pub fn set_mode(&mut self, mode: Mode) {
+        let (col, row) = match mode {
+            Mode::Csv => (",", "\r\n"),
+            Mode::Tsv => ("\t", "\n"),
+            _ => ("|", "\n"),
+        };
+        self.mode = mode;
+        self.separator = col.to_string();
+        self.row_separator = row.to_string();
+    }
+
+    /// Prints rows of a result with `columns` to `out`, in the current mode.
+    pub fn printer<W: Write>(&self, columns: Vec<String>, out: W) -> Printer<W> {
+        let format: Box<dyn Format> = match self.mode {
+            Mode::List | Mode::Csv | Mode::Tsv => Box::new(Delimited {
+                quote: self.mode == Mode::Csv,
+                headers: self.headers,
+            }),
+            Mode::Json | Mode::Jsonl => Box::new(Json {
+                lines: self.mode == Mode::Jsonl,
+                rows: 0,
+            }),
+            Mode::Line => Box::new(Line { rows: 0 }),
+            Mode::Markdown | Mode::Table | Mode::Box => Box::new(Grid {
+                style: self.mode,
+                rows: vec![],
+            }),
+        };
+        Printer {
+            settings: self.clone(),
+            columns,
+            format,
+            out,
+            started: false,
+        }
+    }
+}
+
+/// One way of laying out rows. Modes needing every row before printing
+/// any keep them until `end`.
+trait Format {
+    fn begin(&mut self, p: &Layout, out: &mut dyn Write) -> io::Result<()>;
+    fn row(&mut self, p: &Layout, out: &mut dyn Write, row: &[Value]) -> io::Result<()>;
+    fn end(&mut self, p: &Layout, out: &mut dyn Write) -> io::Result<()>;
+}
+
+/// What a [`Format`] gets to know about the result.
+struct Layout<'a> {
+    settings: &'a Settings,
+    columns: &'a [String],
+}
+
+impl Layout<'_> {
+    fn text(&self, value: &Value) -> String {
+        match value {
+            Value::Null => self.settings.null.clone(),
+            v => v.to_string(),
+        }
+    }
+}
+
+/// Writes the rows of one result through a [`Format`]. Nothing, not even
+/// the column names, is printed for a result without rows.
+pub struct Printer<W: Write> {
+    settings: Settings,
+    columns: Vec<String>,
+    format: Box<dyn Format>,
+    out: W,
+    started: bool,
+}
+
+impl<W: Write> Printer<W> {
+    /// Starts the output, column names included, whether or not rows follow.
+    pub fn begin(&mut self) -> io::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+        let layout = Layout {
+            settings: &self.settings,
+            columns: &self.columns,
+        };
+        self.format.begin(&layout, &mut self.out)
+    }
+
+    pub fn row(&mut self, row: &[Value]) -> io::Result<()> {
+        self.begin()?;
+        let layout = Layout {
+            settings: &self.settings,
+            columns: &self.columns,
+        };
+        self.format.row(&layout, &mut self.out, row)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let layout = Layout {
+            settings: &self.settings,
+            columns: &self.columns,
+        };
+        if self.started {
+            self.format.end(&layout, &mut self.out)?;
+        }
+        self.out.flush()
+    }
+}
+
+/// list, csv and tsv: one line per row, columns between separators.
+struct Delimited {
+    /// Quote fields as RFC 4180 asks, for csv.
+    quote: bool,
+    headers: bool,
+}
+
+impl Delimited {
+    fn field(&self, p: &Layout, text: String, out: &mut dyn Write) -> io::Result<()> {
+        let sep = &p.settings.separator;
+        let special = |c: char| c == '"' || c == '\r' || c == '\n';
+        let needs_quotes = text.is_empty()
+            || text.contains(special)
+            || (!sep.is_empty() && text.contains(sep.as_str()))
+            || text.starts_with(' ')
+            || text.ends_with(' ');
+        match self.quote && needs_quotes {
+            true => write!(out, "\"{}\"", text.replace('"', "\"\"")),
+            false => write!(out, "{text}"),
+        }
+    }
+
+    fn line(&self, p: &Layout, out: &mut dyn Write, fields: Vec<Option<String>>) -> io::Result<()> {
+        for (i, field) in fields.into_iter().enumerate() {
+            if i > 0 {
+                write!(out, "{}", p.settings.separator)?;
+            }
+            match field {
+                Some(text) => self.field(p, text, out)?,
+                // NULL stays unquoted so csv tells it apart from ''.
+                None => write!(out, "{}", p.settings.null)?,
+            }
+        }
+        write!(out, "{}", p.settings.row_separator)
+    }
+}
+
+impl Format for Delimited {
+    fn begin(&mut self, p: &Layout, out: &mut dyn Write) -> io::Result<()> {
+        if !self.headers {
+            return Ok(());
+        }
+        let names = p.columns.iter().cloned().map(Some).collect();
+        self.line(p, out, names)
+    }
+
+    fn row(&mut self, p: &Layout, out: &mut dyn Write, row: &[Value]) -> io::Result<()> {
+        let fields = row
+            .iter()
+            .map(|v| match v {
+                Value::Null => None,
+                v => Some(v.to_string()),
+            })
+            .collect();
+        self.line(p, out, fields)
+    }
+
+    fn end(&mut self, _: &Layout, _: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// json: an array with an object per row, keyed by column name. jsonl:
+/// the same objects, a line each.
+struct Json {
+    lines: bool,
+    rows: usize,
+}
+
+impl Json {
+    fn escape(s: &mut String, c: char) {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(s, "\\u{:04x}", c as u32);
+            }
+            c => s.push(c),
+        }
+    }
+
+    fn string(text: &str) -> String {
+        let mut s = String::from('"');
+        text.chars().for_each(|c| Self::escape(&mut s, c));
+        s.push('"');
+        s
+    }
+
+    fn value(value: &Value) -> String {
+        match value {
+            Value::Null => "null".to_string(),
+            Value::Int(i) => i.to_string(),
+            // JSON has no spelling for these.
+            Value::Float(n) if !n.is_finite() => "null".to_string(),
+            Value::Float(n) => format!("{n:?}"),
+            Value::Text(s) => Self::string(s),
+            // Each byte as the code point of the same value, as sqlite3 does.
+            Value::Blob(b) => {
+                let mut s = String::from('"');
+                for &b in b {
+                    match b {
+                        0..=0x7f => Self::escape(&mut s, b as char),
+                        b => {
+                            let _ = write!(s, "\\u{b:04x}");
+                        }
+                    }
+                }
+                s.push('"');
+                s
+            }
+        }
+    }
+}
+
+impl Format for Json {
+    fn begin(&mut self, _: &Layout, out: &mut dyn Write) -> io::Result<()> {
+        match self.lines {
+            true => Ok(()),
+            false => write!(out, "["),
+        }
+    }
+
+    fn row(&mut self, p: &Layout, out: &mut dyn Write, row: &[Value]) -> io::Result<()> {
+        if self.rows > 0 && !self.lines {
+            writeln!(out, ",")?;
+        }
+        self.rows += 1;
+        let fields = p
+            .columns
+            .iter()
+            .zip(row)
+            .map(|(name, v)| format!("{}:{}", Self::string(name), Self::value(v)))
+            .collect::<Vec<_>>();
+        write!(out, "{{{}}}", fields.join(","))?;
+        match self.lines {
+            true => writeln!(out),
+            false => Ok(()),
+        }
+    }
+
+    fn end(&mut self, _: &Layout, out: &mut dyn Write) -> io::Result<()> {
+        match self.lines {
+            true => Ok(()),
+            false => writeln!(out, "]"),
+        }
+    }
+}
+
+/// line: a `name = value` line per column, rows apart by a blank line.
+struct Line {
+    rows: usize,
+}
+
+impl Format for Line {
+    fn begin(&mut self, _: &Layout, _: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn row(&mut self, p: &Layout, out: &mut dyn Write, row: &[Value]) -> io::Result<()> {
+        if self.rows > 0 {
+            writeln!(out)?;
+        }
+        self.rows += 1;
+        let width = p.columns.iter().map(|c| width(c)).max().unwrap_or(0).max(5);
+        for (name, v) in p.columns.iter().zip(row) {
+            writeln!(out, "{name:>width$} = {}", p.text(v))?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, _: &Layout, _: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// markdown, table and box: columns padded to their widest value, so every
+/// row is held until the end.
+struct Grid {
+    style: Mode,
+    rows: Vec<Vec<String>>,
+}
+
+/// Border pieces: left, horizontal, junction and right, for the top,
+/// middle and bottom rules, then the vertical bar.
+struct Borders {
+    top: [&'static str; 4],
+    mid: [&'static str; 4],
+    bottom: [&'static str; 4],
+    bar: &'static str,
+}
+
+const TABLE: Borders = Borders {
+    top: ["+", "-", "+", "+"],
+    mid: ["+", "-", "+", "+"],
+    bottom: ["+", "-", "+", "+"],
+    bar: "|",
+};
+
+const BOX: Borders = Borders {
+    top: ["┌", "─", "┬", "┐"],
+    mid: ["├", "─", "┼", "┤"],
+    bottom: ["└", "─", "┴", "┘"],
+    bar: "│",
+};
+
+const MARKDOWN: Borders = Borders {
+    top: ["", "", "", ""],
+    mid: ["|", "-", "|", "|"],
+    bottom: ["", "", "", ""],
+    bar: "|",
+};
+
+/// Characters a value takes up on the screen.
+fn width(text: &str) -> usize {
+    text.chars().count()
+}
+
+impl Grid {
+    fn borders(&self) -> &'static Borders {
+        match self.style {
+            Mode::Box => &BOX,
+            Mode::Markdown => &MARKDOWN,
+            _ => &TABLE,
+        }
+    }
+
+    fn rule(
+        out: &mut dyn Write,
+        [left, line, join, right]: [&str; 4],
+        widths: &[usize],
+    ) -> io::Result<()> {
+        if line.is_empty() {
+            return Ok(());
+        }
+        let parts = widths
+            .iter()
+            .map(|w| line.repeat(w + 2))
+            .collect::<Vec<_>>();
+        writeln!(out, "{left}{}{right}", parts.join(join))
+    }
+
+    /// Prints `cells`, each spread over as many lines as it holds.
+    fn cells(
+        &self,
+        out: &mut dyn Write,
+        cells: &[String],
+        widths: &[usize],
+        center: bool,
+    ) -> io::Result<()> {
+        let bar = self.borders().bar;
+        let lines = cells
+            .iter()
+            .map(|c| c.split('\n').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let height = lines.iter().map(Vec::len).max().unwrap_or(1);
+        for i in 0..height {
+            let mut line = String::new();
+            for (cell, &w) in lines.iter().zip(widths) {
+                let text = cell.get(i).copied().unwrap_or_default();
+                let pad = w - width(text);
+                let left = if center { pad / 2 } else { 0 };
+                let _ = write!(
+                    line,
+                    "{bar} {}{text}{} ",
+                    " ".repeat(left),
+                    " ".repeat(pad - left)
+                );
+            }
+            writeln!(out, "{line}{bar}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Format for Grid {
+    fn begin(&mut self, _: &Layout, _: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn row(&mut self, p: &Layout, _: &mut dyn Write, row: &[Value]) -> io::Result<()> {
+        self.rows.push(row.iter().map(|v| p.text(v)).collect());
+        Ok(())
+    }
+
+    fn end(&mut self, p: &Layout, out: &mut dyn Write) -> io::Result<()> {
+        let mut widths = p.columns.iter().map(|c| width(c)).collect::<Vec<_>>();
+        for row in &self.rows {
+            for (w, cell) in widths.iter_mut().zip(row) {
+                *w = cell.split('\n').map(width).fold(*w, usize::max);
+            }
+        }
+        // Rows get rules between them once one spans several lines.
+        let tall = self.rows.iter().flatten().any(|c| c.contains('\n'));
+        let borders = self.borders();
+        Self::rule(out, borders.top, &widths)?;
+        self.cells(out, p.columns, &widths, true)?;
+        Self::rule(out, borders.mid, &widths)?;
+        for (i, row) in self.rows.iter().enumerate() {
+            if tall && i > 0 && self.style != Mode::Markdown {
+                Self::rule(out, borders.mid, &widths)?;
+            }
+            self.cells(out, row, &widths, false)?;
+        }
+        Self::rule(out, borders.bottom, &widths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn print(settings: &Settings, rows: &[Vec<Value>]) -> String {
+        let columns = vec!["a".to_string(), "bb".to_string()];
+        let mut out = vec![];
+        let mut printer = settings.printer(columns, &mut out);
+        for row in rows {
+            printer.row(row).unwrap();
+        }
+        printer.finish().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_modes_lay_out_rows_like_sqlite3() {
+        let rows = [
+            vec![Value::Int(1), Value::Text("x,\"y\"".to_string())],
+            vec![Value::Null, Value::Text(String::new())],
+            vec![Value::Float(2.5), Value::Blob(vec![0, 0xff, b'A'])],
+        ];
+        let mut settings = Settings::default();
+        assert_eq!(print(&settings, &rows), "1|x,\"y\"\n|\n2.5|\0\u{fffd}A\n");
+
+        settings.set_mode("csv".parse().unwrap());
+        settings.headers = true;
+        settings.null = "NULL".to_string();
+        assert_eq!(
+            print(&settings, &rows[..2]),
+            "a,bb\r\n1,\"x,\"\"y\"\"\"\r\nNULL,\"\"\r\n"
+        );
+
+        settings.set_mode("json".parse().unwrap());
+        assert_eq!(
+            print(&settings, &rows),
+            "[{\"a\":1,\"bb\":\"x,\\\"y\\\"\"},\n{\"a\":null,\"bb\":\"\"},\n{\"a\":2.5,\"bb\":\"\\u0000\\u00ffA\"}]\n"
+        );
+        assert_eq!(print(&settings, &[]), "");
+
+        settings.set_mode("line".parse().unwrap());
+        assert_eq!(
+            print(&settings, &rows[..2]),
+            "    a = 1\n   bb = x,\"y\"\n\n    a = NULL\n   bb = \n"
+        );
+
+        settings.set_mode("box".parse().unwrap());
+        let tall = [
+            vec![Value::Int(10), Value::Text("two\nlines".to_string())],
+            vec![Value::Int(2), Value::Null],
+        ];
+        assert_eq!(
+            print(&settings, &tall),
+            "┌────┬───────┐\n│ a  │  bb   │\n├────┼───────┤\n│ 10 │ two   │\n│    │ lines │\n\
+             ├────┼───────┤\n│ 2  │ NULL  │\n└────┴───────┘\n"
+        );
+
+        settings.set_mode("markdown".parse().unwrap());
+        assert_eq!(
+            print(&settings, &tall[1..]),
+            "| a |  bb  |\n|---|------|\n| 2 | NULL |\n"
+        );
+        assert!("html".parse::<Mode>().is_err());
+    }
+}
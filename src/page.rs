@@ -2,42 +2,160 @@ mod cell;
 mod header;
 mod kind;
 
+use crate::chunk::Chunk;
+use crate::codec::text::Encoding;
+use crate::error::{Error, Result};
 use crate::offset::{self, Offset};
 pub use crate::page::kind::Kind;
-use crate::Result;
-use anyhow::{bail, ensure, Context};
-pub use cell::{Column, TableInteriorCell, TableLeafCell};
+pub use cell::{Column, Payload, Record, TableInteriorCell, TableLeafCell};
 pub use header::Header;
+use itertools::Itertools;
 
-#[derive(Debug)]
+/// Page geometry and text encoding, shared by every page of a file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Format {
+    pub page_size: usize,
+    /// Page size minus the reserved chunk at the end of each page.
+    pub usable: usize,
+    pub enc: Encoding,
+}
+
+impl Format {
+    fn max_local(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::TableLeaf => self.usable - 35,
+            _ => (self.usable - 12) * 64 / 255 - 23,
+        }
+    }
+
+    fn min_local(&self) -> usize {
+        (self.usable - 12) * 32 / 255 - 23
+    }
+
+    /// Bytes of a `len` long payload stored on the page itself, the rest
+    /// spilling into overflow pages.
+    pub fn local_size(&self, kind: Kind, len: usize) -> usize {
+        let max = self.max_local(kind);
+        if len <= max {
+            return len;
+        }
+        let min = self.min_local();
+        match min + (len - min) % (self.usable - 4) {
+            k if k <= max => k,
+            _ => min,
+        }
+    }
+
+    /// Content bytes carried by each overflow page.
+    pub fn overflow_size(&self) -> usize {
+        self.usable - 4
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Page {
     pub head: Header,
     cells: Vec<Offset>,
-    data: Box<[u8]>,
+    data: Chunk,
+    /// Where the page header starts, after the database header on page one.
+    pad: usize,
+    fmt: Format,
 }
 
 impl Page {
+    /// Exact bytes of every cell, in pointer order.
+    pub fn cell_slices(&self) -> Result<Vec<&[u8]>> {
+        let fmt = self.fmt;
+        self.cells
+            .iter()
+            .map(|o| {
+                let io = self.cell_at(*o)?;
+                let rest = match self.head.kind {
+                    Kind::TableLeaf => cell::decode::take_leaf_cell(io, fmt).map(|r| r.0),
+                    Kind::TableInterior => cell::decode::take_interior_cell(io).map(|r| r.0),
+                    k => cell::decode::take_index_cell(io, k, fmt).map(|r| r.0),
+                };
+                let rest = rest.map_err(|_| Error::corrupt("malformed cell"))?;
+                Ok(&io[..io.len() - rest.len()])
+            })
+            .collect()
+    }
+
+    /// Bytes from the cell at `offset` to the end of the page.
+    fn cell_at(&self, offset: Offset) -> Result<&[u8]> {
+        self.data
+            .get(offset.as_usize()..self.fmt.usable.min(self.data.len()))
+            .filter(|io| !io.is_empty())
+            .ok_or_else(|| Error::corrupt(format!("cell offset {} out of page", offset.as_usize())))
+    }
+
+    /// Bytes no cell uses: the gap between the cell pointers and the cell
+    /// content area, the freeblocks and the fragmented bytes.
+    pub fn unused(&self) -> Result<usize> {
+        let pointers = self.pad + self.head.kind.header_size() + 2 * self.cells.len();
+        let content = self.head.cell_content() as usize;
+        if content < pointers {
+            return Err(Error::corrupt("cell content overlaps cell pointers"));
+        }
+        let mut unused = content - pointers + self.head.fragment_count as usize;
+        let mut next = self.head.free_block as usize;
+        while next != 0 {
+            let block = self
+                .data
+                .get(next..next + 4)
+                .ok_or_else(|| Error::corrupt(format!("freeblock {next} out of page")))?;
+            unused += u16::from_be_bytes([block[2], block[3]]) as usize;
+            let following = u16::from_be_bytes([block[0], block[1]]) as usize;
+            if following != 0 && following <= next {
+                return Err(Error::corrupt("freeblocks out of order"));
+            }
+            next = following;
+        }
+        Ok(unused)
+    }
+
     pub fn into_leaf(self) -> Result<TableLeafPage> {
-        ensure!(self.head.kind == Kind::TableLeaf);
-        Ok(TableLeafPage(self))
+        match self.head.kind {
+            Kind::TableLeaf => Ok(TableLeafPage(self)),
+            k => Err(Error::corrupt(format!(
+                "{k:?} page where a table leaf was expected"
+            ))),
+        }
     }
 
     pub fn into_interior(self) -> Result<TableInteriorPage> {
-        ensure!(self.head.kind == Kind::TableInterior);
-        Ok(TableInteriorPage(self))
+        match self.head.kind {
+            Kind::TableInterior => Ok(TableInteriorPage(self)),
+            k => Err(Error::corrupt(format!(
+                "{k:?} page where a table interior was expected"
+            ))),
+        }
     }
 }
 
 pub struct TableLeafPage(Page);
 
 impl TableLeafPage {
-    pub fn cells(&self) -> Vec<TableLeafCell> {
+    pub fn len(&self) -> usize {
+        self.0.cells.len()
+    }
+
+    pub fn enc(&self) -> Encoding {
+        self.0.fmt.enc
+    }
+
+    pub fn cells(&self) -> Result<Vec<TableLeafCell<'_>>> {
+        let fmt = self.0.fmt;
         self.0
             .cells
             .iter()
-            .map(|o| &self.0.data[o.as_usize()..] as &[u8])
-            .flat_map(cell::decode::take_leaf_cell)
-            .map(|r| r.1)
+            .map(|o| {
+                let io = self.0.cell_at(*o)?;
+                match cell::decode::take_leaf_cell(io, fmt) {
+                    Ok((_, cell)) => Ok(cell),
+                    Err(_) => Err(Error::corrupt("malformed table leaf cell")),
+                }
+            })
             .collect()
     }
 }
@@ -45,24 +163,40 @@ impl TableLeafPage {
 pub struct TableInteriorPage(Page);
 
 impl TableInteriorPage {
-    pub fn cells(&self) -> Vec<TableInteriorCell> {
+    pub fn cells(&self) -> Result<Vec<TableInteriorCell>> {
         self.0
             .cells
             .iter()
-            .map(|o| &self.0.data[o.as_usize()..] as &[u8])
-            .flat_map(cell::decode::take_interior_cell)
-            .map(|r| r.1)
+            .map(|o| {
+                let io = self.0.cell_at(*o)?;
+                match cell::decode::take_interior_cell(io) {
+                    Ok((_, cell)) => Ok(cell),
+                    Err(_) => Err(Error::corrupt("malformed table interior cell")),
+                }
+            })
             .collect()
     }
 
     pub fn rhs(&self) -> Result<u32> {
-        self.0.head.right_leave.context("Missing right leave")
+        self.0
+            .head
+            .right_leave
+            .ok_or_else(|| Error::corrupt("missing right child"))
+    }
+
+    /// Child page numbers, left to right, right-most pointer included.
+    pub fn children(&self) -> Result<Vec<i64>> {
+        let mut children = self.cells()?.iter().map(|c| c.lhs as i64).collect_vec();
+        children.push(self.rhs()? as i64);
+        Ok(children)
     }
 }
 
 pub mod decode {
     use super::*;
+    pub use cell::decode::{take_index_cell, take_interior_cell, take_leaf_cell};
     use header::decode::*;
+    use nom::error::ErrorKind;
     use nom::multi::fill;
     use nom::{IResult, Parser};
 
@@ -72,15 +206,162 @@ pub mod decode {
         Ok((res.0, vec))
     }
 
-    pub fn take_page(data: &[u8], pad: usize) -> Result<Page> {
-        let Ok((head, cells)) = take_header(&data[pad..]).and_then(|(io, head)| {
-            let (_, offsets) = parse_offsets(io, head.cell_count)?;
-            Ok((head, offsets))
-        }) else {
-            bail!("Page decoding failed");
+    pub fn take_page(data: Chunk, pad: usize, fmt: Format) -> Result<Page> {
+        let Some(io) = data.get(pad..fmt.usable.min(data.len())) else {
+            return Err(Error::corrupt(format!("page of {} bytes", data.len())));
+        };
+        let (head, cells) = match take_header(io) {
+            Ok((io, head)) => match parse_offsets(io, head.cell_count) {
+                Ok((_, offsets)) => (head, offsets),
+                Err(_) => return Err(Error::corrupt("cell pointers overflow the page")),
+            },
+            Err(nom::Err::Failure(e) | nom::Err::Error(e)) if e.code == ErrorKind::MapRes => {
+                return Err(Error::corrupt(format!("unknown page type {:#x}", io[0])));
+            }
+            Err(_) => return Err(Error::corrupt("truncated page header")),
         };
 
-        let data = Box::<[u8]>::from(data);
-        Ok(Page { head, data, cells })
+        Ok(Page {
+            head,
+            data,
+            cells,
+            pad,
+            fmt,
+        })
+    }
+}
+
+pub mod encode {
+    use super::*;
+    use anyhow::ensure;
+    pub use cell::encode::*;
+
+    /// Whether `cells` fit on a single page of `kind`.
+    pub fn fits(kind: Kind, cells: &[Vec<u8>], pad: usize, fmt: Format) -> bool {
+        let used: usize = cells.iter().map(|c| c.len() + 2).sum();
+        pad + kind.header_size() + used <= fmt.usable
+    }
+
+    /// Lays out a page from scratch, with no freeblocks nor fragments. The
+    /// first `pad` bytes, the database header on page one, come from `base`.
+    pub fn page(
+        kind: Kind,
+        cells: &[Vec<u8>],
+        rhs: Option<u32>,
+        pad: usize,
+        base: &[u8],
+        fmt: Format,
+    ) -> anyhow::Result<Vec<u8>> {
+        ensure!(fits(kind, cells, pad, fmt), "Cells overflow {kind:?} page");
+        ensure!(kind.is_interior() == rhs.is_some(), "Invalid right pointer");
+        let mut buf = vec![0u8; fmt.page_size];
+        buf[..pad].copy_from_slice(&base[..pad]);
+
+        let mut content = fmt.usable;
+        let mut pointers = Vec::with_capacity(cells.len() * 2);
+        for cell in cells {
+            content -= cell.len();
+            buf[content..content + cell.len()].copy_from_slice(cell);
+            pointers.extend((content as u16).to_be_bytes());
+        }
+
+        let head = &mut buf[pad..];
+        head[0] = kind.into();
+        head[3..5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+        // A content area starting at 65536 is stored as zero.
+        head[5..7].copy_from_slice(&(content as u16).to_be_bytes());
+        if let Some(rhs) = rhs {
+            head[8..12].copy_from_slice(&rhs.to_be_bytes());
+        }
+        let start = kind.header_size();
+        head[start..start + pointers.len()].copy_from_slice(&pointers);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::SAMPLE_DB;
+
+    /// Xorshift, enough to scramble pages reproducibly without a crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// Decodes everything reachable from a page, errors being fine and
+    /// panics not.
+    fn exercise(page: &Page, fmt: Format) {
+        let _ = page.unused();
+        for cell in page.cell_slices().unwrap_or_default() {
+            let payload = match page.head.kind {
+                Kind::TableInterior => None,
+                Kind::TableLeaf => decode::take_leaf_cell(cell, fmt).ok().map(|c| c.1.payload),
+                k => decode::take_index_cell(cell, k, fmt)
+                    .ok()
+                    .map(|c| c.1.payload),
+            };
+            if let Some(record) = payload.and_then(|p| Record::parse(p.local, fmt.enc).ok()) {
+                for value in record.values().unwrap_or_default() {
+                    let _ = value.to_string();
+                }
+            }
+        }
+        if let Ok(leaf) = page.clone().into_leaf() {
+            let _ = leaf.cells();
+        }
+        if let Ok(interior) = page.clone().into_interior() {
+            let _ = interior.children();
+        }
+    }
+
+    #[test]
+    fn fuzz_take_page() {
+        let file = std::fs::read(SAMPLE_DB).unwrap();
+        let size = u16::from_be_bytes([file[16], file[17]]) as usize;
+        let fmt = Format {
+            page_size: size,
+            usable: size,
+            enc: Encoding::Utf8,
+        };
+        let pages = file.chunks_exact(size).collect::<Vec<_>>();
+        let kinds = [0x02, 0x05, 0x0a, 0x0d];
+        let mut rng = Rng(0x5eed_cafe_f00d_d00d);
+        for _ in 0..20_000 {
+            let n = rng.below(pages.len());
+            let pad = if n == 0 { 100 } else { 0 };
+            let mut data = match rng.below(8) {
+                0 => (0..size).map(|_| rng.next() as u8).collect(),
+                _ => pages[n].to_vec(),
+            };
+            for _ in 0..=rng.below(8) {
+                let at = match rng.below(2) {
+                    // Headers and cell pointers are where decoding branches.
+                    0 => pad + rng.below(32),
+                    _ => rng.below(size),
+                };
+                data[at] = rng.next() as u8;
+            }
+            if rng.below(4) == 0 {
+                data[pad] = kinds[rng.below(kinds.len())];
+            }
+            if rng.below(16) == 0 {
+                data.truncate(rng.below(size));
+            }
+            if let Ok(page) = decode::take_page(data.into(), pad, fmt) {
+                exercise(&page, fmt);
+            }
+        }
     }
 }
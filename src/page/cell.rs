@@ -1,7 +1,8 @@
+use crate::codec::text::Encoding;
 use crate::codec::varint;
-use crate::value::{Type, Value};
-use crate::Result;
-use anyhow::Context;
+use crate::error::{Error, Result};
+use crate::page::{Format, Kind};
+use crate::value::{Type, Value, ValueRef};
 
 #[derive(Debug, Copy, Clone)]
 pub enum Column {
@@ -9,28 +10,83 @@ pub enum Column {
     Content(usize),
 }
 
+/// Record whose header is decoded up front while column values are only
+/// decoded, straight from the payload, when asked for.
 #[derive(Debug, Clone)]
-pub struct Record {
+pub struct Record<'a> {
     pub types: Vec<Type>,
-    pub values: Vec<Value>,
+    offsets: Vec<usize>,
+    body: &'a [u8],
+    enc: Encoding,
 }
 
-#[derive(Debug, Clone)]
-pub struct TableLeafCell {
-    pub id: i64,
-    pub len: i64,
-    pub record: Record,
-}
+impl<'a> Record<'a> {
+    pub fn parse(payload: &'a [u8], enc: Encoding) -> Result<Self> {
+        match decode::take_record(payload, enc) {
+            Ok((_, rec)) => Ok(rec),
+            Err(_) => Err(Error::corrupt("malformed record")),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn get(&self, i: usize) -> Result<ValueRef<'a>> {
+        let Some(t) = self.types.get(i).copied() else {
+            return Err(Error::NotFound {
+                kind: "column",
+                name: i.to_string(),
+            });
+        };
+        let start = self.offsets[i];
+        let chunk = self
+            .body
+            .get(start..start + t.len())
+            .ok_or_else(|| Error::corrupt("record body too short"))?;
+        ValueRef::decode(chunk, t, self.enc)
+    }
+
+    /// Every value of the record, copied out of the page.
+    pub fn values(&self) -> Result<Vec<Value>> {
+        (0..self.len())
+            .map(|i| self.get(i).map(ValueRef::into_owned))
+            .collect()
+    }
 
-impl TableLeafCell {
-    pub fn value(&self, col: &Column) -> Result<Value> {
+    /// Value of `col` for the row `id` this record belongs to.
+    pub fn value(&self, id: i64, col: &Column) -> Result<ValueRef<'a>> {
         match col {
-            Column::ID => Ok(Value::Int(self.id)),
-            Column::Content(i) => self.record.values.get(*i).cloned().context("Invalid index"),
+            Column::ID => Ok(ValueRef::Int(id)),
+            Column::Content(i) if *i >= self.len() => Ok(ValueRef::Null),
+            Column::Content(i) => self.get(*i),
         }
     }
 }
 
+/// Cell payload as laid out on its page, the tail of it living in a chain of
+/// overflow pages when it does not fit.
+#[derive(Debug, Copy, Clone)]
+pub struct Payload<'a> {
+    pub len: usize,
+    pub local: &'a [u8],
+    pub overflow: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableLeafCell<'a> {
+    pub id: i64,
+    pub payload: Payload<'a>,
+}
+
+/// Index entry, `lhs` being set on interior pages only.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct IndexCell<'a> {
+    pub lhs: Option<u32>,
+    pub payload: Payload<'a>,
+}
+
 #[derive(Debug, Clone)]
 pub struct TableInteriorCell {
     pub lhs: u32,
@@ -40,6 +96,9 @@ pub struct TableInteriorCell {
 pub mod decode {
     use super::*;
     use crate::value;
+    use nom::bytes::complete::take;
+    use nom::combinator::{cond, verify};
+    use nom::error::ErrorKind;
     use nom::number::complete::be_u32;
     use nom::sequence::tuple;
     use nom::{IResult, Parser};
@@ -50,34 +109,115 @@ pub mod decode {
             .parse(io)
     }
 
-    pub fn take_leaf_cell(io: &[u8]) -> IResult<&[u8], TableLeafCell> {
-        let (io, len) = varint::take(io)?;
+    pub fn take_payload(
+        io: &[u8],
+        len: usize,
+        kind: Kind,
+        fmt: Format,
+    ) -> IResult<&[u8], Payload<'_>> {
+        let local = fmt.local_size(kind, len);
+        let (io, local) = take(local)(io)?;
+        let (io, overflow) = cond(local.len() < len, be_u32)(io)?;
+        let payload = Payload {
+            len,
+            local,
+            overflow,
+        };
+        Ok((io, payload))
+    }
+
+    /// Payload length, no larger than SQLite ever writes.
+    fn take_len(io: &[u8]) -> IResult<&[u8], i64> {
+        verify(varint::take, |len| (0..=i32::MAX as i64).contains(len))(io)
+    }
+
+    pub fn take_leaf_cell(io: &[u8], fmt: Format) -> IResult<&[u8], TableLeafCell<'_>> {
+        let (io, len) = take_len(io)?;
         let (io, id) = varint::take(io)?;
-        let (io, record) = take_record(io)?;
-        Ok((io, TableLeafCell { id, len, record }))
+        let (io, payload) = take_payload(io, len as usize, Kind::TableLeaf, fmt)?;
+        Ok((io, TableLeafCell { id, payload }))
+    }
+
+    pub fn take_index_cell(io: &[u8], kind: Kind, fmt: Format) -> IResult<&[u8], IndexCell<'_>> {
+        let (io, lhs) = cond(kind.is_interior(), be_u32)(io)?;
+        let (io, len) = take_len(io)?;
+        let (io, payload) = take_payload(io, len as usize, kind, fmt)?;
+        Ok((io, IndexCell { lhs, payload }))
     }
 
-    pub fn take_record(io: &[u8]) -> IResult<&[u8], Record> {
+    pub fn take_record(io: &[u8], enc: Encoding) -> IResult<&[u8], Record<'_>> {
+        let fail = || nom::Err::Error(nom::error::Error::new(io, ErrorKind::Verify));
         let (inner, tsz) = varint::take(io)?;
-        let mut buf = &io[..tsz as usize];
-        buf = &buf[io.len() - inner.len()..];
+        let start = io.len() - inner.len();
+        let tsz = usize::try_from(tsz).map_err(|_| fail())?;
+        let mut buf = io.get(start..tsz).ok_or_else(fail)?;
 
         let mut types = Vec::new();
+        let mut offsets = Vec::new();
+        let mut size = 0usize;
         while !buf.is_empty() {
             let (io, kind) = value::decode::take_type(buf)?;
             types.push(kind);
+            offsets.push(size);
+            size = size.checked_add(kind.len()).ok_or_else(fail)?;
             buf = io;
         }
 
-        let mut values = Vec::with_capacity(types.len());
-        let mut io = &io[tsz as usize..];
-        for t in types.iter() {
-            let (buf, val) = value::decode::take_value(io, *t)?;
-            io = buf;
-            values.push(val);
+        let (io, body) = take(size)(&io[tsz..])?;
+        let rec = Record {
+            types,
+            offsets,
+            body,
+            enc,
+        };
+        Ok((io, rec))
+    }
+}
+
+pub mod encode {
+    use super::*;
+
+    pub fn record(values: &[Value], enc: Encoding) -> Vec<u8> {
+        let (types, bodies): (Vec<_>, Vec<_>) = values.iter().map(|v| v.encode(enc)).unzip();
+        let mut head = types
+            .into_iter()
+            .flat_map(|t| varint::encode(t.into()))
+            .collect::<Vec<_>>();
+        // The header size counts its own varint, which may grow it by a byte.
+        let mut size = head.len() + 1;
+        while varint::encode(size as i64).len() + head.len() != size {
+            size = varint::encode(size as i64).len() + head.len();
+        }
+        let mut buf = varint::encode(size as i64);
+        buf.append(&mut head);
+        bodies.into_iter().for_each(|mut b| buf.append(&mut b));
+        buf
+    }
+
+    /// Table leaf cell, `local` being the part of the payload kept on page.
+    pub fn leaf_cell(id: i64, len: usize, local: &[u8], overflow: Option<u32>) -> Vec<u8> {
+        let mut buf = varint::encode(len as i64);
+        buf.extend(varint::encode(id));
+        buf.extend_from_slice(local);
+        if let Some(page) = overflow {
+            buf.extend(page.to_be_bytes());
+        }
+        buf
+    }
+
+    /// Index leaf cell, interior cells prefixing it with their left child.
+    pub fn index_cell(len: usize, local: &[u8], overflow: Option<u32>) -> Vec<u8> {
+        let mut buf = varint::encode(len as i64);
+        buf.extend_from_slice(local);
+        if let Some(page) = overflow {
+            buf.extend(page.to_be_bytes());
         }
+        buf
+    }
 
-        let rec = Record { types, values };
-        Ok((io, rec))
+    pub fn interior_cell(lhs: u32, row: i64) -> Vec<u8> {
+        let mut buf = lhs.to_be_bytes().to_vec();
+        buf.extend(varint::encode(row));
+        buf
     }
 }
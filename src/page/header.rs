@@ -10,9 +10,9 @@ pub struct Header {
     pub right_leave: Option<u32>,
 }
 
-#[allow(dead_code)]
 impl Header {
-    fn cell_content(&self) -> u32 {
+    /// Start of the cell content area.
+    pub fn cell_content(&self) -> u32 {
         match self.cell_content {
             0 => 65_536,
             _ => self.cell_content as u32,
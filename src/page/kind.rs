@@ -1,6 +1,6 @@
-use anyhow::anyhow;
+use crate::error::{Error, Result};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Kind {
     TableInterior,
     TableLeaf,
@@ -15,18 +15,52 @@ impl Kind {
             Self::TableLeaf | Self::IndexLeaf => false,
         }
     }
+
+    pub fn is_table(&self) -> bool {
+        match self {
+            Self::TableInterior | Self::TableLeaf => true,
+            Self::IndexInterior | Self::IndexLeaf => false,
+        }
+    }
+
+    /// Kind of the interior pages of the same B-tree.
+    pub fn interior(&self) -> Kind {
+        match self.is_table() {
+            true => Self::TableInterior,
+            false => Self::IndexInterior,
+        }
+    }
+
+    /// Size of the page header, the right-most pointer included.
+    pub fn header_size(&self) -> usize {
+        match self.is_interior() {
+            true => 12,
+            false => 8,
+        }
+    }
+}
+
+impl From<Kind> for u8 {
+    fn from(value: Kind) -> Self {
+        match value {
+            Kind::TableInterior => 0x5,
+            Kind::TableLeaf => 0xD,
+            Kind::IndexInterior => 0x2,
+            Kind::IndexLeaf => 0xA,
+        }
+    }
 }
 
 impl TryFrom<u8> for Kind {
-    type Error = anyhow::Error;
+    type Error = Error;
 
-    fn try_from(value: u8) -> anyhow::Result<Self> {
+    fn try_from(value: u8) -> Result<Self> {
         match value {
             0x5 => Ok(Self::TableInterior),
             0xD => Ok(Self::TableLeaf),
             0x2 => Ok(Self::IndexInterior),
             0xA => Ok(Self::IndexLeaf),
-            _ => Err(anyhow!("Unknown page kind")),
+            k => Err(Error::corrupt(format!("unknown page type {k:#x}"))),
         }
     }
 }
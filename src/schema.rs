@@ -1,10 +1,19 @@
 use crate::codec;
-use crate::page::{Header, Page, TableLeafCell};
+use crate::db::{btree, SQL};
+use crate::error;
+use crate::page::Record;
+use crate::value::Collation;
 use anyhow::{bail, Context, Error, Result};
-use itertools::Itertools;
 
 const NAME_PREFIX_SQLITE: &str = "sqlite_";
 
+/// Table keeping the AUTOINCREMENT counters.
+pub const SEQUENCE_TABLE: &str = "sqlite_sequence";
+
+/// Tables ANALYZE keeps row counts in, for the query planner.
+pub const STAT1_TABLE: &str = "sqlite_stat1";
+pub const STAT4_TABLE: &str = "sqlite_stat4";
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Type {
     Table,
@@ -27,6 +36,7 @@ impl TryFrom<&str> for Type {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Descriptor {
     pub id: i64,
@@ -35,18 +45,20 @@ pub struct Descriptor {
     pub root: i64,
     pub internal: bool,
     pub sql: String,
+    /// Table an index or trigger belongs to, the object itself otherwise.
+    pub tbl_name: String,
 }
 
-impl TryFrom<&TableLeafCell> for Descriptor {
+impl TryFrom<(i64, &Record<'_>)> for Descriptor {
     type Error = Error;
 
-    fn try_from(r: &TableLeafCell) -> Result<Self> {
-        let id = r.id;
+    fn try_from((id, r): (i64, &Record<'_>)) -> Result<Self> {
         let name = parser::name(r)?;
         let kind = parser::kind(r)?;
         let internal = name.starts_with(NAME_PREFIX_SQLITE);
         let root = parser::root(r)?;
         let sql = parser::sql(r)?;
+        let tbl_name = parser::tbl_name(r)?;
         Ok(Self {
             id,
             name,
@@ -54,6 +66,7 @@ impl TryFrom<&TableLeafCell> for Descriptor {
             root,
             internal,
             sql,
+            tbl_name,
         })
     }
 }
@@ -62,64 +75,231 @@ impl Descriptor {
     pub fn column_names(&self) -> Vec<&str> {
         codec::sql::column_names(&self.sql)
     }
-}
 
-pub struct Schema {
-    pub head: Header,
-    pub desc: Vec<Descriptor>,
-}
+    /// Whether this index is UNIQUE and the columns of `table` it holds, in
+    /// key order. Only indexes over whole columns, in ascending order and
+    /// covering every row, are supported.
+    pub fn index_columns(&self, table: &Descriptor) -> Result<(bool, Vec<String>)> {
+        let (unique, cols) = match self.sql.as_str() {
+            "" => {
+                let n = self.name.rsplit('_').next().unwrap_or_default();
+                let keys = codec::sql::unique_keys(&table.sql);
+                let key = n
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| keys.get(n.wrapping_sub(1)));
+                let key = key.with_context(|| format!("No constraint for {}", self.name))?;
+                (true, key.clone())
+            }
+            sql => {
+                let def = codec::sql::index(sql);
+                if def.partial {
+                    let name = &self.name;
+                    bail!(error::Error::Unsupported(format!("partial index {name}")));
+                }
+                for (col, suffix) in &def.columns {
+                    key_collation(&self.name, col, suffix)?;
+                }
+                (def.unique, def.columns.into_iter().map(|c| c.0).collect())
+            }
+        };
+        Ok((unique, cols.into_iter().map(String::from).collect()))
+    }
 
-impl TryFrom<Page> for Schema {
-    type Error = Error;
+    /// Collation of each column of this index, in key order: the one the
+    /// index names, else the one declared on the table column.
+    pub fn index_collations(&self, table: &Descriptor) -> Result<Vec<Collation>> {
+        let defs = codec::sql::columns(&table.sql);
+        let declared = |col: &str| {
+            let def = defs.iter().find(|d| d.name.eq_ignore_ascii_case(col));
+            def.and_then(|d| d.collate)
+        };
+        let (_, cols) = self.index_columns(table)?;
+        let def = codec::sql::index(&self.sql);
+        let mut collations = Vec::with_capacity(cols.len());
+        for (i, col) in cols.iter().enumerate() {
+            let named = match def.columns.get(i) {
+                Some((_, suffix)) => key_collation(&self.name, col, suffix)?,
+                None => None,
+            };
+            let collation = named.or_else(|| declared(col));
+            collations.push(collation.map_or(Ok(Collation::Binary), str::parse)?);
+        }
+        Ok(collations)
+    }
+}
 
-    fn try_from(page: Page) -> Result<Self> {
-        let head = page.head;
-        let cells = page.into_leaf()?.cells();
-        let desc = cells
-            .iter()
-            .map(|c| Descriptor::try_from(c))
-            .try_collect()?;
-        Ok(Self { head, desc })
+/// Collation named by the suffix of column `col` in `index`, which may only
+/// add ASC to it.
+fn key_collation<'s>(index: &str, col: &str, suffix: &'s str) -> Result<Option<&'s str>> {
+    let words = suffix.split_whitespace().collect::<Vec<_>>();
+    let (collation, order) = match words.as_slice() {
+        [collate, name, order @ ..] if collate.eq_ignore_ascii_case("COLLATE") => {
+            (Some(*name), order)
+        }
+        order => (None, order),
+    };
+    match order {
+        [] => Ok(collation),
+        [asc] if asc.eq_ignore_ascii_case("ASC") => Ok(collation),
+        _ => bail!(error::Error::Unsupported(format!(
+            "index {index} on {col} {suffix}"
+        ))),
     }
 }
 
+pub struct Schema {
+    pub desc: Vec<Descriptor>,
+}
+
 impl Schema {
-    pub fn tables(&self) -> impl Iterator<Item = &Descriptor> {
-        self.desc.iter().filter(|t| t.kind == Type::Table)
+    /// Reads the `sqlite_schema` table, rooted at page one.
+    pub fn read(db: &dyn SQL) -> Result<Self> {
+        let mut desc = Vec::new();
+        for leaf in btree::Leaves::new(db, 1) {
+            let leaf = leaf?;
+            for cell in leaf.cells()? {
+                let payload = btree::payload(db, &cell.payload)?;
+                let record = Record::parse(&payload, leaf.enc())?;
+                desc.push(Descriptor::try_from((cell.id, &record))?);
+            }
+        }
+        Ok(Self { desc })
     }
 
+    /// The table called `name`, ignoring ASCII case as SQLite does; indexes,
+    /// views and triggers are not tables even when they share the name.
     pub fn table_named(&self, name: &str) -> Result<&Descriptor> {
         self.desc
             .iter()
-            .find(|d| d.name == name)
-            .context("table not found")
+            .find(|d| d.kind == Type::Table && d.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                error::Error::NotFound {
+                    kind: "table",
+                    name: name.to_string(),
+                }
+                .into()
+            })
+    }
+
+    pub fn indexes_on<'a>(&'a self, table: &'a str) -> impl Iterator<Item = &'a Descriptor> {
+        self.desc
+            .iter()
+            .filter(move |d| d.kind == Type::Index && d.tbl_name.eq_ignore_ascii_case(table))
     }
 }
 
+/// SQL `LIKE`: `%` matches any run of characters, `_` any single one, and
+/// ASCII letters match regardless of case.
+pub fn like(pattern: &str, text: &str) -> bool {
+    let fold = |s: &str| {
+        s.chars()
+            .map(|c| c.to_ascii_lowercase())
+            .collect::<Vec<_>>()
+    };
+    let (pattern, text) = (fold(pattern), fold(text));
+    let (mut p, mut t) = (0, 0);
+    // Last `%` seen, and where in the text it started matching.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('%') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '_' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, from)) => {
+                    backtrack = Some((star, from + 1));
+                    p = star + 1;
+                    t = from + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '%')
+}
+
 mod parser {
     use super::*;
-    use crate::value::Value;
+    use crate::value::ValueRef;
 
-    pub fn kind(c: &TableLeafCell) -> Result<Type> {
-        let value = c.record.values.get(0).context("type")?;
-        let Value::Text(s) = value else {
+    pub fn kind(c: &Record) -> Result<Type> {
+        let value = c.get(0).context("type")?;
+        let ValueRef::Text(s) = value else {
             bail!("invalid type")
         };
-        s.as_str().try_into()
+        s.as_ref().try_into()
     }
 
-    pub fn name(c: &TableLeafCell) -> Result<String> {
-        let value = c.record.values.get(1).context("name")?;
+    pub fn name(c: &Record) -> Result<String> {
+        let value = c.get(1).context("name")?;
         Ok(value.to_string())
     }
 
-    pub fn sql(c: &TableLeafCell) -> Result<String> {
-        let value = c.record.values.get(4).context("sql statement")?;
+    pub fn tbl_name(c: &Record) -> Result<String> {
+        let value = c.get(2).context("table name")?;
         Ok(value.to_string())
     }
 
-    pub fn root(c: &TableLeafCell) -> Result<i64> {
-        let value = c.record.values.get(3).context("root page")?;
-        value.try_into()
+    pub fn sql(c: &Record) -> Result<String> {
+        // Automatic indexes have no statement.
+        match c.get(4).context("sql statement")? {
+            ValueRef::Null => Ok(String::new()),
+            value => Ok(value.to_string()),
+        }
+    }
+
+    pub fn root(c: &Record) -> Result<i64> {
+        let value = c.get(3).context("root page")?;
+        Ok(value.try_into()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_like_matches_wildcards_ignoring_case() {
+        assert!(like("apples", "Apples"));
+        assert!(like("a%", "apples"));
+        assert!(like("%PL%", "apples"));
+        assert!(like("a_ples", "apples"));
+        assert!(like("%", ""));
+        assert!(like("a%s%s", "asparagus"));
+        assert!(!like("a_", "a"));
+        assert!(!like("%x", "apples"));
+        assert!(!like("pears", "pear"));
+    }
+
+    #[test]
+    fn test_table_named_skips_other_objects() {
+        let desc = |id, name: &str, kind| Descriptor {
+            id,
+            name: name.to_string(),
+            kind,
+            root: id + 1,
+            internal: false,
+            sql: String::new(),
+            tbl_name: "stock".to_string(),
+        };
+        let schema = Schema {
+            desc: vec![
+                desc(1, "Stock", Type::Index),
+                desc(2, "stock", Type::Table),
+                desc(3, "sold", Type::View),
+            ],
+        };
+        assert_eq!(schema.table_named("STOCK").unwrap().id, 2);
+        let err = schema.table_named("sold").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(error::Error::NotFound { kind: "table", .. })
+        ));
     }
 }
@@ -0,0 +1,235 @@
+use anyhow::Result;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+const PROMPT: &str = "sqlite> ";
+const CONTINUATION: &str = "   ...> ";
+
+/// Entries kept in the history file.
+const HISTORY_SIZE: usize = 1000;
+
+const HELP: &str = "\
+.analyze            Show per B-tree space usage
+.check              Check the integrity of the database
+.dbinfo             Show status information about the database
+.dump ?PATTERN?     Write SQL text rebuilding the tables LIKE PATTERN, or all
+.exit, .quit        Exit this program
+.export TABLE ?FILE? ?--format csv|jsonl? ?--blobs hex|base64?
+                    Write the rows of TABLE to FILE, or to the screen
+.freelist           Show the pages on the freelist
+.headers on|off     Turn display of column names on or off
+.help               Show this message
+.history            Show the statements entered so far
+.import ?OPTIONS? FILE TABLE
+                    Read CSV from FILE into TABLE, created from the header
+                    row when missing. Options: --skip N, --separator SEP
+.indexes ?TABLE?    Show the names of indexes, of tables LIKE TABLE if given
+.mode MODE          Set the output mode: box csv json jsonl line list markdown
+                    table tsv
+.nullvalue STRING   Use STRING in place of NULL values
+.schema ?PATTERN?   Show the CREATE statements, of tables LIKE PATTERN if given
+.separator COL ?ROW? Change the column and row separators
+.stats              Show page cache statistics
+.tables ?PATTERN?   List names of tables and views LIKE PATTERN, internal
+                    ones only when PATTERN starts with sqlite";
+
+/// Splits input into statements, each ending at a `;` outside of quotes
+/// and comments.
+#[derive(Debug, Default)]
+pub struct Statements {
+    buf: String,
+    /// Closing character of the quote being read, if any.
+    quote: Option<char>,
+}
+
+impl Statements {
+    /// Whether no statement is partially read.
+    pub fn is_empty(&self) -> bool {
+        self.buf.trim().is_empty()
+    }
+
+    /// Reads one more line, returning the statements it completes without
+    /// their `;`.
+    pub fn push(&mut self, line: &str) -> Vec<String> {
+        let mut done = vec![];
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (self.quote, c) {
+                (Some(close), c) if c == close => self.quote = None,
+                (Some(_), _) => {}
+                (None, '\'' | '"' | '`') => self.quote = Some(c),
+                (None, '[') => self.quote = Some(']'),
+                (None, '-') if chars.peek() == Some(&'-') => break,
+                (None, ';') => {
+                    let stmt = std::mem::take(&mut self.buf);
+                    if !stmt.trim().is_empty() {
+                        done.push(stmt.trim().to_string());
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            self.buf.push(c);
+        }
+        if !self.is_empty() {
+            self.buf.push('\n');
+        }
+        done
+    }
+}
+
+/// Statements entered in interactive sessions, kept across them in a file
+/// in the home directory.
+struct History {
+    path: Option<PathBuf>,
+    entries: Vec<String>,
+}
+
+impl History {
+    fn load() -> Self {
+        let path = std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".your_sqlite3_history"));
+        let entries = path
+            .as_ref()
+            .and_then(|p| std::fs::read_to_string(p).ok())
+            .map(|text| text.lines().map(String::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let skip = entries.len().saturating_sub(HISTORY_SIZE);
+        Self {
+            path,
+            entries: entries.into_iter().skip(skip).collect(),
+        }
+    }
+
+    fn add(&mut self, entry: &str) {
+        let entry = entry.replace('\n', " ");
+        if self.entries.last() == Some(&entry) {
+            return;
+        }
+        // Losing history is no reason to interrupt the session.
+        if let Some(path) = &self.path {
+            let file = OpenOptions::new().create(true).append(true).open(path);
+            let _ = file.and_then(|mut f| writeln!(f, "{entry}"));
+        }
+        self.entries.push(entry);
+    }
+}
+
+/// Reads statements and dot-commands from `input` until it ends or `.quit`,
+/// handing each to `exec`. A failing one is reported and the session goes
+/// on. Prompts and history are only for `interactive` sessions.
+pub fn run(
+    input: impl BufRead,
+    interactive: bool,
+    mut exec: impl FnMut(&str) -> Result<()>,
+) -> Result<()> {
+    let mut history = match interactive {
+        true => Some(History::load()),
+        false => None,
+    };
+    let mut pending = Statements::default();
+    let mut lines = input.lines();
+    loop {
+        if interactive {
+            let prompt = match pending.is_empty() {
+                true => PROMPT,
+                false => CONTINUATION,
+            };
+            print!("{prompt}");
+            io::stdout().flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+
+        // Dot-commands take the whole line and need no `;`.
+        if pending.is_empty() && line.trim_start().starts_with('.') {
+            let line = line.trim();
+            if let Some(history) = history.as_mut() {
+                history.add(line);
+            }
+            match line.split_whitespace().next() {
+                Some(".quit" | ".exit") => break,
+                Some(".help") => println!("{HELP}"),
+                Some(".history") => {
+                    let entries = history.as_ref().map(|h| h.entries.as_slice());
+                    for (i, entry) in entries.unwrap_or_default().iter().enumerate() {
+                        println!("{:5}  {entry}", i + 1);
+                    }
+                }
+                _ => report(exec(line)),
+            }
+            continue;
+        }
+        for stmt in pending.push(&line) {
+            if let Some(history) = history.as_mut() {
+                history.add(&format!("{stmt};"));
+            }
+            report(exec(&stmt));
+        }
+    }
+    if interactive {
+        println!();
+    }
+    Ok(())
+}
+
+fn report(res: Result<()>) {
+    if let Err(e) = res {
+        eprintln!("Error: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Select;
+
+    #[test]
+    fn test_statements_end_at_semicolons_outside_quotes() {
+        let mut stmts = Statements::default();
+        assert!(stmts.push("select a").is_empty());
+        assert!(!stmts.is_empty());
+        let done = stmts.push("  from t where a = 'x;y'; select 1; -- trailing; comment");
+        assert_eq!(done, ["select a\n  from t where a = 'x;y'", "select 1"]);
+        assert!(stmts.is_empty());
+        assert_eq!(
+            stmts.push("insert into [a;b] values (\"it's\");"),
+            ["insert into [a;b] values (\"it's\")"]
+        );
+
+        let input = ".tables\nselect\n 1;\nbad;\n.quit\nselect 2;\n";
+        let mut seen = vec![];
+        run(input.as_bytes(), false, |s| {
+            seen.push(s.to_string());
+            match s {
+                "bad" => anyhow::bail!("no such thing"),
+                _ => Ok(()),
+            }
+        })
+        .unwrap();
+        assert_eq!(seen, [".tables", "select\n 1", "bad"]);
+
+        let mut stmts = Statements::default();
+        assert!(stmts.push("select name,").is_empty());
+        assert!(stmts.push("  color").is_empty());
+        assert!(stmts.push("from apples").is_empty());
+        let done = stmts.push("where color = 'Red';");
+        let Ok(Select::Column {
+            table,
+            columns,
+            cond,
+        }) = Select::try_from(done[0].clone())
+        else {
+            panic!("{done:?} does not parse");
+        };
+        assert_eq!(table, "apples");
+        assert_eq!(columns.as_slice(), ["name", "color"]);
+        assert_eq!(cond.unwrap().value, crate::value::Value::Text("Red".into()));
+        let done = stmts.push("select count(*)\nfrom apples\nwhere id = 3;");
+        let Ok(Select::Count { table, cond }) = Select::try_from(done[0].clone()) else {
+            panic!("{done:?} does not parse");
+        };
+        assert_eq!((table.as_str(), cond.is_some()), ("apples", true));
+    }
+}
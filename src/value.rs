@@ -1,5 +1,8 @@
 use crate::codec;
-use anyhow::{bail, Error, Result};
+use crate::codec::text::Encoding;
+use crate::error::{Error, Result};
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 
@@ -10,44 +13,82 @@ pub enum Type {
     Float64,
     Zero,
     One,
-    Reserved,
     Blob(i64),
     Text(i64),
 }
 
-impl From<i64> for Type {
-    fn from(value: i64) -> Self {
+impl TryFrom<i64> for Type {
+    type Error = Error;
+
+    fn try_from(value: i64) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Null),
+            n if (1..=4).contains(&n) => Ok(Self::Int(n)),
+            5 => Ok(Self::Int(6)),
+            6 => Ok(Self::Int(8)),
+            7 => Ok(Self::Float64),
+            8 => Ok(Self::Zero),
+            9 => Ok(Self::One),
+            n if n >= 12 && n % 2 == 0 => Ok(Self::Blob((n - 12) / 2)),
+            n if n >= 13 => Ok(Self::Text((n - 13) / 2)),
+            n => Err(Error::corrupt(format!("invalid serial type {n}"))),
+        }
+    }
+}
+
+impl From<Type> for i64 {
+    fn from(value: Type) -> Self {
         match value {
-            0 => Self::Null,
-            n if (1..4).contains(&n) => Self::Int(n),
-            5 => Self::Int(6),
-            6 => Self::Int(8),
-            7 => Self::Float64,
-            8 => Self::Zero,
-            9 => Self::One,
-            10 | 11 => Self::Reserved,
-            n if (n % 2 == 0) => Self::Blob((n - 12) / 2),
-            n if (n % 2 != 0) => Self::Text((n - 13) / 2),
-            _ => unreachable!(),
+            Type::Null => 0,
+            Type::Int(6) => 5,
+            Type::Int(8) => 6,
+            Type::Int(n) => n,
+            Type::Float64 => 7,
+            Type::Zero => 8,
+            Type::One => 9,
+            Type::Blob(n) => n * 2 + 12,
+            Type::Text(n) => n * 2 + 13,
         }
     }
 }
 
 impl Type {
-    fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         match self {
             Self::Null => 0,
             Self::Int(l) => *l as usize,
             Self::Float64 => 8,
             Self::Zero => 0,
             Self::One => 0,
-            Self::Reserved => unreachable!("DB is not well-formed"),
             Self::Blob(l) => *l as usize,
             Self::Text(l) => *l as usize,
         }
     }
 }
 
+/// Column type affinity, derived from the declared type of a column.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Affinity {
+    Text,
+    Numeric,
+    Integer,
+    Real,
+    Blob,
+}
+
+impl From<&str> for Affinity {
+    fn from(decl: &str) -> Self {
+        let decl = decl.to_uppercase();
+        match decl.as_str() {
+            d if d.contains("INT") => Self::Integer,
+            d if d.contains("CHAR") || d.contains("CLOB") || d.contains("TEXT") => Self::Text,
+            d if d.is_empty() || d.contains("BLOB") => Self::Blob,
+            d if d.contains("REAL") || d.contains("FLOA") || d.contains("DOUB") => Self::Real,
+            _ => Self::Numeric,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Null,
@@ -57,74 +98,341 @@ pub enum Value {
     Text(String),
 }
 
-impl Display for Value {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl Value {
+    /// Serial type and body bytes of the value inside a record.
+    pub fn encode(&self, enc: Encoding) -> (Type, Vec<u8>) {
         match self {
-            Value::Null => write!(f, "null"),
-            Value::Text(s) => s.fmt(f),
-            Value::Float(n) => n.fmt(f),
-            Value::Int(i) => i.fmt(f),
-            Value::Blob(_) => todo!(),
+            Self::Null => (Type::Null, vec![]),
+            Self::Int(0) => (Type::Zero, vec![]),
+            Self::Int(1) => (Type::One, vec![]),
+            Self::Int(i) => {
+                let width = codec::two_complements::width(*i);
+                let body = codec::two_complements::encode(*i, width);
+                (Type::Int(width as i64), body)
+            }
+            Self::Float(n) => (Type::Float64, codec::float::encode(*n)),
+            Self::Blob(b) => (Type::Blob(b.len() as i64), b.clone()),
+            Self::Text(s) => {
+                let body = codec::text::encode(s, enc);
+                (Type::Text(body.len() as i64), body)
+            }
         }
     }
+
+    /// Conversion applied to a value stored in a column with `affinity`.
+    pub fn with_affinity(self, affinity: Affinity) -> Self {
+        let numeric = |s: &str| match s.trim().parse::<i64>() {
+            Ok(i) => Some(Self::Int(i)),
+            Err(_) if is_real_literal(s.trim()) => s.trim().parse::<f64>().ok().map(Self::Float),
+            Err(_) => None,
+        };
+        let integral = |n: f64| {
+            let i = n as i64;
+            match i as f64 == n && n.abs() < 9.2e18 {
+                true => Self::Int(i),
+                false => Self::Float(n),
+            }
+        };
+        match (affinity, self) {
+            (Affinity::Text, Self::Int(i)) => Self::Text(i.to_string()),
+            (Affinity::Text, Self::Float(n)) => Self::Text(ValueRef::Float(n).to_string()),
+            (Affinity::Numeric | Affinity::Integer, Self::Text(s)) => match numeric(&s) {
+                Some(Self::Float(n)) => integral(n),
+                Some(v) => v,
+                None => Self::Text(s),
+            },
+            (Affinity::Numeric | Affinity::Integer, Self::Float(n)) => integral(n),
+            (Affinity::Real, Self::Int(i)) => Self::Float(i as f64),
+            (Affinity::Real, Self::Text(s)) => match numeric(&s) {
+                Some(Self::Int(i)) => Self::Float(i as f64),
+                Some(v) => v,
+                None => Self::Text(s),
+            },
+            (_, v) => v,
+        }
+    }
+
+    /// See [`ValueRef::compare`].
+    pub fn compare(&self, other: &Value) -> Ordering {
+        ValueRef::from(self).compare(&other.into())
+    }
+
+    /// See [`ValueRef::collate`].
+    pub fn collate(&self, other: &Value, collation: Collation) -> Ordering {
+        ValueRef::from(self).collate(&other.into(), collation)
+    }
 }
 
-impl TryInto<i64> for &Value {
-    type Error = Error;
+/// Whether `s` is a decimal number as SQLite reads one: a sign, digits with
+/// an optional point, and an optional exponent. Rust's own float syntax also
+/// takes "inf" and "nan", which SQLite keeps as TEXT.
+fn is_real_literal(s: &str) -> bool {
+    let s = s.strip_prefix(['+', '-']).unwrap_or(s);
+    let (mantissa, exponent) = match s.find(['e', 'E']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = |d: &str| d.bytes().all(|b| b.is_ascii_digit());
+    let exponent_ok = exponent.is_none_or(|e| {
+        let e = e.strip_prefix(['+', '-']).unwrap_or(e);
+        !e.is_empty() && digits(e)
+    });
+    !(int.is_empty() && frac.is_empty()) && digits(int) && digits(frac) && exponent_ok
+}
 
-    fn try_into(self) -> Result<i64> {
+/// How TEXT values are ordered, as named by COLLATE.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Collation {
+    #[default]
+    Binary,
+    /// ASCII letters compare regardless of case.
+    NoCase,
+    /// Trailing spaces are ignored.
+    RTrim,
+}
+
+impl Collation {
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
         match self {
-            Value::Null => bail!("null"),
-            Value::Float(n) => Ok(*n as i64),
-            Value::Int(i) => Ok(*i),
-            _ => bail!("NaN"),
+            Self::Binary => a.as_bytes().cmp(b.as_bytes()),
+            Self::NoCase => {
+                let fold = |s: &str| {
+                    s.bytes()
+                        .map(|b| b.to_ascii_lowercase())
+                        .collect::<Vec<_>>()
+                };
+                fold(a).cmp(&fold(b))
+            }
+            Self::RTrim => a.trim_end_matches(' ').cmp(b.trim_end_matches(' ')),
         }
     }
 }
 
-impl FromStr for Value {
+impl FromStr for Collation {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        match s {
-            "NULL" => Ok(Value::Null),
-            s if s.starts_with('\'') && s.ends_with('\'') => {
-                Ok(Value::Text(s[1..s.len() - 1].to_string()))
-            }
-            s if s.chars().all(|c| c.is_digit(10)) => Ok(Value::Int(s.parse()?)),
-            _ => bail!("Unsupported format"),
+        match s.trim_matches(|c| c == '"' || c == '\'' || c == '`') {
+            s if s.eq_ignore_ascii_case("BINARY") => Ok(Self::Binary),
+            s if s.eq_ignore_ascii_case("NOCASE") => Ok(Self::NoCase),
+            s if s.eq_ignore_ascii_case("RTRIM") => Ok(Self::RTrim),
+            s => Err(Error::Unsupported(format!("collation {s}"))),
         }
     }
 }
 
-impl Value {
-    pub fn decode(chunk: &[u8], t: Type) -> Result<Self> {
+/// Value borrowed from the page it was decoded from. Text only owns its
+/// content when it had to be transcoded from UTF-16.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueRef<'a> {
+    Null,
+    Int(i64),
+    Float(f64),
+    Blob(&'a [u8]),
+    Text(Cow<'a, str>),
+}
+
+impl<'a> From<&'a Value> for ValueRef<'a> {
+    fn from(value: &'a Value) -> Self {
+        match value {
+            Value::Null => Self::Null,
+            Value::Int(i) => Self::Int(*i),
+            Value::Float(n) => Self::Float(*n),
+            Value::Blob(b) => Self::Blob(b),
+            Value::Text(s) => Self::Text(Cow::Borrowed(s)),
+        }
+    }
+}
+
+impl PartialEq<Value> for ValueRef<'_> {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Self::Null, Value::Null) => true,
+            (Self::Int(a), Value::Int(b)) => a == b,
+            (Self::Float(a), Value::Float(b)) => a == b,
+            (Self::Blob(a), Value::Blob(b)) => *a == b.as_slice(),
+            (Self::Text(a), Value::Text(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Display for ValueRef<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Text(s) => s.fmt(f),
+            // Keeps whole floats apart from integers, as sqlite3 does.
+            Self::Float(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{n:.1}"),
+            Self::Float(n) => n.fmt(f),
+            Self::Int(i) => i.fmt(f),
+            Self::Blob(b) => String::from_utf8_lossy(b).fmt(f),
+        }
+    }
+}
+
+impl TryFrom<ValueRef<'_>> for i64 {
+    type Error = Error;
+
+    fn try_from(value: ValueRef<'_>) -> Result<i64> {
+        match value {
+            ValueRef::Null => Err(Error::Parse("NULL is not an integer".to_string())),
+            ValueRef::Float(n) => Ok(n as i64),
+            ValueRef::Int(i) => Ok(i),
+            v => Err(Error::Parse(format!("{v} is not an integer"))),
+        }
+    }
+}
+
+impl<'a> ValueRef<'a> {
+    pub fn decode(chunk: &'a [u8], t: Type, enc: Encoding) -> Result<Self> {
         let val = match t {
-            Type::Null => Value::Null,
-            Type::Blob(_) => Value::Blob(chunk.to_vec()),
-            Type::Text(_) => Value::Text(std::str::from_utf8(chunk)?.to_string()),
-            Type::Int(_) => Value::Int(codec::two_complements::decode(chunk)?),
-            Type::Float64 => Value::Float(codec::float::decode(chunk)?),
-            Type::Reserved => bail!("malformed db"),
-            Type::Zero => Value::Int(0),
-            Type::One => Value::Int(1),
+            Type::Null => Self::Null,
+            Type::Blob(_) => Self::Blob(chunk),
+            Type::Text(_) if enc == Encoding::Utf8 => match std::str::from_utf8(chunk) {
+                Ok(s) => Self::Text(Cow::Borrowed(s)),
+                Err(e) => return Err(Error::corrupt(format!("invalid text: {e}"))),
+            },
+            Type::Text(_) => Self::Text(Cow::Owned(codec::text::decode(chunk, enc)?)),
+            Type::Int(_) => Self::Int(codec::two_complements::decode(chunk)?),
+            Type::Float64 => Self::Float(codec::float::decode(chunk)?),
+            Type::Zero => Self::Int(0),
+            Type::One => Self::Int(1),
         };
         Ok(val)
     }
+
+    /// Order of values in an index under the BINARY collation: NULL first,
+    /// then numbers, text and blobs.
+    pub fn compare(&self, other: &ValueRef<'_>) -> Ordering {
+        self.collate(other, Collation::Binary)
+    }
+
+    /// Same order as [`ValueRef::compare`], text following `collation`.
+    pub fn collate(&self, other: &ValueRef<'_>, collation: Collation) -> Ordering {
+        let class = |v: &ValueRef<'_>| match v {
+            ValueRef::Null => 0,
+            ValueRef::Int(_) | ValueRef::Float(_) => 1,
+            ValueRef::Text(_) => 2,
+            ValueRef::Blob(_) => 3,
+        };
+        match (self, other) {
+            (ValueRef::Int(a), ValueRef::Int(b)) => a.cmp(b),
+            (ValueRef::Int(a), ValueRef::Float(b)) => (*a as f64).total_cmp(b),
+            (ValueRef::Float(a), ValueRef::Int(b)) => a.total_cmp(&(*b as f64)),
+            (ValueRef::Float(a), ValueRef::Float(b)) => a.total_cmp(b),
+            (ValueRef::Text(a), ValueRef::Text(b)) => collation.compare(a, b),
+            (ValueRef::Blob(a), ValueRef::Blob(b)) => a.cmp(b),
+            (a, b) => class(a).cmp(&class(b)),
+        }
+    }
+
+    pub fn into_owned(self) -> Value {
+        match self {
+            Self::Null => Value::Null,
+            Self::Int(i) => Value::Int(i),
+            Self::Float(n) => Value::Float(n),
+            Self::Blob(b) => Value::Blob(b.to_vec()),
+            Self::Text(s) => Value::Text(s.into_owned()),
+        }
+    }
+}
+
+impl Value {
+    /// SQL literal reading back as this very value. Floats keep a `.` or an
+    /// exponent to stay REAL, and line breaks are spelled with `char()` so
+    /// the literal sits on one line.
+    pub fn literal(&self) -> String {
+        match self {
+            Self::Null => "NULL".to_string(),
+            Self::Int(i) => i.to_string(),
+            Self::Float(n) if n.is_nan() => "NULL".to_string(),
+            Self::Float(n) if n.is_infinite() => match n.is_sign_positive() {
+                true => "1e999".to_string(),
+                false => "-1e999".to_string(),
+            },
+            Self::Float(n) => format!("{n:?}"),
+            Self::Blob(b) => {
+                let hex = b.iter().map(|b| format!("{b:02x}")).collect::<String>();
+                format!("X'{hex}'")
+            }
+            Self::Text(s) => {
+                let quoted = |s: &str| format!("'{}'", s.replace('\'', "''"));
+                let mut parts = vec![];
+                let mut rest = s.as_str();
+                while let Some(at) = rest.find(['\n', '\r']) {
+                    if at > 0 {
+                        parts.push(quoted(&rest[..at]));
+                    }
+                    parts.push(format!("char({})", rest.as_bytes()[at]));
+                    rest = &rest[at + 1..];
+                }
+                if !rest.is_empty() || parts.is_empty() {
+                    parts.push(quoted(rest));
+                }
+                parts.join("||")
+            }
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        ValueRef::from(self).fmt(f)
+    }
+}
+
+impl FromStr for Value {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            s if s.eq_ignore_ascii_case("NULL") => Ok(Value::Null),
+            s if s.eq_ignore_ascii_case("TRUE") => Ok(Value::Int(1)),
+            s if s.eq_ignore_ascii_case("FALSE") => Ok(Value::Int(0)),
+            s if s.len() > 1 && s.starts_with('\'') && s.ends_with('\'') => {
+                Ok(Value::Text(s[1..s.len() - 1].replace("''", "'")))
+            }
+            s if s.len() > 2
+                && s.get(..2).is_some_and(|p| p.eq_ignore_ascii_case("X'"))
+                && s.ends_with('\'') =>
+            {
+                let hex = &s[2..s.len() - 1];
+                if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(Error::Parse(format!("malformed blob literal: {s}")));
+                }
+                let blob = (0..hex.len())
+                    .step_by(2)
+                    .flat_map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                    .collect();
+                Ok(Value::Blob(blob))
+            }
+            s if s
+                .chars()
+                .all(|c| c.is_ascii_digit() || c == '-' || c == '+') =>
+            {
+                s.parse()
+                    .map(Value::Int)
+                    .map_err(|e| Error::Parse(format!("{s}: {e}")))
+            }
+            s => match s.parse::<f64>() {
+                Ok(n) if !s.contains(char::is_alphabetic) || s.contains(['e', 'E']) => {
+                    Ok(Value::Float(n))
+                }
+                _ => Err(Error::Parse(format!("not a literal: {s}"))),
+            },
+        }
+    }
 }
 
 pub mod decode {
     use crate::codec::varint;
-    use crate::value::{Type, Value};
-    use nom::bytes::complete::take;
+    use crate::value::Type;
     use nom::combinator::map_res;
     use nom::{IResult, Parser};
 
-    pub fn take_value(io: &[u8], t: Type) -> IResult<&[u8], Value> {
-        map_res(take(t.len()), |c| Value::decode(c, t)).parse(io)
-    }
-
     pub fn take_type(io: &[u8]) -> IResult<&[u8], Type> {
-        varint::take.map(Type::from).parse(io)
+        map_res(varint::take, Type::try_from).parse(io)
     }
 }